@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::hint::black_box;
 
 use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
@@ -7,7 +8,8 @@ use raytracer::context::Context;
 use raytracer::frame::Frame;
 use raytracer::integrator::integrator::{Integrator, IntegratorImpl};
 use raytracer::integrator::pathtracing::PathTracingIntegrator;
-use raytracer::options::{DenoiseAlgorithm, DofSettings, RenderOptions};
+use raytracer::options::{BounceLimits, ColorSettings, DenoiseAlgorithm, DisplaySettings, Environment, LightSampling, OutputSettings, PixelFilter, RenderOptions, RussianRoulette, Sampler};
+use raytracer::sampler::SampleIndex;
 
 fn bench_render(c: &mut Criterion) {
     let mut group = c.benchmark_group("render");
@@ -33,11 +35,27 @@ fn bench_render(c: &mut Criterion) {
             video: false,
             integrator: raytracer::options::Integrator::Pathtracing,
             depth_of_field: None,
+            environment: Environment::None,
+            light_sampling: LightSampling::Bvh,
+            bounce_limits: BounceLimits::default(),
+            russian_roulette: RussianRoulette::default(),
+            camera_projections: BTreeMap::new(),
+            cameras: Vec::new(),
+            shutter: None,
+            region: None,
+            stereo: None,
+            filter: PixelFilter::Box,
+            sampler: Sampler::Independent,
+            adaptive_sampling: None,
+            output: OutputSettings::default(),
+            aovs: Vec::new(),
+            display: DisplaySettings::default(),
+            color: ColorSettings::default(),
         };
 
         let ctx = Context::new();
 
-        let (scene, _animation_controller) =
+        let (scene, _node_graph, _animation_controller) =
             GltfLoader::load_scene(&options.scene_file, &options, &ctx).unwrap();
 
         let camera = scene.active_camera().clone();
@@ -53,7 +71,7 @@ fn bench_render(c: &mut Criterion) {
             b.iter_batched(
                 || Frame::new(opts.resolution.width, opts.resolution.height),
                 |mut frame| {
-                    integrator.integrate(&scene, &camera, &mut frame, opts.samples, &opts, &ctx);
+                    integrator.integrate(&scene, &camera, &mut frame, SampleIndex { index: 0, count: opts.samples }, &opts, &ctx);
                 },
                 BatchSize::SmallInput,
             )
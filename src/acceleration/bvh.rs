@@ -228,7 +228,7 @@ impl BVH {
         nodes.push(BVHNode::default());
 
         let bounds = AABB::compound(items.iter().map(|m| {
-            m.bounds().transform(m.transform())
+            m.world_bounds()
         }));
 
         if items.len() < 3 {
@@ -319,7 +319,7 @@ impl BVH {
     }
 
     fn goes_left(item: &MeshInstance, split: &Split) -> bool {
-        let c = item.bounds().transform(&item.transform()).centroid()[split.axis];
+        let c = item.world_bounds().centroid()[split.axis];
 
         let mut bin = ((c - split.min_c) / split.extent * BIN_COUNT as f32) as usize;
         bin = bin.min(BIN_COUNT - 1);
@@ -344,7 +344,7 @@ impl BVH {
         let mut max_c = f32::NEG_INFINITY;
 
         for item in items {
-            let c = item.bounds().transform(&item.transform()).centroid()[axis];
+            let c = item.world_bounds().centroid()[axis];
             min_c = min_c.min(c);
             max_c = max_c.max(c);
         }
@@ -358,7 +358,7 @@ impl BVH {
 
         // TODO: THis is already done in calling function. Pass that bbox instead of doing it again?
         let parent_bbox = AABB::compound(items.iter().map(|m| {
-            m.bounds().transform(m.transform())
+            m.world_bounds()
         }));
         let parent_area = parent_bbox.surface_area();
 
@@ -375,7 +375,7 @@ impl BVH {
             let mut bins = [Bin::default(); BIN_COUNT];
 
             for item in &mut *items {
-                let item_bounds = item.bounds().transform(&item.transform());
+                let item_bounds = item.world_bounds();
                 let c = item_bounds.centroid()[axis];
                 let mut bin_idx = ((c - min_c) / extent * BIN_COUNT as f32) as usize;
                 bin_idx = bin_idx.min(BIN_COUNT - 1);
@@ -0,0 +1,473 @@
+use std::f32::consts::PI;
+use nalgebra::{Point3, Rotation3, Unit, Vector3};
+use crate::acceleration::bounds::AABB;
+
+/// Spatial and directional bounds of the emission of one or more lights.
+///
+/// Emission leaves the lights in directions within `cos_theta_o` of `direction`, and falls off
+/// to zero `cos_theta_e` beyond that.
+#[derive(Copy, Clone, Debug)]
+pub struct LightBounds {
+    pub bounds: AABB,
+    /// Emitted power.
+    pub phi: f32,
+    pub direction: Vector3<f32>,
+    pub cos_theta_o: f32,
+    pub cos_theta_e: f32,
+}
+
+impl LightBounds {
+    /// Bounds of a light emitting equally in all directions, e.g. a point light.
+    pub fn omnidirectional(bounds: AABB, phi: f32) -> Self {
+        Self {
+            bounds,
+            phi,
+            direction: Vector3::z(),
+            cos_theta_o: -1.0,
+            cos_theta_e: 0.0,
+        }
+    }
+
+    pub fn union(&self, other: &LightBounds) -> LightBounds {
+        if self.phi <= 0.0 {
+            return *other;
+        }
+        if other.phi <= 0.0 {
+            return *self;
+        }
+
+        let mut bounds = self.bounds;
+        bounds.union(&other.bounds);
+        let (direction, cos_theta_o) = cone_union(self.direction, self.cos_theta_o, other.direction, other.cos_theta_o);
+
+        LightBounds {
+            bounds,
+            phi: self.phi + other.phi,
+            direction,
+            cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+        }
+    }
+
+    /// Conservative estimate of the light's contribution at `point`. A zero `normal` means the
+    /// receiver isn't a surface and any incident direction counts.
+    pub fn importance(&self, point: &Point3<f32>, normal: &Vector3<f32>) -> f32 {
+        if self.phi <= 0.0 {
+            return 0.0;
+        }
+
+        let centroid = self.bounds.centroid();
+        let diagonal = self.bounds.max() - self.bounds.min();
+        let distance_sq = (point - centroid).norm_squared().max(diagonal.norm() / 2.0);
+
+        let wi = (point - centroid).try_normalize(1e-12).unwrap_or_else(Vector3::z);
+        let cos_theta_w = self.direction.dot(&wi);
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+
+        // Angle subtended by the bounds as seen from the point.
+        let cos_theta_b = bound_subtended_directions(&self.bounds, point);
+        let sin_theta_b = safe_sqrt(1.0 - cos_theta_b * cos_theta_b);
+
+        // Minimum angle between the emission cone and the direction to the point.
+        let sin_theta_o = safe_sqrt(1.0 - self.cos_theta_o * self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.phi * cos_theta_p / distance_sq;
+
+        if normal.norm_squared() > 0.0 {
+            // Transmissive surfaces receive light from both sides, so use the absolute cosine.
+            let cos_theta_i = wi.dot(normal).abs();
+            let sin_theta_i = safe_sqrt(1.0 - cos_theta_i * cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+
+        importance.max(0.0)
+    }
+}
+
+fn safe_sqrt(x: f32) -> f32 {
+    x.max(0.0).sqrt()
+}
+
+/// cos(max(0, a - b))
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b {
+        1.0
+    } else {
+        cos_a * cos_b + sin_a * sin_b
+    }
+}
+
+/// sin(max(0, a - b))
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b {
+        0.0
+    } else {
+        sin_a * cos_b - cos_a * sin_b
+    }
+}
+
+/// Cosine of the half-angle of a cone from `point` that contains `bounds`.
+fn bound_subtended_directions(bounds: &AABB, point: &Point3<f32>) -> f32 {
+    let center = bounds.centroid();
+    let radius_sq = (bounds.max() - center).norm_squared();
+    let distance_sq = (point - center).norm_squared();
+    if distance_sq < radius_sq {
+        return -1.0;
+    }
+
+    let sin_theta_max_sq = radius_sq / distance_sq;
+    safe_sqrt(1.0 - sin_theta_max_sq)
+}
+
+/// Smallest cone containing the two cones (`a`, `cos_a`) and (`b`, `cos_b`).
+fn cone_union(a: Vector3<f32>, cos_a: f32, b: Vector3<f32>, cos_b: f32) -> (Vector3<f32>, f32) {
+    let theta_a = cos_a.clamp(-1.0, 1.0).acos();
+    let theta_b = cos_b.clamp(-1.0, 1.0).acos();
+    let theta_d = a.angle(&b);
+
+    if (theta_d + theta_b).min(PI) <= theta_a {
+        return (a, cos_a);
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+        return (b, cos_b);
+    }
+
+    let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+    if theta_o >= PI {
+        return (a, -1.0);
+    }
+
+    let theta_r = theta_o - theta_a;
+    let Some(axis) = Unit::try_new(a.cross(&b), 1e-12) else {
+        return (a, -1.0);
+    };
+
+    (Rotation3::from_axis_angle(&axis, theta_r) * a, theta_o.cos())
+}
+
+enum LightBVHNodeKind {
+    Leaf { light_index: usize },
+    /// The first child directly follows its parent.
+    Interior { second_child: usize },
+}
+
+struct LightBVHNode {
+    bounds: LightBounds,
+    kind: LightBVHNodeKind,
+}
+
+const BUCKET_COUNT: usize = 12;
+const MAX_DEPTH: usize = 64;
+
+/// Bounding volume hierarchy over lights, used to pick lights in proportion to their
+/// estimated contribution at a shading point.
+pub struct LightBVH {
+    nodes: Vec<LightBVHNode>,
+    /// Branches taken from the root to reach each light's leaf, one bit per level.
+    bit_trails: Vec<Option<u64>>,
+}
+
+impl LightBVH {
+    /// `lights` pairs the index of each light with its bounds. `light_count` is the total
+    /// number of lights the indices refer to.
+    pub fn new(mut lights: Vec<(usize, LightBounds)>, light_count: usize) -> Self {
+        lights.retain(|(_, bounds)| bounds.phi > 0.0);
+
+        let mut bvh = Self {
+            nodes: Vec::new(),
+            bit_trails: vec![None; light_count],
+        };
+
+        if !lights.is_empty() {
+            bvh.build_node(&mut lights, 0, 0);
+        }
+
+        bvh
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn build_node(&mut self, lights: &mut [(usize, LightBounds)], bit_trail: u64, depth: usize) -> LightBounds {
+        if lights.len() == 1 {
+            let (light_index, bounds) = lights[0];
+            self.bit_trails[light_index] = Some(bit_trail);
+            self.nodes.push(LightBVHNode {
+                bounds,
+                kind: LightBVHNodeKind::Leaf { light_index },
+            });
+            return bounds;
+        }
+
+        let node_index = self.nodes.len();
+        self.nodes.push(LightBVHNode {
+            bounds: lights[0].1,
+            kind: LightBVHNodeKind::Interior { second_child: 0 },
+        });
+
+        let mid = Self::partition(lights, depth);
+        let (first, second) = lights.split_at_mut(mid);
+
+        let first_bounds = self.build_node(first, bit_trail, depth + 1);
+        let second_child = self.nodes.len();
+        let second_bounds = self.build_node(second, bit_trail | (1 << depth), depth + 1);
+
+        let bounds = first_bounds.union(&second_bounds);
+        self.nodes[node_index] = LightBVHNode {
+            bounds,
+            kind: LightBVHNodeKind::Interior { second_child },
+        };
+
+        bounds
+    }
+
+    /// Split `lights` in two, minimising the surface area orientation heuristic. Returns the
+    /// index of the first light of the second half.
+    fn partition(lights: &mut [(usize, LightBounds)], depth: usize) -> usize {
+        let half = lights.len() / 2;
+
+        let centroid_bounds = AABB::from_points(lights.iter().map(|(_, bounds)| bounds.bounds.centroid()));
+        let extent = centroid_bounds.max() - centroid_bounds.min();
+
+        // Centroids that all coincide, or deep enough that only even splits are guaranteed to
+        // fit the remaining levels in the bit trail.
+        if depth >= MAX_DEPTH / 2 || extent.max() <= 0.0 {
+            return half;
+        }
+
+        let bounds = lights
+            .iter()
+            .skip(1)
+            .fold(lights[0].1, |bounds, (_, light)| bounds.union(light));
+        let diagonal = bounds.bounds.max() - bounds.bounds.min();
+
+        let mut best: Option<(f32, usize, f32)> = None;
+        for axis in 0..3 {
+            if extent[axis] <= 0.0 {
+                continue;
+            }
+
+            let bucket_of = |light: &LightBounds| {
+                let offset = (light.bounds.centroid()[axis] - centroid_bounds.min()[axis]) / extent[axis];
+                ((offset * BUCKET_COUNT as f32) as usize).min(BUCKET_COUNT - 1)
+            };
+
+            let mut buckets: [Option<LightBounds>; BUCKET_COUNT] = [None; BUCKET_COUNT];
+            for (_, light) in lights.iter() {
+                let bucket = &mut buckets[bucket_of(light)];
+                *bucket = Some(bucket.map_or(*light, |b| b.union(light)));
+            }
+
+            let union_range = |range: &[Option<LightBounds>]| {
+                range.iter().flatten().fold(None, |acc: Option<LightBounds>, b| Some(acc.map_or(*b, |a| a.union(b))))
+            };
+
+            let axis_scale = diagonal.max() / diagonal[axis].max(1e-6);
+            for split in 1..BUCKET_COUNT {
+                let (Some(below), Some(above)) = (union_range(&buckets[..split]), union_range(&buckets[split..])) else {
+                    continue;
+                };
+
+                let cost = axis_scale * (Self::cost(&below) + Self::cost(&above));
+                if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    let boundary = centroid_bounds.min()[axis] + extent[axis] * split as f32 / BUCKET_COUNT as f32;
+                    best = Some((cost, axis, boundary));
+                }
+            }
+        }
+
+        let Some((_, axis, boundary)) = best else {
+            return half;
+        };
+
+        let mut mid = 0;
+        for i in 0..lights.len() {
+            if lights[i].1.bounds.centroid()[axis] < boundary {
+                lights.swap(i, mid);
+                mid += 1;
+            }
+        }
+
+        if mid == 0 || mid == lights.len() {
+            half
+        } else {
+            mid
+        }
+    }
+
+    fn cost(bounds: &LightBounds) -> f32 {
+        let theta_o = bounds.cos_theta_o.clamp(-1.0, 1.0).acos();
+        let theta_e = bounds.cos_theta_e.clamp(-1.0, 1.0).acos();
+        let theta_w = (theta_o + theta_e).min(PI);
+        let sin_theta_o = safe_sqrt(1.0 - bounds.cos_theta_o * bounds.cos_theta_o);
+
+        // Solid angle measure of the emission cone, weighted by its cosine falloff.
+        let m_omega = 2.0 * PI * (1.0 - bounds.cos_theta_o)
+            + PI / 2.0 * (2.0 * theta_w * sin_theta_o - (theta_o - 2.0 * theta_w).cos() - 2.0 * theta_o * sin_theta_o + bounds.cos_theta_o);
+
+        bounds.phi * m_omega * bounds.bounds.surface_area().max(1e-6)
+    }
+
+    fn child_importances(&self, second_child: usize, node_index: usize, point: &Point3<f32>, normal: &Vector3<f32>) -> (f32, f32) {
+        (
+            self.nodes[node_index + 1].bounds.importance(point, normal),
+            self.nodes[second_child].bounds.importance(point, normal),
+        )
+    }
+
+    /// Pick a light for shading `point`. Returns (light index, probability of picking it).
+    pub fn sample(&self, point: &Point3<f32>, normal: &Vector3<f32>, u: f32) -> Option<(usize, f32)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut u = u;
+        let mut pmf = 1.0;
+        let mut node_index = 0;
+
+        loop {
+            let node = &self.nodes[node_index];
+            match node.kind {
+                LightBVHNodeKind::Leaf { light_index } => {
+                    return if node.bounds.importance(point, normal) > 0.0 {
+                        Some((light_index, pmf))
+                    } else {
+                        None
+                    };
+                }
+                LightBVHNodeKind::Interior { second_child } => {
+                    let (first, second) = self.child_importances(second_child, node_index, point, normal);
+                    if first <= 0.0 && second <= 0.0 {
+                        return None;
+                    }
+
+                    let p_first = first / (first + second);
+                    if u < p_first {
+                        node_index += 1;
+                        pmf *= p_first;
+                        u = (u / p_first).min(1.0 - f32::EPSILON);
+                    } else {
+                        node_index = second_child;
+                        pmf *= 1.0 - p_first;
+                        u = ((u - p_first) / (1.0 - p_first)).min(1.0 - f32::EPSILON);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Probability of `sample` returning `light_index` when shading `point`.
+    pub fn pmf(&self, point: &Point3<f32>, normal: &Vector3<f32>, light_index: usize) -> f32 {
+        let Some(mut bit_trail) = self.bit_trails.get(light_index).copied().flatten() else {
+            return 0.0;
+        };
+
+        let mut pmf = 1.0;
+        let mut node_index = 0;
+
+        loop {
+            let node = &self.nodes[node_index];
+            match node.kind {
+                // The same check as `sample`, which never picks a light of no importance
+                LightBVHNodeKind::Leaf { .. } => {
+                    return if node.bounds.importance(point, normal) > 0.0 { pmf } else { 0.0 };
+                }
+                LightBVHNodeKind::Interior { second_child } => {
+                    let (first, second) = self.child_importances(second_child, node_index, point, normal);
+                    if first <= 0.0 && second <= 0.0 {
+                        return 0.0;
+                    }
+
+                    if bit_trail & 1 == 0 {
+                        pmf *= first / (first + second);
+                        node_index += 1;
+                    } else {
+                        pmf *= second / (first + second);
+                        node_index = second_child;
+                    }
+                    bit_trail >>= 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point_light(position: Point3<f32>, phi: f32) -> LightBounds {
+        LightBounds::omnidirectional(AABB::new(position, position), phi)
+    }
+
+    fn create_lights() -> Vec<(usize, LightBounds)> {
+        (0..9)
+            .map(|i| {
+                let position = Point3::new(i as f32 * 3.0, (i % 3) as f32, 0.0);
+                (i, point_light(position, 1.0 + i as f32))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pmf_sums_to_one_and_matches_sample() {
+        let bvh = LightBVH::new(create_lights(), 9);
+        let point = Point3::new(4.0, 2.0, 1.0);
+        let normal = Vector3::zeros();
+
+        let total: f32 = (0..9).map(|i| bvh.pmf(&point, &normal, i)).sum();
+        assert!((total - 1.0).abs() < 1e-4, "pmf sums to {}", total);
+
+        for u in [0.0, 0.1, 0.35, 0.5, 0.77, 0.999] {
+            let (light_index, pmf) = bvh.sample(&point, &normal, u).unwrap();
+            assert!((bvh.pmf(&point, &normal, light_index) - pmf).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn nearby_lights_are_more_likely() {
+        let bvh = LightBVH::new(create_lights(), 9);
+        let point = Point3::new(0.0, 0.0, 0.5);
+        let normal = Vector3::zeros();
+
+        assert!(bvh.pmf(&point, &normal, 0) > bvh.pmf(&point, &normal, 8));
+    }
+
+    #[test]
+    fn lights_facing_away_are_never_picked() {
+        let facing_away = LightBounds {
+            direction: -Vector3::z(),
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            ..point_light(Point3::new(0.0, 0.0, 0.0), 10.0)
+        };
+        let lights = vec![(0, facing_away), (1, point_light(Point3::new(5.0, 0.0, 0.0), 1.0))];
+        let bvh = LightBVH::new(lights, 2);
+        let point = Point3::new(0.0, 0.0, 2.0);
+
+        assert_eq!(bvh.pmf(&point, &Vector3::zeros(), 0), 0.0);
+        assert!((bvh.pmf(&point, &Vector3::zeros(), 1) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn single_light_facing_away_has_no_probability() {
+        let facing_away = LightBounds {
+            direction: -Vector3::z(),
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            ..point_light(Point3::new(0.0, 0.0, 0.0), 10.0)
+        };
+        let bvh = LightBVH::new(vec![(0, facing_away)], 1);
+        let point = Point3::new(0.0, 0.0, 2.0);
+
+        assert!(bvh.sample(&point, &Vector3::zeros(), 0.5).is_none());
+        assert_eq!(bvh.pmf(&point, &Vector3::zeros(), 0), 0.0);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod bounds;
 pub mod bvh;
-pub mod kdtree;
\ No newline at end of file
+pub mod kdtree;
+pub mod light_bvh;
\ No newline at end of file
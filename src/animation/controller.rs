@@ -1,7 +1,9 @@
 use crate::animation::{Animation, AnimationOutputs, Interpolation};
 use crate::scene::node_graph::{NodeGraph, SceneNode};
 use crate::scene::scene::Scene;
-use nalgebra::Vector3;
+use crate::options::ShutterSettings;
+use nalgebra::{Matrix4, Vector3};
+use std::collections::HashMap;
 
 #[derive(Copy, Clone, PartialOrd, PartialEq)]
 pub enum AnimationState {
@@ -45,16 +47,52 @@ impl AnimationController {
         None
     }
 
-    pub fn step(&mut self, delta_time: f32, node_graph: &mut NodeGraph, scene: &mut Scene) -> AnimationState {
+    /// Seconds into the animation.
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    /// Advance by `delta_time`, one frame, and move the scene there.
+    pub fn step(&mut self, delta_time: f32, node_graph: &mut NodeGraph, scene: &mut Scene, shutter: Option<&ShutterSettings>) -> AnimationState {
         self.time += delta_time;
+        self.apply(node_graph, scene, shutter, delta_time);
+
+        if self.time >= self.last_timestamp {
+            AnimationState::Finished
+        } else {
+            AnimationState::Playing
+        }
+    }
+
+    /// Move the scene to the current time. With a shutter, meshes and cameras move from where
+    /// they are when it opens to where they are when it closes, relative to a frame of
+    /// `frame_duration`.
+    pub fn apply(&self, node_graph: &mut NodeGraph, scene: &mut Scene, shutter: Option<&ShutterSettings>, frame_duration: f32) {
+        let (open, close) = shutter.map_or((0.0, 0.0), |shutter| (shutter.open, shutter.close));
+
+        self.evaluate(self.time + open * frame_duration, node_graph);
+        let mut start_transforms = HashMap::new();
+        Self::collect_world_transforms(&node_graph.roots, &Matrix4::identity(), &mut start_transforms);
+
+        self.evaluate(self.time + close * frame_duration, node_graph);
+        let mut end_transforms = HashMap::new();
+        Self::collect_world_transforms(&node_graph.roots, &Matrix4::identity(), &mut end_transforms);
+
+        self.evaluate(self.time, node_graph);
 
+        Self::update_scene(&node_graph.roots, &start_transforms, &end_transforms, scene);
+        scene.rebuild_bvh();
+    }
+
+    /// Set the local transforms of animated nodes to where they are at `time`.
+    fn evaluate(&self, time: f32, node_graph: &mut NodeGraph) {
         for animation in &self.animations {
             for channel in &animation.channels {
                 let node = Self::get_node(&mut node_graph.roots, channel.node_index).unwrap();
                 match channel.interpolation {
                     Interpolation::Linear => {
                         let to_index = channel.timestamps
-                            .partition_point(|t| *t <= self.time);
+                            .partition_point(|t| *t <= time);
                         let from_index = to_index.saturating_sub(1);
 
                         match &channel.outputs {
@@ -71,7 +109,7 @@ impl AnimationController {
                                     let t_start = channel.timestamps[from_index];
                                     let t_end = channel.timestamps[to_index];
                                     let t_delta = t_end - t_start;
-                                    let t_ratio = ((self.time - t_start) / t_delta).clamp(0.0, 1.0);
+                                    let t_ratio = ((time - t_start) / t_delta).clamp(0.0, 1.0);
 
                                     let trans_start = translations[from_index];
                                     let trans_end = translations[to_index];
@@ -92,7 +130,7 @@ impl AnimationController {
                                     let t_start = channel.timestamps[from_index];
                                     let t_end = channel.timestamps[to_index];
                                     let t_delta = t_end - t_start;
-                                    let t_ratio = ((self.time - t_start) / t_delta).clamp(0.0, 1.0);
+                                    let t_ratio = ((time - t_start) / t_delta).clamp(0.0, 1.0);
 
                                     let rot_start = rotations[from_index];
                                     let rot_end = rotations[to_index];
@@ -113,7 +151,7 @@ impl AnimationController {
                                     let t_start = channel.timestamps[from_index];
                                     let t_end = channel.timestamps[to_index];
                                     let t_delta = t_end - t_start;
-                                    let t_ratio = ((self.time - t_start) / t_delta).clamp(0.0, 1.0);
+                                    let t_ratio = ((time - t_start) / t_delta).clamp(0.0, 1.0);
 
                                     let scale_start = scales[from_index];
                                     let scale_end = scales[to_index];
@@ -132,33 +170,35 @@ impl AnimationController {
             }
         }
 
-        Self::update_scene(node_graph.roots.as_mut_slice(), &nalgebra::Matrix4::identity(), scene);
-        scene.rebuild_bvh();
+    }
 
-        if self.time >= self.last_timestamp {
-            AnimationState::Finished
-        } else {
-            AnimationState::Playing
+    fn collect_world_transforms(nodes: &[SceneNode], transform: &Matrix4<f32>, world_transforms: &mut HashMap<usize, Matrix4<f32>>) {
+        for node in nodes {
+            let node_transform = transform * node.local_transform.to_matrix();
+            world_transforms.insert(node.index, node_transform);
+            Self::collect_world_transforms(&node.children, &node_transform, world_transforms);
         }
     }
 
-    fn update_scene(nodes: &mut [SceneNode], transform: &nalgebra::Matrix4<f32>, scene: &mut Scene) {
+    /// Lights don't blur, they stay where they are when the shutter opens.
+    fn update_scene(nodes: &[SceneNode], start_transforms: &HashMap<usize, Matrix4<f32>>, end_transforms: &HashMap<usize, Matrix4<f32>>, scene: &mut Scene) {
         for node in nodes {
-            let node_transform = transform * node.local_transform.to_matrix();
+            let start = start_transforms[&node.index];
+            let end = end_transforms[&node.index];
 
             if let Some(camera_index) = node.camera_index {
-                scene.cameras_mut()[camera_index].update_transform(node_transform);
+                scene.cameras_mut()[camera_index].update_motion(start, end);
             }
 
             if let Some(light_index) = node.light_index {
-                scene.lights_mut()[light_index].update_transform(node_transform);
+                scene.lights_mut()[light_index].update_transform(start);
             }
 
             for mesh_index in &node.mesh_indices {
-                scene.meshes_mut()[*mesh_index].update_transform(node_transform);
+                scene.mesh_mut(*mesh_index).update_motion(start, end);
             }
 
-            Self::update_scene(&mut node.children, &node_transform, scene);
+            Self::update_scene(&node.children, start_transforms, end_transforms, scene);
         }
     }
 
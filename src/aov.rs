@@ -0,0 +1,168 @@
+use nalgebra::{Point3, Vector2, Vector3};
+use crate::options::Aov;
+
+/// What one camera sample found for the AOVs. Light is split by the lobe it left the first hit
+/// through, so the light AOVs add up to the beauty.
+#[derive(Clone, Debug)]
+pub struct AovSample {
+    pub depth: f32,
+    pub position: Point3<f32>,
+    pub shading_normal: Vector3<f32>,
+    pub geometric_normal: Vector3<f32>,
+    pub uv: Vector2<f32>,
+    pub albedo: Vector3<f32>,
+    /// Mesh index plus one, 0 for nothing.
+    pub object_id: u32,
+    /// Material index plus one, 0 for nothing.
+    pub material_id: u32,
+    pub direct_diffuse: Vector3<f32>,
+    pub indirect_diffuse: Vector3<f32>,
+    pub direct_specular: Vector3<f32>,
+    pub indirect_specular: Vector3<f32>,
+    pub emission: Vector3<f32>,
+    pub transmission: Vector3<f32>,
+}
+
+impl Default for AovSample {
+    /// A camera ray that hits nothing.
+    fn default() -> Self {
+        Self {
+            depth: f32::INFINITY,
+            position: Point3::origin(),
+            shading_normal: Vector3::zeros(),
+            geometric_normal: Vector3::zeros(),
+            uv: Vector2::zeros(),
+            albedo: Vector3::zeros(),
+            object_id: 0,
+            material_id: 0,
+            direct_diffuse: Vector3::zeros(),
+            indirect_diffuse: Vector3::zeros(),
+            direct_specular: Vector3::zeros(),
+            indirect_specular: Vector3::zeros(),
+            emission: Vector3::zeros(),
+            transmission: Vector3::zeros(),
+        }
+    }
+}
+
+impl Aov {
+    pub fn value(&self, sample: &AovSample) -> Vector3<f32> {
+        match self {
+            Aov::Depth => Vector3::repeat(sample.depth),
+            Aov::Position => sample.position.coords,
+            Aov::ShadingNormal => sample.shading_normal,
+            Aov::GeometricNormal => sample.geometric_normal,
+            Aov::Uv => Vector3::new(sample.uv.x, sample.uv.y, 0.0),
+            Aov::Albedo => sample.albedo,
+            Aov::ObjectId => Vector3::repeat(sample.object_id as f32),
+            Aov::MaterialId => Vector3::repeat(sample.material_id as f32),
+            Aov::DirectDiffuse => sample.direct_diffuse,
+            Aov::IndirectDiffuse => sample.indirect_diffuse,
+            Aov::DirectSpecular => sample.direct_specular,
+            Aov::IndirectSpecular => sample.indirect_specular,
+            Aov::Emission => sample.emission,
+            Aov::Transmission => sample.transmission,
+            // Counted by the frame from the samples of the beauty instead
+            Aov::SampleCount => Vector3::zeros(),
+        }
+    }
+
+    /// Whether the buffer holds light or colors, rather than data like depths and normals.
+    pub fn is_color(&self) -> bool {
+        matches!(
+            self,
+            Aov::Albedo | Aov::DirectDiffuse | Aov::IndirectDiffuse | Aov::DirectSpecular | Aov::IndirectSpecular | Aov::Emission | Aov::Transmission
+        )
+    }
+
+    /// Whether the samples of a pixel are averaged. Otherwise the pixel keeps its first sample,
+    /// as averages of IDs and depths across edges are neither.
+    fn averaged(&self) -> bool {
+        !matches!(self, Aov::Depth | Aov::ObjectId | Aov::MaterialId)
+    }
+}
+
+/// Pixels of one AOV, each from the samples taken inside it.
+#[derive(Clone)]
+pub struct AovBuffer {
+    aov: Aov,
+    sums: Vec<Vector3<f32>>,
+    counts: Vec<u32>,
+}
+
+impl AovBuffer {
+    pub fn new(aov: Aov, pixel_count: usize) -> Self {
+        Self { aov, sums: vec![Vector3::zeros(); pixel_count], counts: vec![0; pixel_count] }
+    }
+
+    pub fn aov(&self) -> Aov {
+        self.aov
+    }
+
+    pub fn add(&mut self, index: usize, sample: &AovSample) {
+        if self.aov.averaged() {
+            self.sums[index] += self.aov.value(sample);
+        } else if self.counts[index] == 0 {
+            self.sums[index] = self.aov.value(sample);
+        }
+        self.counts[index] += 1;
+    }
+
+    /// Add the samples of `other`, whose pixels start at pixel `offset` of this buffer.
+    pub fn merge(&mut self, other: &AovBuffer, offset: usize) {
+        for (index, (sum, &count)) in other.sums.iter().zip(&other.counts).enumerate() {
+            let own_count = &mut self.counts[offset + index];
+            if self.aov.averaged() {
+                self.sums[offset + index] += sum;
+            } else if *own_count == 0 && count > 0 {
+                self.sums[offset + index] = *sum;
+            }
+            *own_count += count;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.sums.iter_mut().for_each(|sum| *sum = Vector3::zeros());
+        self.counts.iter_mut().for_each(|count| *count = 0);
+    }
+
+    /// Values of all pixels, zero where no sample was taken.
+    pub fn pixels(&self) -> Vec<Vector3<f32>> {
+        self.sums
+            .iter()
+            .zip(&self.counts)
+            .map(|(sum, &count)| match count {
+                0 => Vector3::zeros(),
+                _ if self.aov.averaged() => sum / count as f32,
+                _ => *sum,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_keep_the_first_sample_and_light_is_averaged() {
+        let mut ids = AovBuffer::new(Aov::ObjectId, 2);
+        let mut light = AovBuffer::new(Aov::DirectDiffuse, 2);
+        let samples = [
+            AovSample { object_id: 3, direct_diffuse: Vector3::repeat(1.0), ..Default::default() },
+            AovSample { object_id: 5, direct_diffuse: Vector3::repeat(3.0), ..Default::default() },
+        ];
+        for sample in &samples {
+            ids.add(1, sample);
+            light.add(1, sample);
+        }
+
+        let mut strip = AovBuffer::new(Aov::ObjectId, 1);
+        strip.add(0, &samples[1]);
+        ids.merge(&strip, 0);
+        ids.merge(&strip, 1);
+
+        assert_eq!(ids.pixels(), vec![Vector3::repeat(5.0), Vector3::repeat(3.0)]);
+        assert_eq!(light.pixels(), vec![Vector3::zeros(), Vector3::repeat(2.0)]);
+    }
+}
@@ -1,2 +1,8 @@
 pub mod viewpoint;
-pub mod perspective_camera;
\ No newline at end of file
+pub mod aperture;
+pub mod focus;
+pub mod perspective_camera;
+pub mod orthographic_camera;
+pub mod panoramic_camera;
+pub mod shutter;
+pub mod stereo;
\ No newline at end of file
@@ -0,0 +1,86 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+use nalgebra::Vector2;
+use rand::Rng;
+use crate::math::distribution::Distribution2D;
+
+/// Shape of the lens opening. Out of focus highlights take this shape.
+#[derive(Clone)]
+pub enum Aperture {
+    Circle,
+    /// Regular polygon formed by `blades` straight blades, with a corner at `rotation` radians
+    /// counterclockwise from the right.
+    Polygon { blades: u32, rotation: f32 },
+    /// Image over the square around the aperture, letting light through where it is bright.
+    Mask(Arc<Distribution2D>),
+}
+
+impl Aperture {
+    /// Point on the aperture of radius 1, x right and y up, distributed by how much light passes
+    /// through each point.
+    pub fn sample(&self, rng: &mut impl Rng) -> Vector2<f32> {
+        match self {
+            Aperture::Circle => {
+                let r = rng.random::<f32>().sqrt();
+                let phi = 2.0 * PI * rng.random::<f32>();
+                Vector2::new(r * phi.cos(), r * phi.sin())
+            }
+            Aperture::Polygon { blades, rotation } => {
+                // Every blade has a triangle between its edge and the center, all of equal area
+                let blades = (*blades).max(3);
+                let blade = rng.random_range(0..blades);
+                let corner = |index: u32| {
+                    let angle = rotation + 2.0 * PI * index as f32 / blades as f32;
+                    Vector2::new(angle.cos(), angle.sin())
+                };
+
+                let r = rng.random::<f32>().sqrt();
+                let t = rng.random::<f32>();
+                (corner(blade) * (1.0 - t) + corner(blade + 1) * t) * r
+            }
+            Aperture::Mask(distribution) => {
+                let ((x, y), _) = distribution.sample_continuous((rng.random(), rng.random()));
+                Vector2::new(x * 2.0 - 1.0, 1.0 - y * 2.0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_stay_within_aperture() {
+        let mut rng = rand::rng();
+        let hexagon = Aperture::Polygon { blades: 6, rotation: 0.0 };
+        // Distance from the center to the middle of an edge
+        let apothem = (PI / 6.0).cos();
+
+        let mut mean_radius = 0.0;
+        let count = 10000;
+        for _ in 0..count {
+            let point = hexagon.sample(&mut rng);
+            let angle = point.y.atan2(point.x).rem_euclid(PI / 3.0) - PI / 6.0;
+            assert!(point.norm() * angle.cos() <= apothem + 1e-5);
+
+            let point = Aperture::Circle.sample(&mut rng);
+            assert!(point.norm() <= 1.0);
+            mean_radius += point.norm();
+        }
+
+        // Uniform over the disk, so the mean distance from the center is 2/3
+        assert!((mean_radius / count as f32 - 2.0 / 3.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn mask_samples_bright_texels() {
+        // Only the top right texel lets light through
+        let mask = Aperture::Mask(Arc::new(Distribution2D::new(&[0.0, 1.0, 0.0, 0.0], 2, 2)));
+        let mut rng = rand::rng();
+        for _ in 0..100 {
+            let point = mask.sample(&mut rng);
+            assert!(point.x >= 0.0 && point.y >= 0.0);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use nalgebra::Point3;
+use crate::camera::viewpoint::{CameraImpl, Viewpoint};
+use crate::context::Context;
+use crate::math::lerp;
+use crate::options::{FocalDistance, FocusKeyframe};
+use crate::scene::node_graph::NodeGraph;
+use crate::scene::scene::Scene;
+
+/// Distance from `camera` to keep in focus, `time` seconds into the animation. None if there is
+/// nothing to focus on, like when auto focus hits nothing.
+pub fn focal_distance(focus: &FocalDistance, time: f32, camera: &CameraImpl, scene: &Scene, node_graph: &NodeGraph, ctx: &Context) -> Option<f32> {
+    match focus {
+        FocalDistance::Fixed(distance) => Some(*distance),
+        FocalDistance::Auto(u, v) => {
+            let focus_ray = camera.generate_ray(*u, *v);
+            scene.intersect(&focus_ray, ctx).map(|hit| hit.intersection.dist)
+        }
+        FocalDistance::Object(name) => match node_graph.world_transform(name) {
+            None => {
+                eprintln!("Warning: could not find object '{}' for focal distance", name);
+                None
+            }
+            Some(transform) => Some((transform.transform_point(&Point3::origin()) - camera.origin()).norm()),
+        },
+        FocalDistance::Pull(keyframes) => pull(keyframes, time, |focus| focal_distance(focus, time, camera, scene, node_graph, ctx)),
+    }
+}
+
+/// Focal distance between the keyframes around `time`, which are in order of time. Keyframes
+/// that have nothing to focus on are skipped over.
+fn pull(keyframes: &[FocusKeyframe], time: f32, distance: impl Fn(&FocalDistance) -> Option<f32>) -> Option<f32> {
+    let next = keyframes.partition_point(|keyframe| keyframe.time <= time);
+    let previous = next.checked_sub(1).map(|index| &keyframes[index]);
+
+    match (previous, keyframes.get(next)) {
+        (None, None) => None,
+        (Some(keyframe), None) | (None, Some(keyframe)) => distance(&keyframe.focus),
+        (Some(from), Some(to)) => match (distance(&from.focus), distance(&to.focus)) {
+            (Some(start), Some(end)) => {
+                let t = ((time - from.time) / (to.time - from.time)).clamp(0.0, 1.0);
+                // Ease in and out, so focus starts and stops moving gradually
+                Some(lerp(start, end, t * t * (3.0 - 2.0 * t)))
+            }
+            (start, end) => start.or(end),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed(focus: &FocalDistance) -> Option<f32> {
+        match focus {
+            FocalDistance::Fixed(distance) => Some(*distance),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn pull_eases_between_keyframes() {
+        let keyframes = vec![
+            FocusKeyframe { time: 1.0, focus: FocalDistance::Fixed(2.0) },
+            FocusKeyframe { time: 3.0, focus: FocalDistance::Fixed(4.0) },
+        ];
+
+        assert_eq!(pull(&keyframes, 0.0, fixed), Some(2.0));
+        assert_eq!(pull(&keyframes, 1.0, fixed), Some(2.0));
+        assert!((pull(&keyframes, 2.0, fixed).unwrap() - 3.0).abs() < 1e-5);
+        // Slower than linear close to the keyframes
+        assert!(pull(&keyframes, 1.2, fixed).unwrap() < 2.2);
+        assert_eq!(pull(&keyframes, 5.0, fixed), Some(4.0));
+        assert_eq!(pull(&[], 1.0, fixed), None);
+    }
+}
@@ -0,0 +1,117 @@
+use nalgebra::{Matrix4, Point3, Vector2, Vector3};
+use rand::Rng;
+use crate::camera::viewpoint::Viewpoint;
+use crate::core::Ray;
+use crate::math::motion::TransformMotion;
+
+/// Camera with parallel rays. The image covers `2 * xmag` by `2 * ymag` scene units around the
+/// camera origin, as described by the glTF orthographic projection.
+#[derive(Copy, Clone)]
+pub struct OrthographicCamera {
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    up: Vector3<f32>,
+    xmag: f32,
+    ymag: f32,
+    base: Point3<f32>,
+    size: Vector2<f32>,
+    u_dir: Vector3<f32>,
+    v_dir: Vector3<f32>,
+    motion: Option<TransformMotion>,
+}
+
+impl OrthographicCamera {
+    pub fn new(origin: Point3<f32>, direction: Vector3<f32>, up: Vector3<f32>, xmag: f32, ymag: f32) -> Self {
+        let mut camera = Self {
+            origin,
+            direction,
+            up,
+            xmag,
+            ymag,
+            base: origin,
+            size: Vector2::zeros(),
+            u_dir: Vector3::zeros(),
+            v_dir: Vector3::zeros(),
+            motion: None,
+        };
+        camera.update_film();
+        camera
+    }
+
+    fn update_film(&mut self) {
+        let n = (self.direction * -1.0).normalize();
+        self.u_dir = self.up.cross(&n).normalize();
+        self.v_dir = n.cross(&self.u_dir).normalize();
+        self.size = Vector2::new(2.0 * self.xmag, 2.0 * self.ymag);
+        self.base = self.origin + (self.u_dir * self.xmag) - (self.v_dir * self.ymag);
+    }
+
+    pub fn origin(&self) -> Point3<f32> {
+        self.origin
+    }
+
+    pub fn update_transform(&mut self, transform: Matrix4<f32>) {
+        self.motion = None;
+        self.set_pose(transform);
+    }
+
+    /// Move from `start` to `end` while the shutter is open.
+    pub fn update_motion(&mut self, start: Matrix4<f32>, end: Matrix4<f32>) {
+        self.set_pose(start);
+        self.motion = TransformMotion::new(start, end);
+    }
+
+    /// The camera as it is at `time` in the shutter interval.
+    pub fn at_time(&self, time: f32) -> Self {
+        let mut camera = *self;
+        if let Some(motion) = &self.motion {
+            camera.set_pose(motion.interpolate(time));
+        }
+        camera
+    }
+
+    fn set_pose(&mut self, transform: Matrix4<f32>) {
+        self.origin = transform.transform_point(&Point3::origin());
+        self.direction = transform.transform_vector(&Vector3::new(0.0, 0.0, -1.0)).normalize();
+        self.up = transform.transform_vector(&Vector3::new(0.0, 1.0, 0.0)).normalize();
+        self.update_film();
+    }
+
+    pub fn forward(&self) -> Vector3<f32> {
+        self.direction.normalize()
+    }
+
+    fn film_point(&self, u: f32, v: f32) -> Point3<f32> {
+        self.base - (self.u_dir * u * self.size.x) + (self.v_dir * v * self.size.y)
+    }
+}
+
+impl Viewpoint for OrthographicCamera {
+    fn generate_ray(&self, u: f32, v: f32) -> Ray {
+        Ray::new(self.film_point(u, v), self.forward())
+    }
+
+    /// There is no lens, so every ray is in focus.
+    fn generate_offset_ray(&self, u: f32, v: f32, _rng: &mut impl Rng) -> Ray {
+        self.generate_ray(u, v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rays_are_parallel_and_span_magnification() {
+        let camera = OrthographicCamera::new(Point3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0), Vector3::y(), 2.0, 1.0);
+
+        let center = camera.generate_ray(0.5, 0.5);
+        assert!((center.origin() - Point3::new(0.0, 0.0, 5.0)).norm() < 1e-5);
+
+        // Integrators flip u and v, so (1, 1) is the top left corner of the image.
+        let top_left = camera.generate_ray(1.0, 1.0);
+        assert!((top_left.origin() - Point3::new(-2.0, 1.0, 5.0)).norm() < 1e-5);
+        assert!((top_left.direction() - center.direction()).norm() < 1e-5);
+        assert!((center.direction() - Vector3::new(0.0, 0.0, -1.0)).norm() < 1e-5);
+    }
+}
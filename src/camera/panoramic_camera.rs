@@ -0,0 +1,184 @@
+use std::f32::consts::PI;
+use nalgebra::{Matrix4, Point3, Vector3};
+use rand::Rng;
+use crate::camera::viewpoint::Viewpoint;
+use crate::core::Ray;
+use crate::math::motion::TransformMotion;
+use crate::options::{CameraProjection, EquirectangularLayout, FisheyeMapping};
+
+/// Camera covering a wide angle from a single point, either as an equirectangular panorama or
+/// through a fisheye lens. Both are centered on the view direction.
+#[derive(Copy, Clone)]
+pub struct PanoramicCamera {
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    up: Vector3<f32>,
+    aspect_ratio: f32,
+    projection: CameraProjection,
+    motion: Option<TransformMotion>,
+}
+
+impl PanoramicCamera {
+    pub fn new(origin: Point3<f32>, direction: Vector3<f32>, up: Vector3<f32>, aspect_ratio: f32, projection: CameraProjection) -> Self {
+        Self {
+            origin,
+            direction: direction.normalize(),
+            up: up.normalize(),
+            aspect_ratio,
+            projection,
+            motion: None,
+        }
+    }
+
+    pub fn origin(&self) -> Point3<f32> {
+        self.origin
+    }
+
+    pub fn update_transform(&mut self, transform: Matrix4<f32>) {
+        self.motion = None;
+        self.set_pose(transform);
+    }
+
+    /// Move from `start` to `end` while the shutter is open.
+    pub fn update_motion(&mut self, start: Matrix4<f32>, end: Matrix4<f32>) {
+        self.set_pose(start);
+        self.motion = TransformMotion::new(start, end);
+    }
+
+    /// The camera as it is at `time` in the shutter interval.
+    pub fn at_time(&self, time: f32) -> Self {
+        let mut camera = *self;
+        if let Some(motion) = &self.motion {
+            camera.set_pose(motion.interpolate(time));
+        }
+        camera
+    }
+
+    fn set_pose(&mut self, transform: Matrix4<f32>) {
+        self.origin = transform.transform_point(&Point3::origin());
+        self.direction = transform.transform_vector(&Vector3::new(0.0, 0.0, -1.0)).normalize();
+        self.up = transform.transform_vector(&Vector3::new(0.0, 1.0, 0.0)).normalize();
+    }
+
+    fn right(&self) -> Vector3<f32> {
+        self.direction.cross(&self.up).normalize()
+    }
+
+    /// Camera space direction with the given angles from the view direction, to the right and up.
+    fn direction_from_angles(&self, azimuth: f32, elevation: f32) -> Vector3<f32> {
+        let horizontal = self.right() * azimuth.sin() + self.direction * azimuth.cos();
+        (horizontal * elevation.cos() + self.up * elevation.sin()).normalize()
+    }
+
+    /// `x` and `y` are image coordinates, x right and y down.
+    fn equirectangular_ray(&self, x: f32, y: f32, layout: EquirectangularLayout) -> Ray {
+        let (y, eye_offset) = match layout {
+            EquirectangularLayout::Mono => (y, 0.0),
+            EquirectangularLayout::OverUnder { eye_separation } => {
+                if y < 0.5 {
+                    (y * 2.0, -eye_separation / 2.0)
+                } else {
+                    (y * 2.0 - 1.0, eye_separation / 2.0)
+                }
+            }
+        };
+
+        let azimuth = (x - 0.5) * 2.0 * PI;
+        let elevation = (0.5 - y) * PI;
+        let direction = self.direction_from_angles(azimuth, elevation);
+
+        // Each eye sits on a circle around the origin, offset perpendicular to the horizontal
+        // view direction, so every column of the panorama is seen in stereo.
+        let tangent = self.right() * azimuth.cos() - self.direction * azimuth.sin();
+        Ray::new(self.origin + tangent * eye_offset, direction)
+    }
+
+    fn fisheye_ray(&self, x: f32, y: f32, mapping: FisheyeMapping, fov: f32) -> Ray {
+        // Normalized so the left and right edges of the image are at distance 1 from the center
+        let dx = (x - 0.5) * 2.0;
+        let dy = (0.5 - y) * 2.0 / self.aspect_ratio;
+        let radius = (dx * dx + dy * dy).sqrt();
+        let half_fov = fov.to_radians() / 2.0;
+
+        let theta = match mapping {
+            FisheyeMapping::Equidistant => radius * half_fov,
+            FisheyeMapping::Equisolid => 2.0 * (radius * (half_fov / 2.0).sin()).min(1.0).asin(),
+        }.min(PI);
+
+        let direction = if radius > 0.0 {
+            let radial = (self.right() * dx + self.up * dy) / radius;
+            self.direction * theta.cos() + radial * theta.sin()
+        } else {
+            self.direction
+        };
+
+        Ray::new(self.origin, direction.normalize())
+    }
+}
+
+impl Viewpoint for PanoramicCamera {
+    fn generate_ray(&self, u: f32, v: f32) -> Ray {
+        // Integrators pass flipped coordinates, like they do for perspective cameras
+        let (x, y) = (1.0 - u, 1.0 - v);
+        match self.projection {
+            CameraProjection::Equirectangular(layout) => self.equirectangular_ray(x, y, layout),
+            CameraProjection::Fisheye { mapping, fov } => self.fisheye_ray(x, y, mapping, fov),
+        }
+    }
+
+    /// Panoramic cameras have no lens, so every ray is in focus.
+    fn generate_offset_ray(&self, u: f32, v: f32, _rng: &mut impl Rng) -> Ray {
+        self.generate_ray(u, v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_camera(projection: CameraProjection) -> PanoramicCamera {
+        PanoramicCamera::new(Point3::origin(), Vector3::new(0.0, 0.0, -1.0), Vector3::y(), 2.0, projection)
+    }
+
+    fn assert_direction(ray: &Ray, expected: Vector3<f32>) {
+        assert!((ray.direction() - expected).norm() < 1e-4, "{:?} != {:?}", ray.direction(), expected);
+    }
+
+    #[test]
+    fn equirectangular_covers_the_sphere() {
+        let camera = create_camera(CameraProjection::Equirectangular(EquirectangularLayout::Mono));
+
+        assert_direction(&camera.generate_ray(0.5, 0.5), Vector3::new(0.0, 0.0, -1.0));
+        // A quarter of the image to the right looks along +X
+        assert_direction(&camera.generate_ray(0.25, 0.5), Vector3::new(1.0, 0.0, 0.0));
+        assert_direction(&camera.generate_ray(0.5, 1.0), Vector3::new(0.0, 1.0, 0.0));
+        assert_direction(&camera.generate_ray(1.0, 0.5), Vector3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn over_under_offsets_eyes() {
+        let camera = create_camera(CameraProjection::Equirectangular(EquirectangularLayout::OverUnder { eye_separation: 0.1 }));
+
+        let left = camera.generate_ray(0.5, 0.75);
+        let right = camera.generate_ray(0.5, 0.25);
+        assert_direction(&left, Vector3::new(0.0, 0.0, -1.0));
+        assert_direction(&right, Vector3::new(0.0, 0.0, -1.0));
+        assert!((left.origin() - Point3::new(-0.05, 0.0, 0.0)).norm() < 1e-5);
+        assert!((right.origin() - Point3::new(0.05, 0.0, 0.0)).norm() < 1e-5);
+    }
+
+    #[test]
+    fn fisheye_edges_match_fov() {
+        for mapping in [FisheyeMapping::Equidistant, FisheyeMapping::Equisolid] {
+            let camera = create_camera(CameraProjection::Fisheye { mapping, fov: 180.0 });
+
+            assert_direction(&camera.generate_ray(0.5, 0.5), Vector3::new(0.0, 0.0, -1.0));
+            assert_direction(&camera.generate_ray(0.0, 0.5), Vector3::new(1.0, 0.0, 0.0));
+        }
+
+        // The image is half as tall as it is wide, so its top edge is half as far from the center
+        let camera = create_camera(CameraProjection::Fisheye { mapping: FisheyeMapping::Equidistant, fov: 180.0 });
+        let top = camera.generate_ray(0.5, 1.0).direction();
+        assert!((top.y.atan2(-top.z) - PI / 4.0).abs() < 1e-4);
+    }
+}
@@ -1,8 +1,12 @@
-use std::f32::consts::PI;
 use nalgebra::{Matrix4, Point3, Vector2, Vector3};
 use rand::Rng;
+use crate::camera::aperture::Aperture;
 use crate::camera::viewpoint::Viewpoint;
 use crate::core::Ray;
+use crate::math::motion::TransformMotion;
+use crate::options::LensSettings;
+
+const PLANE_DISTANCE: f32 = 10.0;
 
 #[derive(Copy, Clone)]
 struct ViewPlane {
@@ -13,8 +17,8 @@ struct ViewPlane {
 }
 
 impl ViewPlane {
-    pub fn new(camera_origin: Point3<f32>, camera_direction: Vector3<f32>, camera_up: Vector3<f32>, yfov: f32, aspect_ratio: f32) -> Self {
-        const PLANE_DISTANCE:f32 = 10.0;
+    /// `shift` moves the image to the right, as a fraction of its width.
+    pub fn new(camera_origin: Point3<f32>, camera_direction: Vector3<f32>, camera_up: Vector3<f32>, yfov: f32, aspect_ratio: f32, shift: f32) -> Self {
         let plane_height = 2.0 * PLANE_DISTANCE * (yfov / 2.0).tan();
         let plane_width = plane_height * aspect_ratio;
 
@@ -24,7 +28,7 @@ impl ViewPlane {
         //let u_dir = n.cross(&camera_up).normalize();
         let v_dir = n.cross(&u_dir).normalize();
 
-        let plane_center = camera_origin - (n * PLANE_DISTANCE);
+        let plane_center = camera_origin - (n * PLANE_DISTANCE) + (u_dir * (shift * plane_width));
 
         let base = plane_center +
             (u_dir * (plane_width / 2.0)) -
@@ -41,9 +45,33 @@ impl ViewPlane {
     pub fn get_coordinates_from_uv(&self, u: f32, v: f32) -> Point3<f32> {
         self.base - (self.u_dir * u * self.size.x) + (self.v_dir * v * self.size.y)
     }
+
+    fn center(&self) -> Point3<f32> {
+        self.base - (self.u_dir * (self.size.x / 2.0)) + (self.v_dir * (self.size.y / 2.0))
+    }
 }
 
-#[derive(Copy, Clone)]
+/// A point in the scene connected to the camera lens, used for light tracing.
+pub struct CameraConnection {
+    pub lens_point: Point3<f32>,
+    /// Position on the image, x right and y down, in [0, 1).
+    pub image_position: Vector2<f32>,
+    /// Importance divided by the pdf of having picked `lens_point`, including the geometry term
+    /// between the lens and the connected point.
+    pub weight: f32,
+}
+
+/// Vertical field of view, in radians, and aperture radius, in scene units, of a physical lens.
+/// Without a focal length the lens keeps the field of view `yfov`, like the one of a glTF camera.
+pub fn lens_parameters(lens: &LensSettings, yfov: f32) -> (f32, f32) {
+    let focal_length = lens.focal_length.unwrap_or_else(|| lens.sensor_height / (2.0 * (yfov / 2.0).tan()));
+    let yfov = 2.0 * (lens.sensor_height / (2.0 * focal_length)).atan();
+    // Millimeters to meters
+    let aperture_radius = focal_length / lens.f_stop / 2.0 / 1000.0;
+    (yfov, aperture_radius)
+}
+
+#[derive(Clone)]
 pub struct PerspectiveCamera {
     origin: Point3<f32>,
     direction: Vector3<f32>,
@@ -53,7 +81,12 @@ pub struct PerspectiveCamera {
     view_plane: ViewPlane,
     focal_distance: f32,
     aperture_size: f32,
-
+    aperture: Aperture,
+    /// Distance the camera is moved to the right of its node, for stereo eyes.
+    eye_offset: f32,
+    /// Movement of the image to the right, as a fraction of its width.
+    image_shift: f32,
+    motion: Option<TransformMotion>,
 }
 
 impl PerspectiveCamera {
@@ -64,30 +97,159 @@ impl PerspectiveCamera {
             up,
             yfov,
             aspect_ratio,
-            view_plane: ViewPlane::new(origin, direction, up, yfov, aspect_ratio),
+            view_plane: ViewPlane::new(origin, direction, up, yfov, aspect_ratio, 0.0),
             focal_distance,
             aperture_size,
+            aperture: Aperture::Circle,
+            eye_offset: 0.0,
+            image_shift: 0.0,
+            motion: None,
         }
     }
 
+
     pub fn origin(&self) -> Point3<f32> {
         self.origin
     }
 
     pub fn update_transform(&mut self, transform: Matrix4<f32>) {
+        self.motion = None;
+        self.set_pose(transform);
+    }
+
+    /// Move from `start` to `end` while the shutter is open.
+    pub fn update_motion(&mut self, start: Matrix4<f32>, end: Matrix4<f32>) {
+        self.set_pose(start);
+        self.motion = TransformMotion::new(start, end);
+    }
+
+    /// The camera as it is at `time` in the shutter interval.
+    pub fn at_time(&self, time: f32) -> Self {
+        let mut camera = self.clone();
+        if let Some(motion) = &self.motion {
+            camera.set_pose(motion.interpolate(time));
+        }
+        camera
+    }
+
+    fn set_pose(&mut self, transform: Matrix4<f32>) {
         let position = transform.transform_point(&Point3::origin());
         let forward = transform.transform_vector(&Vector3::new(0.0, 0.0, -1.0)).normalize();
         let up = transform.transform_vector(&Vector3::new(0.0, 1.0, 0.0)).normalize();
 
-        self.origin = position;
+        self.origin = position + forward.cross(&up).normalize() * self.eye_offset;
         self.direction = forward;
         self.up = up;
-        self.view_plane = ViewPlane::new(self.origin, self.direction, self.up, self.yfov, self.aspect_ratio);
+        self.view_plane = ViewPlane::new(self.origin, self.direction, self.up, self.yfov, self.aspect_ratio, self.image_shift);
+    }
+
+    /// Camera for an eye `offset` to the right of this camera. With a convergence distance, the
+    /// image is shifted so the centers of the images of both eyes meet at that distance ahead.
+    pub fn stereo_eye(&self, offset: f32, convergence: Option<f32>) -> Self {
+        let right = self.forward().cross(&self.up).normalize();
+        let mut eye = self.clone();
+        eye.eye_offset = self.eye_offset + offset;
+        eye.image_shift = convergence.map_or(0.0, |distance| -offset * PLANE_DISTANCE / (distance * self.view_plane.size.x));
+        eye.origin = self.origin + right * offset;
+        eye.view_plane = ViewPlane::new(eye.origin, eye.direction, eye.up, eye.yfov, eye.aspect_ratio, eye.image_shift);
+        eye
     }
 
     pub fn set_focal_distance(&mut self, focal_distance: f32) {
         self.focal_distance = focal_distance;
     }
+
+    pub fn set_aperture(&mut self, aperture: Aperture) {
+        self.aperture = aperture;
+    }
+
+    pub fn forward(&self) -> Vector3<f32> {
+        self.direction.normalize()
+    }
+
+    /// Area of the image plane at unit distance from the camera.
+    fn image_area(&self) -> f32 {
+        (self.view_plane.size.x / PLANE_DISTANCE) * (self.view_plane.size.y / PLANE_DISTANCE)
+    }
+
+    /// Where a ray leaving the camera origin in `direction` lands on the image, x right and y down.
+    /// None if it misses the image.
+    pub fn image_position(&self, direction: &Vector3<f32>) -> Option<Vector2<f32>> {
+        let cos_theta = direction.normalize().dot(&self.forward());
+        if cos_theta <= 0.0 {
+            return None;
+        }
+
+        let plane_point = self.origin + direction.normalize() * (PLANE_DISTANCE / cos_theta);
+        let offset = plane_point - self.view_plane.center();
+        let u = offset.dot(&self.view_plane.u_dir) / self.view_plane.size.x + 0.5;
+        let v = 0.5 - offset.dot(&self.view_plane.v_dir) / self.view_plane.size.y;
+
+        ((0.0..1.0).contains(&u) && (0.0..1.0).contains(&v)).then(|| Vector2::new(u, v))
+    }
+
+    /// Solid angle pdf of a camera ray leaving the lens in `direction`.
+    pub fn pdf_direction(&self, direction: &Vector3<f32>) -> f32 {
+        if self.image_position(direction).is_none() {
+            return 0.0;
+        }
+
+        let cos_theta = direction.normalize().dot(&self.forward());
+        1.0 / (self.image_area() * cos_theta * cos_theta * cos_theta)
+    }
+
+    /// Point on the lens a camera ray leaves from.
+    fn sample_lens(&self, rng: &mut impl Rng) -> Point3<f32> {
+        if self.aperture_size <= 0.0 {
+            return self.origin;
+        }
+
+        let offset = self.aperture.sample(rng) * self.aperture_size;
+        self.origin + self.view_plane.u_dir * offset.x + self.view_plane.v_dir * offset.y
+    }
+
+    /// Pick a point on the lens, distributed like the origins of `generate_offset_ray`, and find
+    /// where the ray from it through `point` lands on the image.
+    pub fn connect(&self, point: &Point3<f32>, rng: &mut impl Rng) -> Option<CameraConnection> {
+        let lens_point = self.sample_lens(rng);
+
+        let to_point = point - lens_point;
+        let distance = to_point.norm();
+        if distance <= 0.0 {
+            return None;
+        }
+        let ray_direction = to_point / distance;
+
+        // Rays from the lens converge on a sphere of radius focal_distance around the origin;
+        // the pixel is the one whose direction passes through the same point of that sphere.
+        let (pixel_direction, focal_ratio) = if self.aperture_size > 0.0 {
+            let offset = lens_point - self.origin;
+            let b = offset.dot(&ray_direction);
+            let discriminant = b * b - offset.norm_squared() + self.focal_distance * self.focal_distance;
+            if discriminant < 0.0 {
+                return None;
+            }
+            let t = -b + discriminant.sqrt();
+            let pixel_direction = (offset + ray_direction * t).normalize();
+            let cos_focal = ray_direction.dot(&pixel_direction);
+            if cos_focal <= 0.0 {
+                return None;
+            }
+            (pixel_direction, t * t / (self.focal_distance * self.focal_distance * cos_focal))
+        } else {
+            (ray_direction, 1.0)
+        };
+
+        let image_position = self.image_position(&pixel_direction)?;
+        let cos_theta = pixel_direction.dot(&self.forward());
+        let weight = focal_ratio / (self.image_area() * cos_theta * cos_theta * cos_theta * distance * distance);
+
+        Some(CameraConnection {
+            lens_point,
+            image_position,
+            weight,
+        })
+    }
 }
 
 impl Viewpoint for PerspectiveCamera {
@@ -100,10 +262,7 @@ impl Viewpoint for PerspectiveCamera {
         let direction = self.view_plane.get_coordinates_from_uv(u, v) - self.origin;
         let focal_point = self.origin + direction.normalize() * self.focal_distance;
 
-        let angle = rng.random::<f32>() * PI * 2.0;
-        let length = rng.random::<f32>() * self.aperture_size;
-
-        let origin = self.origin + (self.view_plane.u_dir * angle.sin() * length) + (self.view_plane.v_dir * angle.cos() * length);
+        let origin = self.sample_lens(rng);
         let direction = (focal_point - origin).normalize();
 
         Ray::new(origin, direction)
@@ -0,0 +1,73 @@
+use crate::options::{ShutterCurve, ShutterSettings};
+
+/// Pick the time in the shutter interval a camera ray is traced at, from 0 (open) to 1 (closed),
+/// proportional to how far the shutter is open. Always 0 without a shutter.
+pub fn sample_time(shutter: Option<&ShutterSettings>, u: f32) -> f32 {
+    match shutter.map(|shutter| &shutter.curve) {
+        None => 0.0,
+        Some(ShutterCurve::Box) => u,
+        Some(ShutterCurve::Triangle) => sample_piecewise_linear(&[0.0, 1.0, 0.0], u),
+        Some(ShutterCurve::Custom(openness)) => sample_piecewise_linear(openness, u),
+    }
+}
+
+/// Sample [0, 1] proportional to a function linear between `values`, which are evenly spaced
+/// from 0 to 1.
+fn sample_piecewise_linear(values: &[f32], u: f32) -> f32 {
+    if values.len() < 2 {
+        return u;
+    }
+
+    let width = 1.0 / (values.len() - 1) as f32;
+    let segment_area = |(a, b): (f32, f32)| (a.max(0.0) + b.max(0.0)) * 0.5 * width;
+    let segments = || values.windows(2).map(|pair| (pair[0], pair[1]));
+
+    let total: f32 = segments().map(segment_area).sum();
+    if total <= 0.0 {
+        return u;
+    }
+
+    let mut remaining = u * total;
+    for (index, (a, b)) in segments().enumerate() {
+        let area = segment_area((a, b));
+        if remaining > area && index < values.len() - 2 {
+            remaining -= area;
+            continue;
+        }
+
+        // Solve a * x + slope * x^2 / 2 = remaining for the offset x into the segment
+        let (a, b) = (a.max(0.0), b.max(0.0));
+        let slope = (b - a) / width;
+        let x = if slope.abs() < 1e-6 {
+            remaining / a.max(1e-6)
+        } else {
+            (-a + (a * a + 2.0 * slope * remaining).max(0.0).sqrt()) / slope
+        };
+        return (index as f32 * width + x.clamp(0.0, width)).min(1.0);
+    }
+
+    u
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mean_time(curve: ShutterCurve) -> f32 {
+        let shutter = ShutterSettings { curve, ..Default::default() };
+        let count = 1000;
+        (0..count).map(|i| sample_time(Some(&shutter), (i as f32 + 0.5) / count as f32)).sum::<f32>() / count as f32
+    }
+
+    #[test]
+    fn samples_follow_shutter_curve() {
+        assert!((mean_time(ShutterCurve::Box) - 0.5).abs() < 1e-3);
+        assert!((mean_time(ShutterCurve::Triangle) - 0.5).abs() < 1e-3);
+        // Linear ramp up, so the mean of t weighted by t is 2/3
+        assert!((mean_time(ShutterCurve::Custom(vec![0.0, 1.0])) - 2.0 / 3.0).abs() < 1e-3);
+        // Only open in the second half
+        let late = ShutterSettings { curve: ShutterCurve::Custom(vec![0.0, 0.0, 1.0, 1.0]), ..Default::default() };
+        assert!(sample_time(Some(&late), 0.01) > 1.0 / 3.0);
+        assert_eq!(sample_time(None, 0.7), 0.0);
+    }
+}
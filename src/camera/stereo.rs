@@ -0,0 +1,102 @@
+use nalgebra::Vector3;
+use crate::camera::viewpoint::CameraImpl;
+use crate::frame::{Frame, PixelRect};
+use crate::options::{StereoConvergence, StereoLayout, StereoSettings};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+impl Eye {
+    /// File name safe name of the eye.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Eye::Left => "left",
+            Eye::Right => "right",
+        }
+    }
+}
+
+/// Cameras of the left and right eye, placed around `camera`. Only perspective cameras have eyes.
+pub fn eye_cameras(camera: &CameraImpl, settings: &StereoSettings) -> Option<[(Eye, CameraImpl); 2]> {
+    let CameraImpl::Perspective(camera) = camera else {
+        return None;
+    };
+
+    let convergence = match settings.convergence {
+        StereoConvergence::Parallel => None,
+        StereoConvergence::OffAxis(distance) => Some(distance),
+    };
+    let half_distance = settings.interocular_distance / 2.0;
+
+    Some([
+        (Eye::Left, CameraImpl::Perspective(camera.stereo_eye(-half_distance, convergence))),
+        (Eye::Right, CameraImpl::Perspective(camera.stereo_eye(half_distance, convergence))),
+    ])
+}
+
+/// Both eyes in one image. Separate eyes aren't combined.
+pub fn combine(left: &Frame, right: &Frame, layout: StereoLayout) -> Frame {
+    let (width, height) = (left.width(), left.height());
+    let (left, right) = (left.pixels(), right.pixels());
+    match layout {
+        StereoLayout::SideBySide => {
+            let rows = left.chunks_exact(width as usize).zip(right.chunks_exact(width as usize));
+            let pixels = rows.flat_map(|(left_row, right_row)| left_row.iter().chain(right_row)).copied().collect();
+            Frame::from_pixels(width * 2, height, pixels)
+        }
+        StereoLayout::OverUnder => Frame::from_pixels(width, height * 2, [left, right].concat()),
+        StereoLayout::Anaglyph => {
+            let pixels = left.iter().zip(&right).map(|(l, r)| Vector3::new(l.x, r.y, r.z)).collect();
+            Frame::from_pixels(width, height, pixels)
+        }
+        StereoLayout::Separate => unreachable!("separate eyes are not combined"),
+    }
+}
+
+/// Where `rect` of each eye ends up in the image `combine` makes of eyes `width` by `height`.
+pub fn combined_rects(rect: &PixelRect, layout: StereoLayout, width: usize, height: usize) -> Vec<PixelRect> {
+    match layout {
+        StereoLayout::SideBySide => vec![*rect, PixelRect { x: rect.x + width, ..*rect }],
+        StereoLayout::OverUnder => vec![*rect, PixelRect { y: rect.y + height, ..*rect }],
+        StereoLayout::Anaglyph | StereoLayout::Separate => vec![*rect],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Point3;
+    use crate::camera::perspective_camera::PerspectiveCamera;
+    use crate::camera::viewpoint::Viewpoint;
+
+    #[test]
+    fn off_axis_eyes_converge() {
+        let camera = CameraImpl::Perspective(PerspectiveCamera::new(Point3::origin(), Vector3::new(0.0, 0.0, -1.0), Vector3::y(), 1.5, 1.0, 1.0, 0.0));
+        let settings = StereoSettings { interocular_distance: 0.1, convergence: StereoConvergence::OffAxis(4.0), ..Default::default() };
+        let [(_, left), (_, right)] = eye_cameras(&camera, &settings).unwrap();
+
+        assert!((left.origin() - Point3::new(-0.05, 0.0, 0.0)).norm() < 1e-5);
+        assert!((right.origin() - Point3::new(0.05, 0.0, 0.0)).norm() < 1e-5);
+
+        // The centers of both images look at the convergence point
+        for eye in [left, right] {
+            let ray = eye.generate_ray(0.5, 0.5);
+            let to_convergence = (Point3::new(0.0, 0.0, -4.0) - ray.origin()).normalize();
+            assert!((ray.direction() - to_convergence).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn side_by_side_puts_left_eye_on_the_left() {
+        let mut left = Frame::new(2, 1);
+        let right = Frame::new(2, 1);
+        left.add_sample(1, 0, Vector3::repeat(1.0));
+
+        let combined = combine(&left, &right, StereoLayout::SideBySide);
+        let values: Vec<f32> = combined.pixels().iter().map(|p| p.x).collect();
+        assert_eq!(values, vec![0.0, 1.0, 0.0, 0.0]);
+    }
+}
@@ -1,4 +1,9 @@
+use nalgebra::{Matrix4, Point3, Vector3};
 use rand::Rng;
+use crate::camera::aperture::Aperture;
+use crate::camera::orthographic_camera::OrthographicCamera;
+use crate::camera::panoramic_camera::PanoramicCamera;
+use crate::camera::perspective_camera::{CameraConnection, PerspectiveCamera};
 use crate::core::Ray;
 
 pub trait Viewpoint {
@@ -6,4 +11,106 @@ pub trait Viewpoint {
 
     #[allow(dead_code)]
     fn generate_offset_ray(&self, u: f32, v: f32, rng: &mut impl Rng) -> Ray;
-}
\ No newline at end of file
+}
+
+#[derive(Clone)]
+pub enum CameraImpl {
+    Perspective(PerspectiveCamera),
+    Orthographic(OrthographicCamera),
+    Panoramic(PanoramicCamera),
+}
+
+impl Viewpoint for CameraImpl {
+    fn generate_ray(&self, u: f32, v: f32) -> Ray {
+        match self {
+            CameraImpl::Perspective(c) => c.generate_ray(u, v),
+            CameraImpl::Orthographic(c) => c.generate_ray(u, v),
+            CameraImpl::Panoramic(c) => c.generate_ray(u, v),
+        }
+    }
+
+    fn generate_offset_ray(&self, u: f32, v: f32, rng: &mut impl Rng) -> Ray {
+        match self {
+            CameraImpl::Perspective(c) => c.generate_offset_ray(u, v, rng),
+            CameraImpl::Orthographic(c) => c.generate_offset_ray(u, v, rng),
+            CameraImpl::Panoramic(c) => c.generate_offset_ray(u, v, rng),
+        }
+    }
+}
+
+impl CameraImpl {
+    pub fn origin(&self) -> Point3<f32> {
+        match self {
+            CameraImpl::Perspective(c) => c.origin(),
+            CameraImpl::Orthographic(c) => c.origin(),
+            CameraImpl::Panoramic(c) => c.origin(),
+        }
+    }
+
+    pub fn update_transform(&mut self, transform: Matrix4<f32>) {
+        match self {
+            CameraImpl::Perspective(c) => c.update_transform(transform),
+            CameraImpl::Orthographic(c) => c.update_transform(transform),
+            CameraImpl::Panoramic(c) => c.update_transform(transform),
+        }
+    }
+
+    /// Move from `start` to `end` while the shutter is open.
+    pub fn update_motion(&mut self, start: Matrix4<f32>, end: Matrix4<f32>) {
+        match self {
+            CameraImpl::Perspective(c) => c.update_motion(start, end),
+            CameraImpl::Orthographic(c) => c.update_motion(start, end),
+            CameraImpl::Panoramic(c) => c.update_motion(start, end),
+        }
+    }
+
+    /// The camera as it is at `time` in the shutter interval.
+    pub fn at_time(&self, time: f32) -> Self {
+        match self {
+            CameraImpl::Perspective(c) => CameraImpl::Perspective(c.at_time(time)),
+            CameraImpl::Orthographic(c) => CameraImpl::Orthographic(c.at_time(time)),
+            CameraImpl::Panoramic(c) => CameraImpl::Panoramic(c.at_time(time)),
+        }
+    }
+
+    /// Like `generate_offset_ray`, for a ray traced at `time` in the shutter interval.
+    pub fn generate_ray_at_time(&self, u: f32, v: f32, time: f32, rng: &mut impl Rng) -> Ray {
+        self.at_time(time).generate_offset_ray(u, v, rng).with_time(time)
+    }
+
+    /// Only perspective cameras have a lens to focus.
+    pub fn set_focal_distance(&mut self, focal_distance: f32) {
+        if let CameraImpl::Perspective(c) = self {
+            c.set_focal_distance(focal_distance);
+        }
+    }
+
+    /// Only perspective cameras have a lens to shape.
+    pub fn set_aperture(&mut self, aperture: Aperture) {
+        if let CameraImpl::Perspective(c) = self {
+            c.set_aperture(aperture);
+        }
+    }
+
+    /// Whether light subpaths can be connected to the lens with `connect`. Only perspective
+    /// cameras support it, the others are treated like a delta distribution by light tracing.
+    pub fn can_connect(&self) -> bool {
+        matches!(self, CameraImpl::Perspective(_))
+    }
+
+    /// Solid angle pdf of a camera ray leaving the lens in `direction`, 0 unless `can_connect`.
+    pub fn pdf_direction(&self, direction: &Vector3<f32>) -> f32 {
+        match self {
+            CameraImpl::Perspective(c) => c.pdf_direction(direction),
+            _ => 0.0,
+        }
+    }
+
+    /// See [`PerspectiveCamera::connect`]. Always None unless `can_connect`.
+    pub fn connect(&self, point: &Point3<f32>, rng: &mut impl Rng) -> Option<CameraConnection> {
+        match self {
+            CameraImpl::Perspective(c) => c.connect(point, rng),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::OnceLock;
+use nalgebra::{Matrix3, Vector3};
+use crate::options::{ColorSettings, RgbSpace};
+
+/// Linear Rec.709 to CIE XYZ.
+fn rec709_to_xyz() -> Matrix3<f32> {
+    Matrix3::new(
+        0.4124564, 0.3575761, 0.1804375,
+        0.2126729, 0.7151522, 0.0721750,
+        0.0193339, 0.119192, 0.9503041,
+    )
+}
+
+/// Matrix converting linear Rec.709 colors, like those of glTF files, to `space`.
+pub fn from_rec709(space: RgbSpace) -> Matrix3<f32> {
+    match space {
+        RgbSpace::LinearRec709 => Matrix3::identity(),
+        // Adapted from D65 to the ACES white with Bradford
+        RgbSpace::AcesCg => Matrix3::new(
+            0.6130973, 0.3395229, 0.0473793,
+            0.0701942, 0.9163556, 0.0134526,
+            0.0206156, 0.1095698, 0.8698151,
+        ),
+    }
+}
+
+/// Matrix converting colors in `space` to linear Rec.709.
+pub fn to_rec709(space: RgbSpace) -> Matrix3<f32> {
+    from_rec709(space).try_inverse().expect("color space matrices are invertible")
+}
+
+/// Matrix in linear Rec.709 making light of a black body at `temperature` kelvin white, by
+/// adapting its white to D65 with Bradford.
+pub fn white_balance(temperature: f32) -> Matrix3<f32> {
+    let bradford = Matrix3::new(
+        0.8951, 0.2664, -0.1614,
+        -0.7502, 1.7135, 0.0367,
+        0.0389, -0.0685, 1.0296,
+    );
+    let cone_response = |(x, y): (f32, f32)| bradford * Vector3::new(x / y, 1.0, (1.0 - x - y) / y);
+    let source = cone_response(planckian_chromaticity(temperature));
+    let target = cone_response((0.3127, 0.3290));
+
+    let adaptation = bradford.try_inverse().unwrap() * Matrix3::from_diagonal(&target.component_div(&source)) * bradford;
+    rec709_to_xyz().try_inverse().unwrap() * adaptation * rec709_to_xyz()
+}
+
+/// Matrix from the working space to the output space of `settings`, white balanced.
+pub fn output_matrix(settings: &ColorSettings) -> Matrix3<f32> {
+    from_rec709(settings.output_space) * display_matrix(settings)
+}
+
+/// Matrix from the working space of `settings` to linear Rec.709 for displays, white balanced.
+pub fn display_matrix(settings: &ColorSettings) -> Matrix3<f32> {
+    let balance = settings.white_balance.map_or(Matrix3::identity(), white_balance);
+    balance * to_rec709(settings.working_space)
+}
+
+/// Linear value of an 8 bit sRGB encoded value.
+pub fn srgb_to_linear(value: u8) -> f32 {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        std::array::from_fn(|index| {
+            let encoded = index as f32 / 255.0;
+            if encoded <= 0.04045 { encoded / 12.92 } else { ((encoded + 0.055) / 1.055).powf(2.4) }
+        })
+    });
+    table[value as usize]
+}
+
+/// CIE xy of the Planckian locus at `temperature` kelvin, from the fit of Kim et al.
+fn planckian_chromaticity(temperature: f32) -> (f32, f32) {
+    let t = temperature.clamp(1667.0, 25000.0) as f64;
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / (t * t * t) - 0.2343589e6 / (t * t) + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / (t * t * t) + 2.1070379e6 / (t * t) + 0.2226347e3 / t + 0.240390
+    };
+    let y = if t <= 2222.0 {
+        -1.1063814 * x * x * x - 1.34811020 * x * x + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x * x * x - 1.37418593 * x * x + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x * x * x - 5.87338670 * x * x + 3.75112997 * x - 0.37001483
+    };
+    (x as f32, y as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions_keep_white_and_white_balance_cools_warm_light() {
+        assert_eq!(srgb_to_linear(0), 0.0);
+        assert_eq!(srgb_to_linear(255), 1.0);
+        assert!((srgb_to_linear(128) - 0.2158605).abs() < 1e-5);
+
+        let white = Vector3::repeat(1.0);
+        assert!((from_rec709(RgbSpace::AcesCg) * white - white).norm() < 1e-3);
+        assert!((to_rec709(RgbSpace::AcesCg) * from_rec709(RgbSpace::AcesCg) - Matrix3::identity()).norm() < 1e-5);
+
+        // White under candle light is orange, so balancing for it makes white blue
+        let balanced = white_balance(2500.0) * white;
+        assert!(balanced.z > balanced.y && balanced.y > balanced.x);
+        assert!((white_balance(6500.0) * white - white).amax() < 0.05);
+    }
+}
@@ -0,0 +1,19 @@
+use std::sync::Arc;
+use crate::camera::aperture::Aperture;
+use crate::context::Context;
+use crate::math::distribution::Distribution2D;
+
+pub fn load_aperture_mask(file: &str, ctx: &Context) -> anyhow::Result<Aperture> {
+    println!("Loading aperture mask {}..", file);
+    let img = image::open(file)?.to_luma32f();
+    let (width, height) = img.dimensions();
+
+    ctx.mem.texture_memory_bytes(width as u64 * height as u64 * size_of::<f32>() as u64);
+
+    let func: Vec<f32> = img.pixels().map(|p| p[0].max(0.0)).collect();
+    if func.iter().all(|value| *value <= 0.0) {
+        anyhow::bail!("aperture mask {} lets no light through", file);
+    }
+
+    Ok(Aperture::Mask(Arc::new(Distribution2D::new(&func, width as usize, height as usize))))
+}
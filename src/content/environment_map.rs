@@ -0,0 +1,23 @@
+use nalgebra::Vector3;
+use crate::color;
+use crate::context::Context;
+use crate::options::{EnvironmentMapSettings, RgbSpace};
+use crate::scene::environment::EnvironmentLight;
+
+/// Environment light of the map in `settings`, read as linear Rec.709 and converted to
+/// `working_space`.
+pub fn load_environment_map(settings: &EnvironmentMapSettings, working_space: RgbSpace, ctx: &Context) -> anyhow::Result<EnvironmentLight> {
+    println!("Loading environment map {}..", settings.file);
+    let img = image::open(&settings.file)?.to_rgb32f();
+    let (width, height) = img.dimensions();
+
+    ctx.mem.texture_memory_bytes(width as u64 * height as u64 * size_of::<Vector3<f32>>() as u64);
+
+    let to_working_space = color::from_rec709(working_space);
+    let pixels = img
+        .pixels()
+        .map(|p| to_working_space * Vector3::new(p[0], p[1], p[2]))
+        .collect();
+
+    Ok(EnvironmentLight::new(pixels, width, height, settings.rotation.to_radians(), settings.intensity))
+}
@@ -1,15 +1,21 @@
 use crate::animation::controller::AnimationController;
 use crate::animation::{Animation, AnimationChannel, AnimationOutputs};
-use crate::camera::perspective_camera::PerspectiveCamera;
+use crate::camera::orthographic_camera::OrthographicCamera;
+use crate::camera::panoramic_camera::PanoramicCamera;
+use crate::camera::aperture::Aperture;
+use crate::camera::perspective_camera::{lens_parameters, PerspectiveCamera};
+use crate::camera::viewpoint::CameraImpl;
+use crate::color;
 use crate::content::gltf::material::create_material;
 use crate::content::mesh::{MeshData, MeshInstance};
 use crate::content::scene_loader::{SceneError, SceneLoader};
 use crate::content::triangle::Vertex;
-use crate::options::{FocalDistance, RenderOptions};
-use crate::scene::light::{DirectionalLight, LightSource, PointLight};
+use crate::options::{ApertureShape, Environment, FocalDistance, FocusKeyframe, RenderOptions, RgbSpace, SkySettings, SunPosition};
+use crate::scene::light::{DirectionalLight, LightSource, PointLight, SpotLight};
 use crate::scene::material::Material;
 use crate::scene::node_graph::{NodeGraph, NodeTransform, SceneNode};
 use crate::scene::scene::Scene;
+use crate::scene::sky::SkyLight;
 use gltf::animation::util::{ReadOutputs, Rotations};
 use gltf::animation::Interpolation;
 use gltf::buffer::Data;
@@ -22,14 +28,61 @@ use serde::Deserialize;
 use std::path::Path;
 use std::sync::Arc;
 use crate::context::Context;
+use crate::content::aperture_mask::load_aperture_mask;
+use crate::content::environment_map::load_environment_map;
 
 #[derive(Deserialize)]
 struct PointLightExtras {
     radius: Option<f32>,
 }
 
+/// Focus of a camera, set on the camera or on the node holding it.
+#[derive(Deserialize)]
+struct CameraExtras {
+    /// Focal distance, in scene units.
+    focus_distance: Option<f32>,
+    /// Name of the node to keep in focus.
+    focus_object: Option<String>,
+    /// Pairs of time, in seconds, and focal distance, pulled between over the animation.
+    focus_keyframes: Option<Vec<(f32, f32)>>,
+}
+
 pub struct GltfLoader{}
 
+/// What every node of a scene is loaded with.
+#[derive(Copy, Clone)]
+struct NodeInputs<'a> {
+    buffers: &'a [Data],
+    folder: &'a Path,
+    options: &'a RenderOptions,
+    ctx: &'a Context,
+}
+
+/// What loading the nodes of a scene collects.
+struct SceneContents {
+    cameras: Vec<CameraImpl>,
+    lights: Vec<LightSource>,
+    meshes: Vec<MeshInstance>,
+    materials: Vec<Material>,
+    /// Data of each glTF mesh, once a node has loaded it.
+    mesh_data_map: Vec<Option<Vec<Arc<MeshData>>>>,
+    /// Index into `materials` of each glTF material, offset by one for the default material.
+    material_map: Vec<Option<u32>>,
+}
+
+impl SceneContents {
+    fn new(mesh_count: usize, material_count: usize) -> Self {
+        Self {
+            cameras: Vec::new(),
+            lights: Vec::new(),
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            mesh_data_map: vec![None; mesh_count],
+            material_map: vec![None; material_count + 1],
+        }
+    }
+}
+
 impl GltfLoader {
 
     fn extract_point_light_radius(light: &gltf::khr_lights_punctual::Light<'_>) -> f32 {
@@ -43,6 +96,63 @@ impl GltfLoader {
             .unwrap_or(0.0)
     }
 
+    fn extract_camera_focus(node: &Node) -> Option<FocalDistance> {
+        let camera_extras = node.camera().and_then(|camera| camera.extras().clone());
+        [camera_extras, node.extras().clone()]
+            .into_iter()
+            .flatten()
+            .filter_map(|extras| gltf::json::deserialize::from_str::<CameraExtras>(extras.get()).ok())
+            .find_map(|extras| {
+                if let Some(keyframes) = extras.focus_keyframes {
+                    Self::focus_pull(keyframes)
+                } else if let Some(name) = extras.focus_object {
+                    Some(FocalDistance::Object(name))
+                } else {
+                    extras.focus_distance.map(FocalDistance::Fixed)
+                }
+            })
+    }
+
+    /// Focus pulled between `keyframes` of time and focal distance, given in any order. Keyframes
+    /// with a time that isn't a number leave the focus unset.
+    fn focus_pull(mut keyframes: Vec<(f32, f32)>) -> Option<FocalDistance> {
+        if keyframes.iter().any(|(time, _)| time.is_nan()) {
+            eprintln!("Warning: focus keyframe time is not a number, ignoring the focus keyframes");
+            return None;
+        }
+
+        keyframes.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        let keyframes = keyframes.into_iter()
+            .map(|(time, distance)| FocusKeyframe { time, focus: FocalDistance::Fixed(distance) })
+            .collect();
+        Some(FocalDistance::Pull(keyframes))
+    }
+
+    fn add_sky(settings: &SkySettings, working_space: RgbSpace, lights: &mut Vec<LightSource>) -> anyhow::Result<()> {
+        let create_sky = |sun_direction: Vector3<f32>| {
+            LightSource::Sky(SkyLight::new(sun_direction, settings.turbidity, settings.sun_angular_diameter.to_radians(), settings.ground_albedo, settings.intensity, working_space))
+        };
+
+        match settings.sun {
+            SunPosition::Angles { elevation, azimuth } => {
+                let (elevation, azimuth) = (elevation.to_radians(), azimuth.to_radians());
+                let sun_direction = Vector3::new(elevation.cos() * azimuth.sin(), elevation.sin(), -elevation.cos() * azimuth.cos());
+                lights.push(create_sky(sun_direction));
+            }
+            SunPosition::DirectionalLight => {
+                // The sky takes the light's place, so the node animating the light moves the sun instead.
+                let light_index = lights
+                    .iter()
+                    .position(|light| matches!(light, LightSource::Directional(_)))
+                    .ok_or(SceneError::NoDirectionalLight)?;
+                let LightSource::Directional(light) = &lights[light_index] else { unreachable!() };
+                lights[light_index] = create_sky(-light.direction);
+            }
+        }
+
+        Ok(())
+    }
+
     fn build_fallback_tangents(
         positions: &[Point3<f32>],
         normals: &[Vector3<f32>],
@@ -122,7 +232,8 @@ impl GltfLoader {
         Point3::new(transform[(0, 3)], transform[(1, 3)], transform[(2, 3)])
     }
 
-    fn create_mesh_data(buffers: &Vec<Data>, mesh: &gltf::mesh::Mesh, materials: &mut Vec<Material>, material_map: &mut Vec<Option<u32>>, folder: &Path, ctx: &Context) -> anyhow::Result<Vec<Arc<MeshData>>> {
+    fn create_mesh_data(mesh: &gltf::mesh::Mesh, inputs: &NodeInputs, materials: &mut Vec<Material>, material_map: &mut [Option<u32>]) -> anyhow::Result<Vec<Arc<MeshData>>> {
+        let NodeInputs { buffers, folder, options, ctx } = *inputs;
         let mut meshes = Vec::new();
 
         for primitive in mesh.primitives() {
@@ -137,7 +248,7 @@ impl GltfLoader {
             let material_index = if material_map[material_node_index].is_some() {
                 material_map[material_node_index].unwrap()
             } else {
-                materials.push(create_material(&primitive.material(), folder, ctx)?);
+                materials.push(create_material(&primitive.material(), options.color.working_space, folder, ctx)?);
                 material_map[material_node_index] = Some(materials.len() as u32 - 1);
 
                 materials.len() as u32 - 1
@@ -207,18 +318,21 @@ impl GltfLoader {
         Ok(meshes)
     }
 
-    fn create_scene_node(node: &Node, buffers: &Vec<Data>, cameras: &mut Vec<PerspectiveCamera>, lights: &mut Vec<LightSource>, meshes: &mut Vec<MeshInstance>, mesh_data_map: &mut Vec<Option<Vec<Arc<MeshData>>>>, materials: &mut Vec<Material>, material_map: &mut Vec<Option<u32>>, folder: &Path, parent_transform: &Matrix4<f32>, options: &RenderOptions, ctx: &Context) -> anyhow::Result<SceneNode> {
+    fn create_scene_node(node: &Node, parent_transform: &Matrix4<f32>, inputs: &NodeInputs, contents: &mut SceneContents) -> anyhow::Result<SceneNode> {
         let transform = parent_transform * Matrix4::from(node.transform().matrix());
         let children = node.children().map(|child|{
-            Self::create_scene_node(&child, buffers, cameras, lights, meshes, mesh_data_map, materials, material_map, folder, &transform, options, ctx)
+            Self::create_scene_node(&child, &transform, inputs, contents)
         }).collect::<anyhow::Result<Vec<SceneNode>>>()?;
 
+        let options = inputs.options;
+        let SceneContents { cameras, lights, meshes, materials, mesh_data_map, material_map } = contents;
+
         let mut mesh_indices = Vec::new();
         if let Some(mesh) = node.mesh() {
             let mesh_data = if mesh_data_map[mesh.index()].is_some() {
                 mesh_data_map[mesh.index()].clone().unwrap()
             } else {
-                let data = Self::create_mesh_data(&buffers, &mesh, materials, material_map, folder, ctx)?;
+                let data = Self::create_mesh_data(&mesh, inputs, materials, material_map)?;
                 mesh_data_map[mesh.index()] = Some(data.clone());
                 data
             };
@@ -231,47 +345,65 @@ impl GltfLoader {
 
         let mut light_index = None;
         if let Some(light) = node.light() {
+            let to_working_space = color::from_rec709(options.color.working_space);
             match light.kind() {
                 Kind::Point => {
                     let position = Self::extract_translation(&transform);
                     let intensity = light.intensity();
                     let radius = Self::extract_point_light_radius(&light);
 
-                    let color = light.color();
+                    let color = to_working_space * Vector3::from(light.color());
                     light_index = Some(lights.len());
-                    lights.push(LightSource::Point(PointLight::new(position, Vector3::new(color[0], color[1], color[2]), intensity, radius)))
+                    lights.push(LightSource::Point(PointLight::new(position, color, intensity, radius)))
                 },
                 Kind::Directional => {
                     let direction = transform.transform_vector(&Vector3::new(0.0, 0.0, -1.0));
                     let intensity = light.intensity();
-                    let color = light.color();
+                    let color = to_working_space * Vector3::from(light.color());
                     light_index = Some(lights.len());
-                    lights.push(LightSource::Directional(DirectionalLight::new(direction, Vector3::new(color[0], color[1], color[2]), intensity)))
+                    lights.push(LightSource::Directional(DirectionalLight::new(direction, color, intensity)))
 
                 }
-                _ => {}
+                Kind::Spot { inner_cone_angle, outer_cone_angle } => {
+                    let position = Self::extract_translation(&transform);
+                    let direction = transform.transform_vector(&Vector3::new(0.0, 0.0, -1.0));
+                    let intensity = light.intensity();
+                    let color = to_working_space * Vector3::from(light.color());
+                    light_index = Some(lights.len());
+                    lights.push(LightSource::Spot(SpotLight::new(position, direction, color, intensity, inner_cone_angle, outer_cone_angle)))
+                }
             }
         }
 
         let mut camera_index = None;
         if let Some(camera) = node.camera() {
 
-            let projection = camera.projection();
-
-            let perspective = match projection {
-                Projection::Orthographic(_) =>
-                    return Err(SceneError::UnsupportedFormat("Orthographic projection not supported".to_string()).into()),
-                Projection::Perspective(x) => x
-            };
-
             let (_, up, forward) = Self::extract_directions(&transform);
             let origin = Self::extract_translation(&transform);
-
             let aspect_ratio = options.resolution.width as f32 / options.resolution.height as f32;
 
+            let projection_override = [camera.name(), node.name()].into_iter()
+                .flatten()
+                .find_map(|name| options.camera_projections.get(name));
+
             camera_index = Some(cameras.len());
-            let dof_settings = options.depth_of_field.clone().unwrap_or_default();
-            cameras.push(PerspectiveCamera::new(origin, forward, up, aspect_ratio, perspective.yfov(), 1.0, dof_settings.aperture_size))
+            if let Some(projection) = projection_override {
+                cameras.push(CameraImpl::Panoramic(PanoramicCamera::new(origin, forward, up, aspect_ratio, *projection)))
+            } else {
+                match camera.projection() {
+                    Projection::Orthographic(orthographic) => {
+                        cameras.push(CameraImpl::Orthographic(OrthographicCamera::new(origin, forward, up, orthographic.xmag(), orthographic.ymag())))
+                    },
+                    Projection::Perspective(perspective) => {
+                        let dof_settings = options.depth_of_field.clone().unwrap_or_default();
+                        let (yfov, aperture_size) = match &dof_settings.lens {
+                            Some(lens) => lens_parameters(lens, perspective.yfov()),
+                            None => (perspective.yfov(), dof_settings.aperture_size),
+                        };
+                        cameras.push(CameraImpl::Perspective(PerspectiveCamera::new(origin, forward, up, aspect_ratio, yfov, 1.0, aperture_size)))
+                    }
+                }
+            }
         }
 
         let (translation, rotation, scale) = node.transform().decomposed();
@@ -286,24 +418,23 @@ impl GltfLoader {
             ),
             mesh_indices,
             camera_index,
+            camera_name: node.camera().and_then(|camera| camera.name().map(|s| s.to_string())),
+            camera_focus: Self::extract_camera_focus(node),
             light_index,
             children,
         })
     }
 
 
-    fn load_node_graph(scene: &gltf::scene::Scene, buffers: &Vec<Data>, cameras: &mut Vec<PerspectiveCamera>, lights: &mut Vec<LightSource>, meshes: &mut Vec<MeshInstance>, materials: &mut Vec<Material>, folder: &Path, total_mesh_count: usize, total_material_count: usize, options: &RenderOptions, ctx: &Context) -> anyhow::Result<NodeGraph> {
-        let mut mesh_data_map :Vec<Option<Vec<Arc<MeshData>>>> = vec![None; total_mesh_count];
-        let mut material_map : Vec<Option<u32>> = vec![None; total_material_count + 1];
-
+    fn load_node_graph(scene: &gltf::scene::Scene, inputs: &NodeInputs, contents: &mut SceneContents) -> anyhow::Result<NodeGraph> {
         let nodes = scene.nodes().map(|node|{
-            Self::create_scene_node(&node, buffers, cameras, lights, meshes, &mut mesh_data_map, materials, &mut material_map, folder, &Matrix4::identity(), options, ctx)
+            Self::create_scene_node(&node, &Matrix4::identity(), inputs, contents)
         }).collect::<anyhow::Result<Vec<SceneNode>>>()?;
 
         Ok(NodeGraph::new(nodes))
     }
 
-    fn load_animations(document: &gltf::Document, buffers: &Vec<Data>) -> anyhow::Result<Vec<Animation>> {
+    fn load_animations(document: &gltf::Document, buffers: &[Data]) -> anyhow::Result<Vec<Animation>> {
         let mut animations = Vec::new();
         for animation in document.animations() {
             let mut channels = Vec::new();
@@ -367,20 +498,62 @@ impl SceneLoader for GltfLoader {
 
         if let Some(scene) = document.default_scene() {
 
-            let mut cameras = Vec::new();
-            let mut lights = Vec::new();
-            let mut meshes = Vec::new();
-            let mut materials = Vec::new();
-            let node_graph = Self::load_node_graph(&scene, &buffers, &mut cameras, &mut lights, &mut meshes, &mut materials, parent_folder, document.meshes().len(), document.materials().len(), options, ctx)?;
+            let inputs = NodeInputs { buffers: &buffers, folder: parent_folder, options, ctx };
+            let mut contents = SceneContents::new(document.meshes().len(), document.materials().len());
+            let node_graph = Self::load_node_graph(&scene, &inputs, &mut contents)?;
+            let SceneContents { mut cameras, mut lights, meshes, materials, .. } = contents;
             let animations = Self::load_animations(&document, &buffers)?;
 
+            match &options.environment {
+                Environment::None => {}
+                Environment::Map(settings) => lights.push(LightSource::Environment(load_environment_map(settings, options.color.working_space, ctx)?)),
+                Environment::Sky(settings) => Self::add_sky(settings, options.color.working_space, &mut lights)?,
+            }
+
             if cameras.is_empty() { return Err(SceneError::NoCameras.into()); }
 
-            let scene = Scene::new(cameras, meshes, materials, lights);
+            if let Some(dof) = &options.depth_of_field {
+                let aperture = match &dof.aperture_shape {
+                    ApertureShape::Circle => Aperture::Circle,
+                    ApertureShape::Polygon { blades, rotation } => Aperture::Polygon { blades: *blades, rotation: rotation.to_radians() },
+                    ApertureShape::Mask(file) => load_aperture_mask(file, ctx)?,
+                };
+                for camera in &mut cameras {
+                    camera.set_aperture(aperture.clone());
+                }
+            }
+
+            let selected_cameras = options.cameras.iter()
+                .map(|selector| node_graph.find_camera(selector)
+                    .filter(|&index| index < cameras.len())
+                    .ok_or_else(|| SceneError::CameraNotFound(selector.to_string())))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut scene = Scene::new(cameras, meshes, materials, lights, options.light_sampling);
+            if !selected_cameras.is_empty() {
+                scene.set_selected_cameras(selected_cameras);
+            }
             println!("Loaded scene {}", scene);
 
             Ok((scene, node_graph, AnimationController::new(animations)))
         }
         else { Err(SceneError::NoDefaultScene.into()) }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn focus_keyframes_are_sorted_by_time() {
+        let Some(FocalDistance::Pull(keyframes)) = GltfLoader::focus_pull(vec![(2.0, 5.0), (0.0, 1.0), (1.0, 3.0)]) else {
+            panic!("expected a focus pull");
+        };
+        let times: Vec<f32> = keyframes.iter().map(|keyframe| keyframe.time).collect();
+        assert_eq!(times, [0.0, 1.0, 2.0]);
+        assert!(matches!(keyframes[0].focus, FocalDistance::Fixed(distance) if distance == 1.0));
+
+        assert!(GltfLoader::focus_pull(vec![(0.0, 1.0), (f32::NAN, 2.0)]).is_none());
+    }
+}
@@ -1,5 +1,7 @@
 use crate::scene::material::Material;
-use crate::scene::texture::{Texture, WrapMode};
+use crate::color;
+use crate::options::RgbSpace;
+use crate::scene::texture::{ColorSpace, Texture, WrapMode};
 use gltf::image::Source;
 use gltf::material::NormalTexture;
 use gltf::texture;
@@ -26,7 +28,7 @@ fn extract_invert_albedo_flag(material: &gltf::material::Material) -> bool {
         .unwrap_or(false)
 }
 
-fn create_texture_internal(texture: &texture::Texture, folder: &Path, ctx: &Context) -> Texture {
+fn create_texture_internal(texture: &texture::Texture, color_space: ColorSpace, working_space: RgbSpace, folder: &Path, ctx: &Context) -> Texture {
     let source = texture.source();
     let wrap_mode = match texture.sampler().wrap_s() {
         WrappingMode::ClampToEdge => WrapMode::ClampToEdge,
@@ -44,31 +46,34 @@ fn create_texture_internal(texture: &texture::Texture, folder: &Path, ctx: &Cont
                 Err(e) => panic!("Failed to load image {}. Error: {}", image_path.display(), e)
             };
             ctx.mem.texture_memory_bytes(img.width() as u64 * img.height() as u64 * 4);
-            Texture::new(img.to_rgba8().to_vec(), img.width(), img.height(), wrap_mode)
+            Texture::new(img.to_rgba8().to_vec(), img.width(), img.height(), wrap_mode, color_space).in_working_space(working_space)
         }
     }
 }
-fn create_normal_texture(texture: &Option<NormalTexture>, folder: &Path, ctx: &Context) -> Option<Texture> {
+fn create_normal_texture(texture: &Option<NormalTexture>, working_space: RgbSpace, folder: &Path, ctx: &Context) -> Option<Texture> {
     texture.as_ref().map(|x| {
         let texture = x.texture();
-        create_texture_internal(&texture, folder, ctx)
+        create_texture_internal(&texture, ColorSpace::Linear, working_space, folder, ctx)
     })
 }
 
-fn create_texture(texture: &Option<texture::Info<'_>>, folder: &Path, ctx: &Context) -> Option<Texture> {
+fn create_texture(texture: &Option<texture::Info<'_>>, color_space: ColorSpace, working_space: RgbSpace, folder: &Path, ctx: &Context) -> Option<Texture> {
     texture.as_ref().map(|x| {
         let texture = x.texture();
-        create_texture_internal(&texture, folder, ctx)
+        create_texture_internal(&texture, color_space, working_space, folder, ctx)
     })
 }
 
-pub fn create_material(material: &gltf::Material, folder: &Path, ctx: &Context) -> anyhow::Result<Material> {
-    let albedo_texture = create_texture(&material.pbr_metallic_roughness().base_color_texture(), folder, ctx);
-    let emissive_texture = create_texture(&material.emissive_texture(), folder, ctx);
+/// Material with its colors in `working_space`. Base color and emissive textures are sRGB, the
+/// others linear, as glTF defines them.
+pub fn create_material(material: &gltf::Material, working_space: RgbSpace, folder: &Path, ctx: &Context) -> anyhow::Result<Material> {
+    let albedo_texture = create_texture(&material.pbr_metallic_roughness().base_color_texture(), ColorSpace::Srgb, working_space, folder, ctx);
+    let emissive_texture = create_texture(&material.emissive_texture(), ColorSpace::Srgb, working_space, folder, ctx);
     let normal_texture = material.normal_texture();
-    let normal_map = create_normal_texture(&normal_texture, folder, ctx);
+    let normal_map = create_normal_texture(&normal_texture, working_space, folder, ctx);
     let normal_scale = normal_texture.as_ref().map_or(1.0, |x| x.scale());
-    let metallic_roughness_texture = create_texture(&material.pbr_metallic_roughness().metallic_roughness_texture(), folder, ctx);
+    let metallic_roughness_texture = create_texture(&material.pbr_metallic_roughness().metallic_roughness_texture(), ColorSpace::Linear, working_space, folder, ctx);
+    let to_working_space = color::from_rec709(working_space);
 
     let base_color = material.pbr_metallic_roughness().base_color_factor();
     let roughness = material.pbr_metallic_roughness().roughness_factor();
@@ -84,5 +89,7 @@ pub fn create_material(material: &gltf::Material, folder: &Path, ctx: &Context)
     const EMISSIVE_SCALE: f32 = 1.0; // TODO: This is a hack to make emissive materials more visible. Should probably be exposed as a parameter.
     let emissive_strength = material.emissive_strength().unwrap_or(0.0) * EMISSIVE_SCALE;
     let emissive = Vector3::new(material.emissive_factor()[0] * emissive_strength, material.emissive_factor()[1] * emissive_strength, material.emissive_factor()[2] * emissive_strength);
-    Ok(Material::new(Vector3::new(base_color[0], base_color[1], base_color[2]), albedo_texture, normal_map, emissive_texture, metallic_roughness_texture, normal_scale, emissive, roughness, metallic, transmission_factor, ior, invert_albedo))
+    let base_color = to_working_space * Vector3::new(base_color[0], base_color[1], base_color[2]);
+    let emissive = to_working_space * emissive;
+    Ok(Material::new(base_color, albedo_texture, normal_map, emissive_texture, metallic_roughness_texture, normal_scale, emissive, roughness, metallic, transmission_factor, ior, invert_albedo))
 }
\ No newline at end of file
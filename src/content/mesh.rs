@@ -1,11 +1,13 @@
 use std::sync::Arc;
-use nalgebra::{Matrix3, Matrix4, Vector3, Vector4};
+use nalgebra::{Matrix3, Matrix4, Point3, Vector2, Vector3, Vector4};
+use rand::Rng;
 use crate::acceleration::bounds::AABB;
 use crate::acceleration::kdtree::KDTree;
 use crate::content::triangle::{Triangle, IntersectTriangle, Vertex};
 use crate::core::Ray;
+use crate::math::distribution::Distribution1D;
+use crate::math::motion::TransformMotion;
 use crate::scene::{Intersectable, Intersection, Shadeable};
-use crate::scene::material::Material;
 
 pub struct MeshData {
     intersect_triangles: Vec<IntersectTriangle>,
@@ -75,11 +77,16 @@ impl MeshData {
 
             let tangent = tangent0 * w + tangent1 * x.barycentric.x + tangent2 * x.barycentric.y;
 
+            let edges = &self.intersect_triangles[tri_index];
+            let face_normal = edges.edge1.cross(&edges.edge2);
+            let geometric_normal = if face_normal.dot(&normal) < 0.0 { -face_normal } else { face_normal };
+
             Some(Intersection {
                 dist: x.dist,
                 tex_coord,
                 normal,
                 tangent,
+                geometric_normal,
             })
         })
     }
@@ -112,6 +119,24 @@ fn transform_normal_and_tangent(
     (world_normal, world_tangent)
 }
 
+/// A point sampled uniformly by area on the surface of a mesh instance.
+pub struct SurfaceSample {
+    pub position: Point3<f32>,
+    pub normal: Vector3<f32>,
+    pub tex_coord: Vector2<f32>,
+    /// Pdf with respect to world-space surface area.
+    pub pdf: f32,
+}
+
+/// World-space triangle areas of an instance, used to sample points uniformly over its surface.
+#[derive(Clone)]
+struct AreaDistribution {
+    triangles: Distribution1D,
+    surface_area: f32,
+    /// Axis and cosine of the half-angle of a cone containing all shading normals.
+    normal_cone: (Vector3<f32>, f32),
+}
+
 #[derive(Clone)]
 pub struct MeshInstance {
     data: Arc<MeshData>,
@@ -120,19 +145,32 @@ pub struct MeshInstance {
 
     normal_matrix: Matrix3<f32>,
     orientation_sign: f32,
+    light_index: Option<usize>,
+    area_distribution: Option<AreaDistribution>,
+    /// Motion while the shutter is open. `transform` is where the motion starts.
+    motion: Option<TransformMotion>,
+    world_bounds: AABB,
+    /// Index the mesh was added to the scene with, which stays the same when the BVH reorders meshes.
+    scene_index: usize,
 }
 
 impl MeshInstance {
     pub fn new(data: Arc<MeshData>, transform: Matrix4<f32>) -> Self {
 
         let (normal_matrix, orientation_sign) = Self::calculate_normal_matrix_and_orientation(&transform);
+        let world_bounds = data.bounds().transform(&transform);
 
         Self {
             data,
             transform,
             inverse_transform: transform.try_inverse().unwrap(),
             normal_matrix,
-            orientation_sign
+            orientation_sign,
+            light_index: None,
+            area_distribution: None,
+            motion: None,
+            world_bounds,
+            scene_index: 0,
         }
     }
 
@@ -150,13 +188,144 @@ impl MeshInstance {
 
     
     pub fn update_transform(&mut self, transform: Matrix4<f32>) {
+        self.motion = None;
+        self.world_bounds = self.data.bounds().transform(&transform);
         self.transform = transform;
         self.inverse_transform = transform.try_inverse().unwrap();
         let (normal_matrix, orientation_sign) = Self::calculate_normal_matrix_and_orientation(&transform);
         self.normal_matrix = normal_matrix;
         self.orientation_sign = orientation_sign;
+
+        if self.area_distribution.is_some() {
+            self.area_distribution = Some(self.build_area_distribution());
+        }
     }
-    
+
+    /// Move from `start` to `end` while the shutter is open. Light sampling only sees the mesh
+    /// at `start`.
+    pub fn update_motion(&mut self, start: Matrix4<f32>, end: Matrix4<f32>) {
+        self.update_transform(start);
+        self.motion = TransformMotion::new(start, end);
+        if let Some(motion) = &self.motion {
+            self.world_bounds = motion.swept_bounds(&self.data.bounds());
+        }
+    }
+
+    pub fn scene_index(&self) -> usize {
+        self.scene_index
+    }
+
+    pub fn set_scene_index(&mut self, scene_index: usize) {
+        self.scene_index = scene_index;
+    }
+
+    /// Index of the scene light this mesh emits as, if it is emissive.
+    pub fn light_index(&self) -> Option<usize> {
+        self.light_index
+    }
+
+    pub fn set_light_index(&mut self, light_index: Option<usize>) {
+        self.light_index = light_index;
+    }
+
+    /// Build the per-triangle area CDF needed by [`sample_surface`](Self::sample_surface).
+    /// It is kept up to date by `update_transform` from then on.
+    pub fn enable_area_sampling(&mut self) {
+        self.area_distribution = Some(self.build_area_distribution());
+    }
+
+    fn build_area_distribution(&self) -> AreaDistribution {
+        let linear = self.transform.fixed_view::<3, 3>(0, 0).into_owned();
+        let areas: Vec<f32> = self
+            .data
+            .intersect_triangles
+            .iter()
+            .map(|triangle| 0.5 * (linear * triangle.edge1).cross(&(linear * triangle.edge2)).norm())
+            .collect();
+        let surface_area = areas.iter().sum();
+
+        let normals: Vec<Vector3<f32>> = self
+            .data
+            .vertices
+            .iter()
+            .filter_map(|vertex| (self.normal_matrix * vertex.normal).try_normalize(1e-12))
+            .collect();
+        let normal_cone = match normals.iter().sum::<Vector3<f32>>().try_normalize(1e-6) {
+            Some(axis) => (axis, normals.iter().map(|n| n.dot(&axis)).fold(1.0, f32::min)),
+            None => (Vector3::z(), -1.0),
+        };
+
+        AreaDistribution {
+            triangles: Distribution1D::new(areas),
+            surface_area,
+            normal_cone,
+        }
+    }
+
+    /// Cone bounding the directions the surface faces in, as (axis, cos half-angle).
+    /// `None` unless area sampling has been enabled.
+    pub fn normal_cone(&self) -> Option<(Vector3<f32>, f32)> {
+        self.area_distribution.as_ref().map(|distribution| distribution.normal_cone)
+    }
+
+    /// Bounds in world space, covering the whole motion if the mesh moves.
+    pub fn world_bounds(&self) -> AABB {
+        self.world_bounds
+    }
+
+    /// World-space surface area. Zero unless area sampling has been enabled.
+    pub fn surface_area(&self) -> f32 {
+        self.area_distribution.as_ref().map_or(0.0, |distribution| distribution.surface_area)
+    }
+
+    /// Sample a point uniformly by area over the instance's world-space surface.
+    /// Requires [`enable_area_sampling`](Self::enable_area_sampling) to have been called.
+    pub fn sample_surface(&self, rng: &mut impl Rng) -> Option<SurfaceSample> {
+        let distribution = self.area_distribution.as_ref()?;
+        if distribution.surface_area <= 0.0 {
+            return None;
+        }
+
+        let (triangle_index, _) = distribution.triangles.sample_discrete(rng.random());
+        let triangle = &self.data.tri_indices[triangle_index];
+        let v0 = &self.data.vertices[triangle[0] as usize];
+        let v1 = &self.data.vertices[triangle[1] as usize];
+        let v2 = &self.data.vertices[triangle[2] as usize];
+
+        // Uniform barycentric coordinates
+        let sqrt_u = rng.random::<f32>().sqrt();
+        let v: f32 = rng.random();
+        let b0 = 1.0 - sqrt_u;
+        let b1 = v * sqrt_u;
+        let b2 = 1.0 - b0 - b1;
+
+        let position = Point3::from(v0.position.coords * b0 + v1.position.coords * b1 + v2.position.coords * b2);
+        let normal = v0.normal * b0 + v1.normal * b1 + v2.normal * b2;
+        let tex_coord = v0.uv * b0 + v1.uv * b1 + v2.uv * b2;
+
+        Some(SurfaceSample {
+            position: self.transform.transform_point(&position),
+            normal: (self.normal_matrix * normal).normalize(),
+            tex_coord,
+            pdf: 1.0 / distribution.surface_area,
+        })
+    }
+
+    fn intersect_transformed(&self, ray: &Ray, t_min: f32, t_max: f32, inverse_transform: &Matrix4<f32>, normal_matrix: &Matrix3<f32>, orientation_sign: f32) -> Option<Intersection> {
+        let object_space_ray = ray.transform(*inverse_transform);
+
+        self.data.intersect(&object_space_ray, t_min, t_max).map(|x| {
+            let (normal, tangent) = transform_normal_and_tangent(normal_matrix, orientation_sign, x.normal, x.tangent);
+            Intersection {
+                dist: x.dist,
+                tex_coord: x.tex_coord,
+                normal,
+                tangent,
+                geometric_normal: (normal_matrix * x.geometric_normal).normalize(),
+            }
+        })
+    }
+
     pub fn triangle_count(&self) -> usize {
         self.data.tri_indices.len()
     }
@@ -178,17 +347,14 @@ impl Intersectable for MeshInstance {
     }
 
     fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<Intersection> {
-        let object_space_ray = ray.transform(self.inverse_transform);
+        let Some(motion) = &self.motion else {
+            return self.intersect_transformed(ray, t_min, t_max, &self.inverse_transform, &self.normal_matrix, self.orientation_sign);
+        };
 
-        self.data.intersect(&object_space_ray, t_min, t_max).map(|x| {
-            let (normal, tangent) = transform_normal_and_tangent(&self.normal_matrix, self.orientation_sign, x.normal, x.tangent);
-            Intersection {
-                dist: x.dist,
-                tex_coord: x.tex_coord,
-                normal,
-                tangent,
-            }
-        })
+        let transform = motion.interpolate(ray.time());
+        let inverse_transform = transform.try_inverse()?;
+        let (normal_matrix, orientation_sign) = Self::calculate_normal_matrix_and_orientation(&transform);
+        self.intersect_transformed(ray, t_min, t_max, &inverse_transform, &normal_matrix, orientation_sign)
     }
 
     fn transform(&self) -> &nalgebra::Matrix4<f32> {
@@ -205,7 +371,10 @@ impl Shadeable for MeshInstance {
 #[cfg(test)]
 mod tests {
     use nalgebra::{Matrix4, Vector4, Point3, Vector2};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
     use crate::content::triangle::Vertex;
+    use crate::scene::material::Material;
     use super::*;
 
     fn create_test_mesh() -> Arc<MeshData> {
@@ -328,4 +497,30 @@ mod tests {
 
         assert_eq!(intersection.tangent.w, -1.0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn surface_area_accounts_for_instance_transform() {
+        let mut mesh = MeshInstance::new(create_test_mesh(), Matrix4::new_nonuniform_scaling(&Vector3::new(2.0, 3.0, 1.0)));
+        mesh.enable_area_sampling();
+        assert!((mesh.surface_area() - 24.0).abs() < 1e-4);
+
+        mesh.update_transform(Matrix4::new_scaling(0.5));
+        assert!((mesh.surface_area() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sample_surface_returns_points_on_world_space_surface() {
+        let transform = Matrix4::new_translation(&Vector3::new(0.0, 0.0, 4.0)) * Matrix4::new_scaling(2.0);
+        let mut mesh = MeshInstance::new(create_test_mesh(), transform);
+        mesh.enable_area_sampling();
+        let mut rng = StdRng::seed_from_u64(3);
+
+        for _ in 0..32 {
+            let sample = mesh.sample_surface(&mut rng).unwrap();
+            assert!((sample.position.z - 6.0).abs() < 1e-4);
+            assert!(sample.position.x.abs() <= 2.0 + 1e-4 && sample.position.y.abs() <= 2.0 + 1e-4);
+            assert!((sample.normal - Vector3::new(0.0, 0.0, 1.0)).norm() < 1e-5);
+            assert!((sample.pdf - 1.0 / 16.0).abs() < 1e-6);
+        }
+    }
+}
@@ -2,4 +2,6 @@ pub mod mesh;
 pub mod triangle;
 pub mod gltf;
 pub mod scene_loader;
+pub mod environment_map;
+pub mod aperture_mask;
 //TODO: remove 'pub' from mod triangle
\ No newline at end of file
@@ -9,20 +9,20 @@ use crate::scene::scene::Scene;
 pub enum SceneError {
     NoDefaultScene,
     NoCameras,
+    CameraNotFound(String),
+    NoDirectionalLight,
     UnsupportedFormat(String)
 }
 
-impl std::error::Error for SceneError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(self)
-    }
-}
+impl std::error::Error for SceneError {}
 
 impl std::fmt::Display for SceneError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             SceneError::NoDefaultScene => write!(f, "No default scene found"),
             SceneError::NoCameras => write!(f, "No cameras found"),
+            SceneError::CameraNotFound(camera) => write!(f, "Camera {} not found", camera),
+            SceneError::NoDirectionalLight => write!(f, "No directional light found to drive the sun"),
             SceneError::UnsupportedFormat(message) => write!(f, "Unsupported format: {}", message)
         }
     }
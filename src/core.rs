@@ -5,6 +5,8 @@ pub struct Ray {
     origin: Point3<f32>,
     direction: Vector3<f32>,
     direction_inv: Vector3<f32>,
+    /// Point in the shutter interval the ray is traced at, from 0 (open) to 1 (closed).
+    time: f32,
 }
 
 impl Ray {
@@ -13,16 +15,23 @@ impl Ray {
             origin,
             direction,
             direction_inv: Vector3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z),
+            time: 0.0,
         }
     }
 
+    pub fn with_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
+    }
+
     pub fn origin(&self) -> Point3<f32> { self.origin }
     pub fn direction(&self) -> Vector3<f32> { self.direction }
     pub fn direction_inv(&self) -> Vector3<f32> { self.direction_inv }
+    pub fn time(&self) -> f32 { self.time }
 
     pub fn transform(&self, matrix: Matrix4<f32>) -> Ray {
         let origin = matrix.transform_point(&self.origin);
         let direction = matrix.transform_vector(&self.direction);
-        Ray::new(origin, direction)
+        Ray::new(origin, direction).with_time(self.time)
     }
 }
\ No newline at end of file
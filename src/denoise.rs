@@ -4,13 +4,14 @@ mod oidn;
 mod passthrough;
 
 use std::io::Write;
-use crate::camera::perspective_camera::PerspectiveCamera;
+use crate::camera::viewpoint::CameraImpl;
 use crate::context::Context;
 use crate::frame::Frame;
 use crate::integrator::albedo::AlbedoIntegrator;
 use crate::integrator::integrator::Integrator;
 use crate::integrator::normal::NormalIntegrator;
-use crate::options::{DenoiseAlgorithm, DenoiseSettings, RenderOptions};
+use crate::options::{Aov, DenoiseAlgorithm, DenoiseSettings, RenderOptions};
+use crate::sampler::SampleIndex;
 use crate::scene::scene::Scene;
 
 pub enum DenoiseImpl {
@@ -66,21 +67,39 @@ pub struct DenoiseResult {
 pub struct Denoiser {
     denoise_filter: DenoiseImpl,
     settings: DenoiseSettings,
+    /// Whether the integrator keeps the albedo and normal in AOVs of the frame. Otherwise they are
+    /// rendered in passes of their own.
+    from_aovs: bool,
 }
 
 impl Denoiser {
-    pub fn denoise(&self, frame: &Frame, scene: &Scene, camera: &PerspectiveCamera, samples: u32, options: &RenderOptions, ctx: &Context) -> DenoiseResult {
+    /// AOVs the frame has to keep for the auxiliary images of the denoiser.
+    pub fn auxiliary_aovs(&self) -> Vec<Aov> {
+        let mut aovs = Vec::new();
+        if !self.from_aovs {
+            return aovs;
+        }
+        if self.settings.auxiliary_albedo && self.denoise_filter.supports_auxiliary_albedo() {
+            aovs.push(Aov::Albedo);
+        }
+        if self.settings.auxiliary_normal && self.denoise_filter.supports_auxiliary_normal() {
+            aovs.push(Aov::ShadingNormal);
+        }
+        aovs
+    }
 
+    /// Denoise `frame`, with the albedo and normal the path tracer kept in its AOVs as auxiliary
+    /// images, see `auxiliary_aovs`. For other integrators they are rendered of `scene` as seen by
+    /// `camera`.
+    pub fn denoise(&self, frame: &Frame, scene: &Scene, camera: &CameraImpl, options: &RenderOptions, ctx: &Context) -> DenoiseResult {
         let albedo = if self.settings.auxiliary_albedo {
             if self.denoise_filter.supports_auxiliary_albedo() {
-                let albedo_integrator = AlbedoIntegrator {};
-                print!("Creating auxiliary albedo frame for denoising...");
-                let mut albedo_frame = Frame::new(frame.width(), frame.height());
-                for _ in 0..samples {
-                    albedo_integrator.integrate(scene, camera, &mut albedo_frame, samples, options, &ctx);
+                if self.from_aovs {
+                    frame.aov(Aov::Albedo)
+                } else {
+                    print!("Creating auxiliary albedo frame for denoising...");
+                    Some(Self::render_pass(&AlbedoIntegrator {}, frame, scene, camera, options, ctx))
                 }
-                println!("Done.");
-                Some(albedo_frame)
             }
             else {
                 println!("Warning: Denoiser does not support auxiliary albedo, but it was requested in settings. Ignoring.");
@@ -92,16 +111,12 @@ impl Denoiser {
 
         let normal = if self.settings.auxiliary_normal {
             if self.denoise_filter.supports_auxiliary_normal() {
-                let normal_integrator = NormalIntegrator {};
-                print!("Creating auxiliary normal frame for denoising...");
-                std::io::stdout().flush().unwrap();
-                let mut normal_frame = Frame::new(frame.width(), frame.height());
-                for _ in 0..samples {
-                    normal_integrator.integrate(scene, camera, &mut normal_frame, samples, options, &ctx);
+                if self.from_aovs {
+                    frame.aov(Aov::ShadingNormal)
+                } else {
+                    print!("Creating auxiliary normal frame for denoising...");
+                    Some(Self::render_pass(&NormalIntegrator::new(), frame, scene, camera, options, ctx))
                 }
-
-                println!("Done.");
-                Some(normal_frame)
             }
             else {
                 println!("Warning: Denoiser does not support auxiliary normal, but it was requested in settings. Ignoring.");
@@ -118,13 +133,27 @@ impl Denoiser {
 
         DenoiseResult { denoised_frame: result, auxiliary_albedo: albedo, auxiliary_normal: normal }
     }
+
+    /// Every sample of `integrator` in a frame the size of `frame`.
+    fn render_pass(integrator: &impl Integrator, frame: &Frame, scene: &Scene, camera: &CameraImpl, options: &RenderOptions, ctx: &Context) -> Frame {
+        std::io::stdout().flush().unwrap();
+        let mut pass = Frame::new(frame.width(), frame.height());
+        for index in 0..options.samples {
+            integrator.integrate(scene, camera, &mut pass, SampleIndex { index, count: options.samples }, options, ctx);
+        }
+        println!("Done.");
+        pass
+    }
 }
 
-pub fn create_denoiser(denoise: &DenoiseAlgorithm) -> Denoiser {
-    let (denoise_impl, settings) = match denoise {
+pub fn create_denoiser(options: &RenderOptions) -> Denoiser {
+    let (denoise_impl, settings) = match &options.denoise {
         DenoiseAlgorithm::OpenImageDenoise(x) => (DenoiseImpl::OpenImageDenoise(oidn::Oidn::new()), x.clone()),
         DenoiseAlgorithm::None => (DenoiseImpl::None(passthrough::Passthrough::new()), DenoiseSettings::default()),
     };
 
-    Denoiser { denoise_filter: denoise_impl, settings }
+    // Only the path tracer fills the AOVs the auxiliary images come from
+    let from_aovs = matches!(options.integrator, crate::options::Integrator::Pathtracing);
+
+    Denoiser { denoise_filter: denoise_impl, settings, from_aovs }
 }
\ No newline at end of file
@@ -1,3 +1,4 @@
+use nalgebra::Vector3;
 use crate::denoise::DenoiseFilter;
 use crate::frame::Frame;
 use crate::options::DenoiseSettings;
@@ -13,24 +14,11 @@ impl Oidn {
 #[cfg(feature = "open_image_denoise")]
 impl DenoiseFilter for Oidn {
     fn denoise(&self, frame: &Frame, albedo: &Option<Frame>, normal: &Option<Frame>) -> Frame {
-        let mut new_frame = Frame::new(frame.width(), frame.height());
-
-        fn slice_from_frame(frame: &Frame) -> &[f32] {
-            unsafe {
-                std::slice::from_raw_parts(
-                    frame.pixels().as_ptr() as *const f32,
-                    frame.pixels().len() * 3
-                )
-            }
+        fn flatten(frame: &Frame) -> Vec<f32> {
+            frame.pixels().iter().flat_map(|p| [p.x, p.y, p.z]).collect()
         }
-        let input: &[f32] = slice_from_frame(frame);
-
-        let output: &mut [f32] = unsafe {
-            std::slice::from_raw_parts_mut(
-                new_frame.pixels_mut().as_mut_ptr() as *mut f32,
-                frame.pixels().len() * 3
-            )
-        };
+        let input = flatten(frame);
+        let mut output = vec![0.0; input.len()];
 
         let device = oidn::device::Device::new();
         let mut rt = oidn::RayTracing::new(&device);
@@ -38,23 +26,24 @@ impl DenoiseFilter for Oidn {
         rt.srgb(false);
         rt.image_dimensions(frame.width() as usize, frame.height() as usize);
         if let Some(albedo) = albedo {
-            let albedo_slice: &[f32] = slice_from_frame(&albedo);
+            let albedo = flatten(albedo);
             if let Some(normal) = normal {
-                let normal_slice: &[f32] = slice_from_frame(&normal);
+                let normal = flatten(normal);
 
-                rt.albedo_normal(albedo_slice, normal_slice);
+                rt.albedo_normal(&albedo, &normal);
             } else {
-                rt.albedo(albedo_slice);
+                rt.albedo(&albedo);
             }
         }
 
-        rt.filter(input, output).expect("Filter config error!");
+        rt.filter(&input, &mut output).expect("Filter config error!");
 
         if let Err(e) = device.get_error() {
             eprintln!("Error denoising image: {}", e.1);
         }
 
-        new_frame
+        let pixels = output.chunks_exact(3).map(|p| Vector3::new(p[0], p[1], p[2])).collect();
+        Frame::from_pixels(frame.width(), frame.height(), pixels)
     }
 
     fn supports_auxiliary_albedo(&self) -> bool { true }
@@ -11,10 +11,7 @@ impl Passthrough {
 
 impl DenoiseFilter for Passthrough {
     fn denoise(&self, frame: &Frame, _albedo: &Option<Frame>, _normal: &Option<Frame>) -> Frame {
-        let mut new_frame = Frame::new(frame.width(), frame.height());
-        new_frame.pixels_mut().copy_from_slice(frame.pixels());
-
-        new_frame
+        Frame::from_pixels(frame.width(), frame.height(), frame.pixels())
     }
 
     fn supports_auxiliary_albedo(&self) -> bool { false }
@@ -0,0 +1,163 @@
+use nalgebra::{Matrix3, Vector3};
+use crate::options::{DisplaySettings, ToneMapper, TransferFunction};
+
+/// Turns the linear light of pixels into the values shown on a display: conversion to Rec.709,
+/// exposure, then tone mapping, then the transfer function of the display.
+#[derive(Copy, Clone, Debug)]
+pub struct DisplayTransform {
+    to_rec709: Matrix3<f32>,
+    exposure_scale: f32,
+    tone_mapper: ToneMapper,
+    /// Leaves values linear without one.
+    transfer: Option<TransferFunction>,
+}
+
+impl DisplayTransform {
+    pub fn new(settings: &DisplaySettings) -> Self {
+        Self {
+            to_rec709: Matrix3::identity(),
+            exposure_scale: settings.exposure.exp2(),
+            tone_mapper: settings.tone_mapper,
+            transfer: Some(settings.transfer),
+        }
+    }
+
+    /// Writes values as they are, only clamped to [0, 1], for data like depths and normals.
+    pub fn identity() -> Self {
+        Self {
+            to_rec709: Matrix3::identity(),
+            exposure_scale: 1.0,
+            tone_mapper: ToneMapper::None,
+            transfer: None,
+        }
+    }
+
+    /// Take pixels in the linear RGB space that `to_rec709` converts from, instead of Rec.709.
+    pub fn with_color_matrix(mut self, to_rec709: Matrix3<f32>) -> Self {
+        self.to_rec709 = to_rec709;
+        self
+    }
+
+    /// Display encoded value of a pixel of linear light, in [0, 1].
+    pub fn apply(&self, pixel: Vector3<f32>) -> Vector3<f32> {
+        let exposed = (self.to_rec709 * pixel).map(|v| if v.is_finite() { v.max(0.0) } else { 0.0 }) * self.exposure_scale;
+        let mapped = match self.tone_mapper {
+            ToneMapper::None => exposed,
+            ToneMapper::Reinhard => exposed.map(|v| v / (1.0 + v)),
+            ToneMapper::Hable => hable(exposed),
+            ToneMapper::Aces => aces_fitted(exposed),
+            ToneMapper::AgX => agx(exposed),
+        };
+        mapped.map(|v| self.encode(v.clamp(0.0, 1.0)))
+    }
+
+    pub fn to_rgb8(&self, pixel: Vector3<f32>) -> [u8; 3] {
+        let encoded = self.apply(pixel);
+        [quantize(encoded.x), quantize(encoded.y), quantize(encoded.z)]
+    }
+
+    fn encode(&self, value: f32) -> f32 {
+        match self.transfer {
+            None => value,
+            Some(TransferFunction::Srgb) => {
+                if value <= 0.0031308 { value * 12.92 } else { 1.055 * value.powf(1.0 / 2.4) - 0.055 }
+            }
+            Some(TransferFunction::Rec709) => {
+                if value < 0.018 { value * 4.5 } else { 1.099 * value.powf(0.45) - 0.099 }
+            }
+        }
+    }
+}
+
+impl Default for DisplayTransform {
+    fn default() -> Self {
+        Self::new(&DisplaySettings::default())
+    }
+}
+
+/// 8 bit value of a display encoded value in [0, 1].
+pub fn quantize(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn hable(color: Vector3<f32>) -> Vector3<f32> {
+    const EXPOSURE_BIAS: f32 = 2.0;
+    const WHITE: f32 = 11.2;
+    let curve = |x: f32| {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+    };
+    color.map(|v| curve(v * EXPOSURE_BIAS) / curve(WHITE))
+}
+
+fn aces_fitted(color: Vector3<f32>) -> Vector3<f32> {
+    // Rec.709 to the ACES rendering space, with the saturation of the reference transform
+    let input = Matrix3::new(
+        0.59719, 0.35458, 0.04823,
+        0.07600, 0.90834, 0.01566,
+        0.02840, 0.13383, 0.83777,
+    );
+    let output = Matrix3::new(
+        1.60475, -0.53108, -0.07367,
+        -0.10208, 1.10813, -0.00605,
+        -0.00327, -0.07276, 1.07602,
+    );
+    let fit = |v: f32| (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.432951) + 0.238081);
+    output * (input * color).map(fit)
+}
+
+fn agx(color: Vector3<f32>) -> Vector3<f32> {
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+    let inset = Matrix3::from_columns(&[
+        Vector3::new(0.84247905, 0.042328242, 0.042375654),
+        Vector3::new(0.0784336, 0.87846863, 0.0784336),
+        Vector3::new(0.079223745, 0.07916613, 0.879143),
+    ]);
+    let outset = Matrix3::from_columns(&[
+        Vector3::new(1.196879, -0.052896854, -0.052971635),
+        Vector3::new(-0.09802088, 1.1519032, -0.09804345),
+        Vector3::new(-0.09902974, -0.098961174, 1.1510737),
+    ]);
+
+    // Polynomial fit of the default AgX sigmoid over the log encoded range
+    let sigmoid = |x: f32| {
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    };
+    let log_encoded = (inset * color).map(|v| ((v.max(1e-10).log2() - MIN_EV) / (MAX_EV - MIN_EV)).clamp(0.0, 1.0));
+    // The sigmoid gives display values for a 2.2 gamma, decoded back to light for the transfer function
+    (outset * log_encoded.map(sigmoid)).map(|v| v.max(0.0).powf(2.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tone_mappers_keep_black_and_compress_highlights() {
+        let mappers = [ToneMapper::None, ToneMapper::Reinhard, ToneMapper::Hable, ToneMapper::Aces, ToneMapper::AgX];
+        for tone_mapper in mappers {
+            let transform = DisplayTransform::new(&DisplaySettings { tone_mapper, ..Default::default() });
+            assert!(transform.apply(Vector3::zeros()).max() < 0.01, "{:?}", tone_mapper);
+
+            let mut previous = 0.0;
+            for stop in -4..8 {
+                let value = transform.apply(Vector3::repeat((stop as f32).exp2())).x;
+                assert!(value >= previous && value <= 1.0, "{:?}", tone_mapper);
+                previous = value;
+            }
+            if tone_mapper != ToneMapper::None {
+                assert!(transform.apply(Vector3::repeat(1.0)).x < 1.0, "{:?}", tone_mapper);
+            }
+        }
+
+        // The sRGB curve is linear near black, and exposure doubles the light per stop
+        let srgb = DisplayTransform::default();
+        assert!((srgb.apply(Vector3::repeat(0.001)).x - 0.01292).abs() < 1e-6);
+        let brighter = DisplayTransform::new(&DisplaySettings { exposure: 1.0, ..Default::default() });
+        assert_eq!(brighter.apply(Vector3::repeat(0.25)), srgb.apply(Vector3::repeat(0.5)));
+        assert_eq!(DisplayTransform::identity().apply(Vector3::new(0.25, 0.5, 2.0)), Vector3::new(0.25, 0.5, 1.0));
+    }
+}
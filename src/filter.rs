@@ -0,0 +1,87 @@
+use std::f32::consts::PI;
+use crate::options::PixelFilter;
+
+/// Weight of a sample for the pixels around it, by its offset from their centers, in pixels.
+#[derive(Copy, Clone, Debug)]
+pub struct Filter {
+    kind: PixelFilter,
+}
+
+impl Filter {
+    pub fn new(kind: PixelFilter) -> Self {
+        Self { kind }
+    }
+
+    /// Offset from the pixel center past which samples have no weight.
+    pub fn radius(&self) -> f32 {
+        match self.kind {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Tent { radius }
+            | PixelFilter::Gaussian { radius, .. }
+            | PixelFilter::Mitchell { radius, .. }
+            | PixelFilter::BlackmanHarris { radius } => radius.max(0.5),
+        }
+    }
+
+    pub fn evaluate(&self, dx: f32, dy: f32) -> f32 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, x: f32) -> f32 {
+        let radius = self.radius();
+        let x = x.abs();
+        if x > radius {
+            return 0.0;
+        }
+
+        match self.kind {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Tent { .. } => radius - x,
+            PixelFilter::Gaussian { sigma, .. } => {
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                // Shifted down to reach 0 at the radius
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            PixelFilter::Mitchell { b, c, .. } => {
+                // The cubic is defined over [-2, 2]
+                let x = 2.0 * x / radius;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+                } else {
+                    ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+                }
+            }
+            PixelFilter::BlackmanHarris { .. } => {
+                let t = 2.0 * PI * (x / radius + 1.0) / 2.0;
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_peak_at_center_and_vanish_past_radius() {
+        let filters = [
+            PixelFilter::Box,
+            PixelFilter::Tent { radius: 1.0 },
+            PixelFilter::Gaussian { radius: 1.5, sigma: 0.5 },
+            PixelFilter::Mitchell { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 },
+            PixelFilter::BlackmanHarris { radius: 2.0 },
+        ];
+
+        for kind in filters {
+            let filter = Filter::new(kind);
+            let center = filter.evaluate(0.0, 0.0);
+            assert!(center > 0.0, "{:?}", kind);
+            assert!(filter.evaluate(0.3, 0.1) <= center, "{:?}", kind);
+            assert_eq!(filter.evaluate(filter.radius() + 0.01, 0.0), 0.0, "{:?}", kind);
+            if !matches!(kind, PixelFilter::Box) {
+                assert!(filter.evaluate(filter.radius() - 1e-4, 0.0).abs() < 1e-2, "{:?}", kind);
+            }
+        }
+    }
+}
@@ -1,17 +1,217 @@
+use std::ops::Range;
 use std::path::Path;
-use nalgebra::Vector3;
+use nalgebra::{Matrix3, Vector2, Vector3};
+use rayon::prelude::*;
+use crate::aov::{AovBuffer, AovSample};
+use crate::display::DisplayTransform;
+use crate::filter::Filter;
+use crate::image_io;
+use crate::math::luminance;
+use crate::options::{AdaptiveSampling, Aov, RenderOptions, RenderRegion};
 
+/// Rectangle of pixels, from `x`, `y` at its top left corner.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PixelRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Luminance of the samples that landed in a pixel, unfiltered, to estimate its noise.
+#[derive(Copy, Clone, Debug, Default)]
+struct PixelStats {
+    count: u32,
+    sum: f32,
+    sum_of_squares: f32,
+}
+
+impl PixelStats {
+    fn add(&mut self, sample: &Vector3<f32>) {
+        let value = luminance(sample);
+        if value.is_finite() {
+            self.count += 1;
+            self.sum += value;
+            self.sum_of_squares += value * value;
+        }
+    }
+
+    fn merge(&mut self, other: &PixelStats) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.sum_of_squares += other.sum_of_squares;
+    }
+
+    /// Standard error of the mean luminance relative to the mean.
+    fn relative_error(&self) -> f32 {
+        if self.count < 2 {
+            return f32::INFINITY;
+        }
+        let n = self.count as f32;
+        let mean = self.sum / n;
+        let variance = ((self.sum_of_squares - n * mean * mean) / (n - 1.0)).max(0.0);
+        // Black pixels are converged once they stay black
+        (variance / n).sqrt() / (mean + 1e-4)
+    }
+}
+
+/// Rows of a frame that samples of some of them are added to, and the rows their filter reaches.
+pub struct FrameStrip<'a> {
+    filter: &'a Filter,
+    width: usize,
+    /// Row of the frame the strip starts at.
+    first_row: usize,
+    pixels: Vec<Vector3<f32>>,
+    weights: Vec<f32>,
+    stats: Vec<PixelStats>,
+    aovs: Vec<AovBuffer>,
+    /// Whether each pixel of the whole frame is converged.
+    converged: &'a [bool],
+}
+
+impl FrameStrip<'_> {
+    /// Add a sample taken at `position` on the image, in pixels from its top left corner.
+    pub fn add_sample(&mut self, position: Vector2<f32>, sample: Vector3<f32>) {
+        let radius = self.filter.radius();
+        let rows = self.pixels.len() / self.width;
+        // Pixels whose centers are within the radius
+        let x0 = (position.x - 0.5 - radius).ceil().max(0.0) as usize;
+        let x1 = ((position.x - 0.5 + radius).floor() as isize).min(self.width as isize - 1);
+        let y0 = ((position.y - 0.5 - radius).ceil().max(0.0) as usize).max(self.first_row);
+        let y1 = ((position.y - 0.5 + radius).floor() as isize).min((self.first_row + rows) as isize - 1);
+
+        for y in y0 as isize..=y1 {
+            for x in x0 as isize..=x1 {
+                let weight = self.filter.evaluate(x as f32 + 0.5 - position.x, y as f32 + 0.5 - position.y);
+                if weight != 0.0 {
+                    let index = x as usize + (y as usize - self.first_row) * self.width;
+                    self.pixels[index] += sample * weight;
+                    self.weights[index] += weight;
+                }
+            }
+        }
+
+        let index = self.index_of(position);
+        self.stats[index].add(&sample);
+    }
+
+    /// Whether the pixel at `x`, `y` needs no more samples, see `Frame::update_convergence`.
+    pub fn is_converged(&self, x: usize, y: usize) -> bool {
+        self.converged[x + y * self.width]
+    }
+
+    /// Index in the strip of the pixel `position` is in.
+    fn index_of(&self, position: Vector2<f32>) -> usize {
+        let x = (position.x.max(0.0) as usize).min(self.width - 1);
+        let y = (position.y.max(0.0) as usize).clamp(self.first_row, self.first_row + self.pixels.len() / self.width - 1);
+        x + (y - self.first_row) * self.width
+    }
+
+    /// Add the AOVs of a sample taken at `position` to the pixel it is in, unfiltered.
+    pub fn add_aovs(&mut self, position: Vector2<f32>, sample: &AovSample) {
+        let index = self.index_of(position);
+        for buffer in &mut self.aovs {
+            buffer.add(index, sample);
+        }
+    }
+}
+
+impl PixelRect {
+    /// The pixels of a `width` by `height` image that `options` render. The whole image, unless
+    /// there is a render region.
+    pub fn rendered(options: &RenderOptions, width: usize, height: usize) -> Self {
+        let (x, y, region_width, region_height) = match options.region.map(|settings| settings.region) {
+            None => (0, 0, width, height),
+            Some(RenderRegion::Pixels { x, y, width, height }) => (x as usize, y as usize, width as usize, height as usize),
+            Some(RenderRegion::Normalized { x, y, width: region_width, height: region_height }) => {
+                let x0 = (x * width as f32).round().max(0.0) as usize;
+                let y0 = (y * height as f32).round().max(0.0) as usize;
+                let x1 = ((x + region_width) * width as f32).round().max(0.0) as usize;
+                let y1 = ((y + region_height) * height as f32).round().max(0.0) as usize;
+                (x0, y0, x1.saturating_sub(x0), y1.saturating_sub(y0))
+            }
+        };
+
+        let x = x.min(width);
+        let y = y.min(height);
+        Self {
+            x,
+            y,
+            width: region_width.min(width - x),
+            height: region_height.min(height - y),
+        }
+    }
+
+    pub fn area(&self) -> usize {
+        self.width * self.height
+    }
+
+    pub fn rows(&self) -> Range<usize> {
+        self.y..self.y + self.height
+    }
+
+    pub fn columns(&self) -> Range<usize> {
+        self.x..self.x + self.width
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        self.columns().contains(&x) && self.rows().contains(&y)
+    }
+}
+
+/// Image of samples averaged with the weights of a reconstruction filter.
+#[derive(Clone)]
 pub struct Frame {
+    /// Sums of the weighted samples of every pixel.
     pixels: Vec<Vector3<f32>>,
+    weights: Vec<f32>,
+    /// Added to the pixels as they are, like light traced onto the image.
+    splats: Vec<Vector3<f32>>,
+    stats: Vec<PixelStats>,
+    /// Pixels that need no more samples, see `update_convergence`.
+    converged: Vec<bool>,
+    aovs: Vec<AovBuffer>,
     width: u32,
     height: u32,
 }
 
+/// Number of rows traced together by `Frame::add_rows`.
+const STRIP_ROWS: usize = 8;
 
 impl Frame {
     pub fn new(width: u32, height: u32) -> Self {
+        let pixel_count = (width * height) as usize;
         Self {
-            pixels: vec![Vector3::default(); (width * height) as usize],
+            pixels: vec![Vector3::default(); pixel_count],
+            weights: vec![0.0; pixel_count],
+            splats: vec![Vector3::default(); pixel_count],
+            stats: vec![PixelStats::default(); pixel_count],
+            converged: vec![false; pixel_count],
+            aovs: Vec::new(),
+            width,
+            height,
+        }
+    }
+
+    /// Frame that also keeps `aovs` of the samples added to it.
+    pub fn with_aovs(width: u32, height: u32, aovs: &[Aov]) -> Self {
+        let pixel_count = (width * height) as usize;
+        Self {
+            aovs: aovs.iter().map(|&aov| AovBuffer::new(aov, pixel_count)).collect(),
+            ..Self::new(width, height)
+        }
+    }
+
+    /// Frame of finished pixel values, in rows from the top.
+    pub fn from_pixels(width: u32, height: u32, pixels: Vec<Vector3<f32>>) -> Self {
+        assert_eq!(pixels.len(), (width * height) as usize);
+        Self {
+            weights: vec![1.0; pixels.len()],
+            splats: vec![Vector3::default(); pixels.len()],
+            stats: vec![PixelStats::default(); pixels.len()],
+            converged: vec![false; pixels.len()],
+            aovs: Vec::new(),
+            pixels,
             width,
             height,
         }
@@ -19,55 +219,300 @@ impl Frame {
 
     pub fn clear(&mut self) {
         self.pixels.iter_mut().for_each(|p| *p = Vector3::default());
+        self.weights.iter_mut().for_each(|w| *w = 0.0);
+        self.splats.iter_mut().for_each(|p| *p = Vector3::default());
+        self.stats.iter_mut().for_each(|stats| *stats = PixelStats::default());
+        self.converged.iter_mut().for_each(|converged| *converged = false);
+        self.aovs.iter_mut().for_each(|buffer| buffer.clear());
+    }
+
+    /// Value of the pixel at `index`, in rows from the top.
+    pub fn pixel(&self, index: usize) -> Vector3<f32> {
+        let weight = self.weights[index];
+        let average = if weight != 0.0 { self.pixels[index] / weight } else { Vector3::zeros() };
+        average + self.splats[index]
+    }
+
+    /// Values of all pixels, in rows from the top.
+    pub fn pixels(&self) -> Vec<Vector3<f32>> {
+        (0..self.pixels.len()).map(|index| self.pixel(index)).collect()
+    }
+
+    /// The AOVs the frame keeps, each as a frame of its own.
+    pub fn aovs(&self) -> Vec<(Aov, Frame)> {
+        self.aovs
+            .iter()
+            .map(|buffer| {
+                let pixels = match buffer.aov() {
+                    Aov::SampleCount => self.sample_heatmap(),
+                    _ => buffer.pixels(),
+                };
+                (buffer.aov(), Frame::from_pixels(self.width, self.height, pixels))
+            })
+            .collect()
     }
 
-    pub fn pixels(&self) -> &[Vector3<f32>] { &self.pixels }
-    pub fn pixels_mut(&mut self) -> &mut [Vector3<f32>] {
-        &mut self.pixels
+    /// The AOV `aov` as a frame of its own, if the frame keeps it.
+    pub fn aov(&self, aov: Aov) -> Option<Frame> {
+        self.aovs().into_iter().find(|(kept, _)| *kept == aov).map(|(_, frame)| frame)
     }
-    
+
+    /// Number of samples taken in each pixel, from blue for none to red for the most.
+    fn sample_heatmap(&self) -> Vec<Vector3<f32>> {
+        let max_count = self.stats.iter().map(|stats| stats.count).max().unwrap_or(0).max(1);
+        self.stats.iter().map(|stats| heatmap(stats.count as f32 / max_count as f32)).collect()
+    }
+
+    /// Mark the tiles of `region` whose pixels all took `min_samples` and have a relative error
+    /// below the threshold of `settings` as converged. Returns whether all of them are.
+    pub fn update_convergence(&mut self, region: &PixelRect, settings: &AdaptiveSampling) -> bool {
+        let tile_size = settings.tile_size.max(1) as usize;
+        let width = self.width as usize;
+        let mut all_converged = true;
+        for tile_y in region.rows().step_by(tile_size) {
+            for tile_x in region.columns().step_by(tile_size) {
+                let tile = PixelRect {
+                    x: tile_x,
+                    y: tile_y,
+                    width: tile_size.min(region.x + region.width - tile_x),
+                    height: tile_size.min(region.y + region.height - tile_y),
+                };
+                let indices = || tile.rows().flat_map(move |y| tile.columns().map(move |x| x + y * width));
+
+                let converged = indices().all(|index| {
+                    let stats = &self.stats[index];
+                    stats.count >= settings.min_samples && stats.relative_error() < settings.threshold
+                });
+                if converged {
+                    for index in indices() {
+                        self.converged[index] = true;
+                    }
+                } else {
+                    all_converged = false;
+                }
+            }
+        }
+        all_converged
+    }
+
     pub fn width(&self) -> u32 {
         self.width
     }
-    
+
     pub fn height(&self) -> u32 {
         self.height
     }
 
+    /// Add a sample counting only for the pixel at `x`, `y`.
     pub fn add_sample(&mut self, x: usize, y: usize, sample: Vector3<f32>) {
-        self.pixels[x + y * self.width as usize] += sample;
+        let index = x + y * self.width as usize;
+        self.pixels[index] += sample;
+        self.weights[index] += 1.0;
+        self.stats[index].add(&sample);
+    }
+
+    /// Replace the pixel at `x`, `y` with `value`, and whatever was added to it.
+    pub fn set_pixel(&mut self, x: usize, y: usize, value: Vector3<f32>) {
+        let index = x + y * self.width as usize;
+        self.pixels[index] = value;
+        self.weights[index] = 1.0;
+        self.splats[index] = Vector3::zeros();
     }
 
-    pub fn write_rgba(&self, output: &mut [u8]) {
+    pub fn add_splat(&mut self, index: usize, value: Vector3<f32>) {
+        self.splats[index] += value;
+    }
+
+    /// Trace the rows `rows` in parallel, `trace_row` adding the samples of one row to a strip of
+    /// the frame, and add them to the frame weighted by `filter`.
+    pub fn add_rows(&mut self, rows: Range<usize>, filter: &Filter, trace_row: impl Fn(usize, &mut FrameStrip) + Sync) {
+        // Samples reach this many rows past their own
+        let margin = (filter.radius() - 0.5).ceil().max(0.0) as usize;
+        let (width, height) = (self.width as usize, self.height as usize);
+
+        let strips: Vec<FrameStrip> = rows
+            .clone()
+            .step_by(STRIP_ROWS)
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|start| {
+                let first_row = start.saturating_sub(margin);
+                let end_row = (start + STRIP_ROWS + margin).min(height);
+                let mut strip = FrameStrip {
+                    filter,
+                    width,
+                    first_row,
+                    pixels: vec![Vector3::zeros(); (end_row - first_row) * width],
+                    weights: vec![0.0; (end_row - first_row) * width],
+                    stats: vec![PixelStats::default(); (end_row - first_row) * width],
+                    aovs: self.aovs.iter().map(|buffer| AovBuffer::new(buffer.aov(), (end_row - first_row) * width)).collect(),
+                    converged: &self.converged,
+                };
+                for y in start..(start + STRIP_ROWS).min(rows.end) {
+                    trace_row(y, &mut strip);
+                }
+                strip
+            })
+            .collect();
+
+        for strip in strips {
+            let offset = strip.first_row * width;
+            for (index, (pixel, weight)) in strip.pixels.iter().zip(&strip.weights).enumerate() {
+                self.pixels[offset + index] += pixel;
+                self.weights[offset + index] += weight;
+            }
+            for (index, stats) in strip.stats.iter().enumerate() {
+                self.stats[offset + index].merge(stats);
+            }
+            for (buffer, strip_buffer) in self.aovs.iter_mut().zip(&strip.aovs) {
+                buffer.merge(strip_buffer, offset);
+            }
+        }
+    }
+
+    pub fn write_rgba(&self, output: &mut [u8], display: &DisplayTransform) {
         assert_eq!(output.len(), (self.width * self.height * 4) as usize);
 
-        for (pixel, rgba) in self.pixels.iter().zip(output.chunks_exact_mut(4)) {
-            rgba[0] = Self::to_display_u8(pixel.x);
-            rgba[1] = Self::to_display_u8(pixel.y);
-            rgba[2] = Self::to_display_u8(pixel.z);
-            rgba[3] = 255;
+        for (pixel, rgba) in self.pixels().iter().zip(output.chunks_exact_mut(4)) {
+            let [r, g, b] = display.to_rgb8(*pixel);
+            rgba.copy_from_slice(&[r, g, b, 255]);
         }
     }
 
-    fn to_display_u8(value: f32) -> u8 {
-        let gamma_corrected = value.clamp(0.0, 1.0).powf(1.0 / 2.2);
-        (gamma_corrected * 255.0).round() as u8
+    /// The pixels as shown on a display, encoded by `display`.
+    pub fn to_display(&self, display: &DisplayTransform) -> Frame {
+        let pixels = self.pixels().into_iter().map(|pixel| display.apply(pixel)).collect();
+        Frame::from_pixels(self.width, self.height, pixels)
     }
 
-    pub fn save<P: AsRef<Path>>(&self, path: P) {
-        let subpixels: Vec<u8> = self
-            .pixels
-            .iter()
-            .flat_map(|p| {
-                [
-                    Self::to_display_u8(p.x),
-                    Self::to_display_u8(p.y),
-                    Self::to_display_u8(p.z),
-                ]
-            })
+    /// The pixels converted to another linear RGB space by `matrix`.
+    pub fn transform_colors(&self, matrix: &Matrix3<f32>) -> Frame {
+        let pixels = self.pixels().into_iter().map(|pixel| matrix * pixel).collect();
+        Frame::from_pixels(self.width, self.height, pixels)
+    }
+
+    /// The pixels inside `rect`, as a frame of their own.
+    pub fn crop(&self, rect: &PixelRect) -> Frame {
+        let pixels = rect.rows()
+            .flat_map(|y| rect.columns().map(move |x| x + y * self.width as usize))
+            .map(|index| self.pixel(index))
             .collect();
+        Frame::from_pixels(rect.width as u32, rect.height as u32, pixels)
+    }
+
+    /// Save the pixels inside `rects` over the image already at `path`. Without an image of the
+    /// same size there, the pixels outside `rects` are saved as they are.
+    pub fn save_over<P: AsRef<Path>>(&self, path: P, rects: &[PixelRect], display: &DisplayTransform) {
+        let path = path.as_ref();
+        match image_io::read(path) {
+            Ok(previous) if previous.width == self.width && previous.height == self.height => {
+                // The previous pixels stay as they were stored, without going through the display
+                // transform again
+                let composite = self.for_file(path, display).composite_over(&previous, rects);
+                image_io::write(&composite, path).expect("Failed to save image")
+            }
+            _ => {
+                eprintln!("Warning: no previous frame at {:?} to composite the render region into", path);
+                self.save(path, display)
+            }
+        }
+    }
 
-        let image = image::RgbImage::from_vec(self.width, self.height,subpixels).expect("Failed to create image");
-        image.save(path).expect("Failed to save image");
+    /// `previous` with the pixels inside `rects` taken from this frame.
+    pub fn composite_over(&self, previous: &Frame, rects: &[PixelRect]) -> Frame {
+        let mut pixels = previous.pixels();
+        for rect in rects {
+            for y in rect.rows() {
+                for x in rect.columns() {
+                    let index = x + y * self.width as usize;
+                    pixels[index] = self.pixel(index);
+                }
+            }
+        }
+        Frame::from_pixels(self.width, self.height, pixels)
+    }
+
+    /// Save in the format of the extension of `path`, see `image_io::write`. 8 bit formats are
+    /// encoded by `display`.
+    pub fn save<P: AsRef<Path>>(&self, path: P, display: &DisplayTransform) {
+        image_io::write(&self.for_file(path.as_ref(), display), path.as_ref()).expect("Failed to save image");
     }
-}
\ No newline at end of file
+
+    /// The pixels as stored in a file at `path`: display encoded for 8 bit formats, linear
+    /// otherwise.
+    fn for_file(&self, path: &Path, display: &DisplayTransform) -> Frame {
+        if image_io::is_display_encoded(path) { self.to_display(display) } else { self.clone() }
+    }
+}
+/// Color of `t` in [0, 1] on a blue, cyan, green, yellow, red ramp.
+fn heatmap(t: f32) -> Vector3<f32> {
+    let stops = [
+        Vector3::new(0.0, 0.0, 1.0),
+        Vector3::new(0.0, 1.0, 1.0),
+        Vector3::new(0.0, 1.0, 0.0),
+        Vector3::new(1.0, 1.0, 0.0),
+        Vector3::new(1.0, 0.0, 0.0),
+    ];
+    let position = t.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+    let index = (position as usize).min(stops.len() - 2);
+    stops[index].lerp(&stops[index + 1], position - index as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::PixelFilter;
+
+    #[test]
+    fn crop_copies_pixels_inside_rect() {
+        let frame = Frame::from_pixels(4, 3, (0..12).map(|index| Vector3::repeat(index as f32)).collect());
+
+        let rect = PixelRect { x: 1, y: 1, width: 2, height: 2 };
+        let cropped = frame.crop(&rect);
+        assert_eq!((cropped.width(), cropped.height()), (2, 2));
+        let values: Vec<f32> = cropped.pixels().iter().map(|p| p.x).collect();
+        assert_eq!(values, vec![5.0, 6.0, 9.0, 10.0]);
+        assert!(rect.contains(2, 2) && !rect.contains(3, 1));
+    }
+
+    #[test]
+    fn samples_are_averaged_by_filter_weight() {
+        let mut frame = Frame::new(3, 3);
+        let filter = Filter::new(PixelFilter::Tent { radius: 1.0 });
+        frame.add_rows(0..3, &filter, |y, strip| {
+            if y == 1 {
+                strip.add_sample(Vector2::new(1.5, 1.5), Vector3::repeat(2.0));
+                strip.add_sample(Vector2::new(1.75, 1.5), Vector3::repeat(4.0));
+            }
+        });
+
+        // Weights of 1 and 0.75 at the center
+        assert!((frame.pixel(4).x - (2.0 + 4.0 * 0.75) / 1.75).abs() < 1e-5);
+        // Only the second sample reaches the right neighbour
+        assert!((frame.pixel(5).x - 4.0).abs() < 1e-5);
+        assert_eq!(frame.pixel(3).x, 0.0);
+    }
+
+    #[test]
+    fn only_tiles_without_noise_converge() {
+        let mut frame = Frame::with_aovs(4, 2, &[Aov::SampleCount]);
+        let settings = AdaptiveSampling { threshold: 0.01, min_samples: 4, tile_size: 2 };
+        let region = PixelRect { x: 0, y: 0, width: 4, height: 2 };
+        for sample in 0..8 {
+            for y in 0..2 {
+                // The left tile is flat, the right one alternates between black and white
+                frame.add_sample(0, y, Vector3::repeat(0.5));
+                frame.add_sample(1, y, Vector3::repeat(0.5));
+                frame.add_sample(2, y, Vector3::repeat((sample % 2) as f32));
+                frame.add_sample(3, y, Vector3::repeat(0.5));
+            }
+        }
+
+        assert!(!frame.update_convergence(&region, &settings));
+        assert!(frame.converged[0] && frame.converged[5]);
+        assert!(!frame.converged[3] && !frame.converged[7]);
+
+        let (_, heatmap) = &frame.aovs()[0];
+        assert_eq!(heatmap.pixel(0), Vector3::new(1.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use anyhow::{anyhow, bail, Context};
+use exr::prelude::{AnyChannel, AnyChannels, Encoding, FlatSamples, Image, ImageAttributes, IntegerBounds, Layer, LayerAttributes, WritableImage};
+use nalgebra::Vector3;
+use crate::display;
+use crate::frame::Frame;
+
+/// Write `frame` in the format of the extension of `path`. EXR, PFM and HDR files keep the
+/// linear values; other formats are 8 bit and take values already encoded for display, see
+/// `is_display_encoded`.
+pub fn write(frame: &Frame, path: &Path) -> anyhow::Result<()> {
+    match extension(path).as_str() {
+        "pfm" => write_pfm(frame, path),
+        "exr" | "hdr" => {
+            let values = frame.pixels().iter().flat_map(|p| [p.x, p.y, p.z]).collect();
+            let image = image::Rgb32FImage::from_vec(frame.width(), frame.height(), values).context("Failed to create image")?;
+            Ok(image.save(path)?)
+        }
+        _ => {
+            let values = frame.pixels().iter().flat_map(|p| [display::quantize(p.x), display::quantize(p.y), display::quantize(p.z)]).collect();
+            let image = image::RgbImage::from_vec(frame.width(), frame.height(), values).context("Failed to create image")?;
+            Ok(image.save(path)?)
+        }
+    }
+}
+
+/// Values of the image at `path` as `write` was given them.
+pub fn read(path: &Path) -> anyhow::Result<Frame> {
+    match extension(path).as_str() {
+        "pfm" => read_pfm(path),
+        "exr" | "hdr" => {
+            let image = image::open(path)?.to_rgb32f();
+            let pixels = image.pixels().map(|p| Vector3::new(p[0], p[1], p[2])).collect();
+            Ok(Frame::from_pixels(image.width(), image.height(), pixels))
+        }
+        _ => {
+            let image = image::open(path)?.to_rgb8();
+            let pixels = image.pixels().map(|p| Vector3::new(p[0] as f32, p[1] as f32, p[2] as f32) / 255.0).collect();
+            Ok(Frame::from_pixels(image.width(), image.height(), pixels))
+        }
+    }
+}
+
+/// Write `layers` to one EXR file, each layer named and with R, G and B channels. All layers
+/// have the size of the first.
+pub fn write_layers(layers: &[(&str, &Frame)], path: &Path) -> anyhow::Result<()> {
+    let Some((_, first)) = layers.first() else {
+        bail!("No layers to write to {:?}", path);
+    };
+    let size = (first.width() as usize, first.height() as usize);
+
+    let exr_layers: Vec<_> = layers
+        .iter()
+        .map(|(name, frame)| {
+            let pixels = frame.pixels();
+            let channel = |channel_name: &str, component: usize| {
+                AnyChannel::new(channel_name, FlatSamples::F32(pixels.iter().map(|p| p[component]).collect()))
+            };
+            let channels = AnyChannels::sort([channel("R", 0), channel("G", 1), channel("B", 2)].into_iter().collect());
+            Layer::new(size, LayerAttributes::named(*name), Encoding::FAST_LOSSLESS, channels)
+        })
+        .collect();
+
+    let image = Image::from_layers(ImageAttributes::new(IntegerBounds::from_dimensions(size)), exr_layers);
+    image.write().to_file(path).map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))
+}
+
+/// Named layers of an EXR file written by `write_layers`.
+pub fn read_layers(path: &Path) -> anyhow::Result<Vec<(String, Frame)>> {
+    let image = exr::prelude::read_all_flat_layers_from_file(path).map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))?;
+
+    image
+        .layer_data
+        .iter()
+        .map(|layer| {
+            let name = layer.attributes.layer_name.as_ref().map(|name| name.to_string()).unwrap_or_default();
+            let channel = |channel_name: &str| {
+                layer
+                    .channel_data
+                    .list
+                    .iter()
+                    .find(|channel| channel.name == *channel_name)
+                    .map(|channel| channel.sample_data.values_as_f32().collect::<Vec<_>>())
+                    .with_context(|| format!("Layer '{}' has no {} channel", name, channel_name))
+            };
+            let (r, g, b) = (channel("R")?, channel("G")?, channel("B")?);
+            let pixels = (0..r.len()).map(|index| Vector3::new(r[index], g[index], b[index])).collect();
+            Ok((name, Frame::from_pixels(layer.size.0 as u32, layer.size.1 as u32, pixels)))
+        })
+        .collect()
+}
+
+/// Whether the format of `path` stores values encoded for display rather than linear light.
+pub fn is_display_encoded(path: &Path) -> bool {
+    !matches!(extension(path).as_str(), "pfm" | "exr" | "hdr")
+}
+
+fn extension(path: &Path) -> String {
+    path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default()
+}
+
+/// Portable float map: a text header, then little endian RGB floats in rows from the bottom.
+fn write_pfm(frame: &Frame, path: &Path) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    // A negative scale means little endian
+    write!(file, "PF\n{} {}\n-1.0\n", frame.width(), frame.height())?;
+
+    let pixels = frame.pixels();
+    for row in pixels.chunks_exact(frame.width().max(1) as usize).rev() {
+        for value in row.iter().flat_map(|p| [p.x, p.y, p.z]) {
+            file.write_all(&value.to_le_bytes())?;
+        }
+    }
+    Ok(file.flush()?)
+}
+
+fn read_pfm(path: &Path) -> anyhow::Result<Frame> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut header = String::new();
+    for _ in 0..3 {
+        file.read_line(&mut header)?;
+    }
+
+    let mut fields = header.split_whitespace();
+    if fields.next() != Some("PF") {
+        bail!("{:?} is not an RGB portable float map", path);
+    }
+    let mut number = || fields.next().context("Truncated portable float map header");
+    let width: u32 = number()?.parse()?;
+    let height: u32 = number()?.parse()?;
+    let little_endian = number()?.parse::<f32>()? < 0.0;
+
+    let size = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(3 * 4))
+        .with_context(|| format!("{:?} is too large, {} by {} pixels", path, width, height))?;
+    let mut bytes = vec![0; size];
+    file.read_exact(&mut bytes)?;
+    let values: Vec<f32> = bytes
+        .chunks_exact(4)
+        .map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            if little_endian { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) }
+        })
+        .collect();
+
+    let pixels = values
+        .chunks_exact(width.max(1) as usize * 3)
+        .rev()
+        .flat_map(|row| row.chunks_exact(3).map(|p| Vector3::new(p[0], p[1], p[2])))
+        .collect();
+    Ok(Frame::from_pixels(width, height, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn float_formats_keep_values() {
+        let folder = std::env::temp_dir().join(format!("raytracer_image_io_{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let frame = Frame::from_pixels(3, 2, (0..6).map(|index| Vector3::new(index as f32 * 2.5, 0.25, 100.0)).collect());
+
+        for name in ["frame.pfm", "frame.exr"] {
+            let path = folder.join(name);
+            write(&frame, &path).unwrap();
+            assert_eq!(read(&path).unwrap().pixels(), frame.pixels(), "{}", name);
+        }
+
+        let path = folder.join("layers.exr");
+        let half = Frame::from_pixels(3, 2, frame.pixels().iter().map(|p| p / 2.0).collect());
+        write_layers(&[("beauty", &frame), ("denoised", &half)], &path).unwrap();
+        let layers = read_layers(&path).unwrap();
+        assert_eq!(layers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), vec!["beauty", "denoised"]);
+        assert_eq!(layers[1].1.pixels()[1], Vector3::new(1.25, 0.125, 50.0));
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn oversized_float_maps_are_rejected() {
+        let path = std::env::temp_dir().join(format!("raytracer_oversized_{}.pfm", std::process::id()));
+        std::fs::write(&path, "PF\n4294967295 4294967295\n-1.0\n").unwrap();
+        let result = read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err_and(|error| error.to_string().contains("too large")));
+    }
+}
@@ -1,36 +1,274 @@
-/*use crate::core::Ray;
-use crate::frame::Frame;
+use std::sync::Mutex;
+use crate::camera::shutter;
+use crate::camera::viewpoint::{CameraImpl, Viewpoint};
+use crate::consts::ETA_STACK_SIZE;
+use crate::context::Context;
+use crate::core::Ray;
+use crate::filter::Filter;
+use crate::frame::{Frame, PixelRect};
 use crate::integrator::integrator::Integrator;
-use crate::camera::viewpoint::Viewpoint;
+use crate::integrator::path_termination::{self, BounceCounter};
+use crate::math;
+use crate::options::{BounceLimits, RenderOptions};
+use crate::sampler::{self, Dimensions, Sampler, SampleIndex};
+use crate::scene::light::LightSource;
+use crate::scene::light_sampler::LightSampleContext;
+use crate::scene::material::{BsdfLobe, CachedTextureLookups, IOR_AIR};
 use crate::scene::scene::Scene;
-use crate::scene::ShadingContext;
-use nalgebra::{Point3, Vector2, Vector3, Vector4};
-use rand::Rng;
-use rayon::prelude::*;
-use crate::camera::perspective_camera::PerspectiveCamera;
-use crate::context::Context;
-use crate::scene::material::{CachedTextureLookups, Material, IOR_AIR};
 use crate::static_stack::StaticStack;
+use nalgebra::{Point3, Vector2, Vector3};
+use rand::Rng;
 
+/// Bidirectional path tracer. Every pixel traces one subpath from the camera and one from a light
+/// and connects every prefix of the one to every prefix of the other, weighting the strategies
+/// against each other with the balance heuristic. Paths that connect to the camera directly
+/// (light tracing) can land on any pixel, so they are splatted into the frame.
+#[derive(Default)]
 pub struct BidirectionalPathTracingIntegrator {}
 
-const MAX_BOUNCES: u32 = 32;
-const RR_WARMUP_BOUNCES: u32 = 3;
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
 
-const EYE_SUBPATH_LENGTH: u32 = MAX_BOUNCES;
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum TransportMode {
+    Radiance,
+    Importance,
+}
 
-#[derive(Copy, Clone)]
-struct SubpathVertex<'a> {
+#[derive(Clone, Debug)]
+struct Vertex {
+    kind: VertexKind,
     position: Point3<f32>,
+    /// Shading normal, zero for vertices that aren't on a surface.
     normal: Vector3<f32>,
-    tex_coords: Vector2<f32>,
-    tangent: Vector4<f32>,
-    material: &'a Material,
+    /// Towards the previous vertex of the subpath. For infinite lights, towards the light.
+    wo: Vector3<f32>,
+    tex_coord: Vector2<f32>,
+    material_index: u32,
+    /// The light this vertex is on, also set for surfaces of emissive meshes.
+    light_index: Option<usize>,
+    /// Environment and directional lights, which are infinitely far away.
+    infinite: bool,
+    /// Point, spot and directional lights, which camera subpaths can never hit.
+    delta_light: bool,
+    /// The subpath left this vertex through a delta lobe.
+    delta: bool,
+    /// The lobe the subpath left this vertex through.
+    lobe: Option<BsdfLobe>,
+    beta: Vector3<f32>,
+    /// Area pdf of sampling this vertex from the previous one of its own subpath.
+    pdf_fwd: f32,
+    /// Area pdf of sampling this vertex from the next one, as if the path was traced the other way.
+    pdf_rev: f32,
+}
+
+/// Light tracing contribution to the pixel at `index`.
+struct Splat {
+    index: usize,
+    value: Vector3<f32>,
+}
+
+/// What the subpaths of one camera sample share: the scene, the camera at the time of the sample
+/// and the sampler the subpaths take their random numbers from.
+struct PathContext<'a, S: Sampler> {
+    scene: &'a Scene,
+    camera: &'a CameraImpl,
+    options: &'a RenderOptions,
+    time: f32,
+    /// Width and height of the image light tracing splats onto.
+    image_size: (usize, usize),
+    sampler: &'a mut S,
+    ctx: &'a Context,
 }
 
-struct Subpath {
-    vertices: [SubpathVertex; EYE_SUBPATH_LENGTH as usize],
-    path_length: u32,
+const MIN_PDF: f32 = 1e-5;
+const RAY_OFFSET: f32 = 0.001;
+
+impl Vertex {
+    fn new(kind: VertexKind, position: Point3<f32>, beta: Vector3<f32>) -> Self {
+        Self {
+            kind,
+            position,
+            normal: Vector3::zeros(),
+            wo: Vector3::zeros(),
+            tex_coord: Vector2::zeros(),
+            material_index: 0,
+            light_index: None,
+            infinite: false,
+            delta_light: false,
+            delta: false,
+            lobe: None,
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn is_on_surface(&self) -> bool {
+        self.normal != Vector3::zeros()
+    }
+
+    fn is_light(&self) -> bool {
+        self.kind == VertexKind::Light || self.light_index.is_some()
+    }
+
+    /// Unit direction from `self` towards `other`.
+    fn direction_to(&self, other: &Vertex) -> Vector3<f32> {
+        if other.infinite {
+            other.wo
+        } else if self.infinite {
+            -self.wo
+        } else {
+            (other.position - self.position).normalize()
+        }
+    }
+
+    /// Lobe of a connection from `wo` through this vertex towards `other`. Connections through
+    /// the surface transmit, but which reflective lobe one takes isn't known, so those have none.
+    fn connection_lobe(&self, other: &Vertex) -> Option<BsdfLobe> {
+        if self.kind != VertexKind::Surface {
+            return None;
+        }
+        let wi = self.direction_to(other);
+        (self.wo.dot(&self.normal) * wi.dot(&self.normal) < 0.0).then_some(BsdfLobe::Transmission)
+    }
+
+    /// Point slightly off the surface towards `direction`, to start rays from.
+    fn offset_towards(&self, direction: &Vector3<f32>) -> Point3<f32> {
+        if !self.is_on_surface() {
+            return self.position;
+        }
+        let sign = if self.normal.dot(direction) >= 0.0 { 1.0 } else { -1.0 };
+        self.position + self.normal * (RAY_OFFSET * sign)
+    }
+
+    /// Convert a solid angle pdf of sampling `next` from this vertex to an area pdf.
+    fn convert_density(&self, pdf: f32, next: &Vertex) -> f32 {
+        if next.infinite {
+            return pdf;
+        }
+
+        let to_next = next.position - self.position;
+        let distance_sq = to_next.norm_squared();
+        if distance_sq <= 0.0 {
+            return 0.0;
+        }
+
+        let mut pdf = pdf / distance_sq;
+        if next.is_on_surface() {
+            pdf *= next.normal.dot(&(to_next / distance_sq.sqrt())).abs();
+        }
+        pdf
+    }
+
+    /// The BSDF for light arriving from `next`, with `wo` as the other direction.
+    fn f(&self, scene: &Scene, next: &Vertex, mode: TransportMode) -> Vector3<f32> {
+        let wi = self.direction_to(next);
+        let (view_dir, light_dir) = match mode {
+            TransportMode::Radiance => (self.wo, wi),
+            TransportMode::Importance => (wi, self.wo),
+        };
+
+        let material = &scene.materials()[self.material_index as usize];
+        let mut cached_textures = CachedTextureLookups::new(material, self.tex_coord);
+        let albedo = cached_textures.albedo();
+
+        let n_dot_v = self.normal.dot(&view_dir);
+        let n_dot_l = self.normal.dot(&light_dir);
+        if n_dot_v > 0.0 && n_dot_l > 0.0 {
+            material.evaluate_bsdf(&light_dir, &view_dir, &self.normal, &albedo, &mut cached_textures)
+        } else if material.transmission_factor() > 0.0 && n_dot_v * n_dot_l < 0.0 {
+            let (normal, eta_i, eta_t) = if n_dot_v > 0.0 {
+                (self.normal, IOR_AIR, material.ior())
+            } else {
+                (-self.normal, material.ior(), IOR_AIR)
+            };
+            material.evaluate_btdf(&light_dir, &view_dir, &normal, &albedo, &mut cached_textures, eta_i, eta_t)
+        } else {
+            Vector3::zeros()
+        }
+    }
+
+    /// Area pdf of sampling `next` from this vertex when it was reached from `prev`.
+    fn pdf(&self, scene: &Scene, camera: &CameraImpl, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let pdf = match self.kind {
+            VertexKind::Light => return self.pdf_light(scene, next),
+            VertexKind::Camera => camera.pdf_direction(&self.direction_to(next)),
+            VertexKind::Surface => {
+                let Some(prev) = prev else {
+                    return 0.0;
+                };
+                let view_dir = self.direction_to(prev);
+                let light_dir = self.direction_to(next);
+
+                let material = &scene.materials()[self.material_index as usize];
+                let mut cached_textures = CachedTextureLookups::new(material, self.tex_coord);
+                let albedo = cached_textures.albedo();
+                material.pdf_bsdf(&light_dir, &view_dir, &self.normal, &albedo, &mut cached_textures)
+            }
+        };
+
+        self.convert_density(pdf, next)
+    }
+
+    /// Area pdf of a light subpath starting on this light vertex continuing to `next`.
+    fn pdf_light(&self, scene: &Scene, next: &Vertex) -> f32 {
+        let Some(light_index) = self.light_index else {
+            return 0.0;
+        };
+        let direction = self.direction_to(next);
+
+        let mut pdf = if self.infinite {
+            let radius = scene.bounding_sphere().1;
+            1.0 / (std::f32::consts::PI * radius * radius)
+        } else {
+            let (_, pdf_direction) = scene.light_emission_pdf(light_index, &self.normal, &direction);
+            pdf_direction / (next.position - self.position).norm_squared()
+        };
+
+        if next.is_on_surface() {
+            pdf *= next.normal.dot(&direction).abs();
+        }
+        pdf
+    }
+
+    /// Pdf of a light subpath starting on this light vertex, heading towards `next`.
+    fn pdf_light_origin(&self, scene: &Scene, next: &Vertex) -> f32 {
+        let Some(light_index) = self.light_index else {
+            return 0.0;
+        };
+
+        if self.infinite {
+            if self.delta_light {
+                0.0
+            } else {
+                scene.environment_emission_pdf(&self.wo)
+            }
+        } else {
+            scene.light_emission_pdf(light_index, &self.normal, &self.direction_to(next)).0
+        }
+    }
+
+    /// Radiance emitted from this vertex towards `prev`.
+    fn emitted(&self, scene: &Scene, prev: &Vertex) -> Vector3<f32> {
+        if !self.is_light() {
+            return Vector3::zeros();
+        }
+
+        if self.infinite {
+            return scene.environment(&Ray::new(prev.position, self.wo));
+        }
+
+        if self.normal.dot(&self.direction_to(prev)) <= 0.0 {
+            return Vector3::zeros();
+        }
+        let material = &scene.materials()[self.material_index as usize];
+        material.sample_emissive(self.tex_coord.x, self.tex_coord.y)
+    }
 }
 
 impl BidirectionalPathTracingIntegrator {
@@ -38,51 +276,588 @@ impl BidirectionalPathTracingIntegrator {
         Self {}
     }
 
-    fn create_eye_subpath(&self, camera_ray: Ray, scene: &Scene, ctx: &Context) -> Subpath {
-        let mut subpath = Subpath {
-            vertices: [SubpathVertex {}; EYE_SUBPATH_LENGTH as usize],
-            path_length: 0,
+    /// Extend `path` by sampling the BSDF at every vertex, until it holds `max_vertices` vertices,
+    /// leaves the scene or is terminated by the bounce limits or Russian roulette.
+    /// `pdf` is the solid angle pdf of `ray`.
+    fn random_walk<S: Sampler>(
+        path_context: &mut PathContext<S>,
+        ray: Ray,
+        beta: Vector3<f32>,
+        pdf: f32,
+        max_vertices: usize,
+        mode: TransportMode,
+        path: &mut Vec<Vertex>,
+    ) {
+        let (scene, options, ctx) = (path_context.scene, path_context.options, path_context.ctx);
+        let rng = &mut *path_context.sampler;
+        let mut ray = ray;
+        let mut beta = beta;
+        let mut pdf_fwd = pdf;
+        // Assume initial eta = 1.000277 (Air) for all rays
+        let mut eta_stack = StaticStack::<f32, ETA_STACK_SIZE>::new_with_default(IOR_AIR);
+        let mut bounces = BounceCounter::default();
+
+        while path.len() < max_vertices {
+            let previous = path.len() - 1;
+
+            let Some(hit) = scene.intersect(&ray, ctx) else {
+                // Camera subpaths that escape end on the environment.
+                if mode == TransportMode::Radiance && scene.environment_light().is_some() {
+                    let (center, radius) = scene.bounding_sphere();
+                    let mut vertex = Vertex::new(VertexKind::Light, center + ray.direction() * (2.0 * radius + 1.0), beta);
+                    vertex.wo = ray.direction();
+                    vertex.infinite = true;
+                    vertex.light_index = scene.lights().iter().position(|light| matches!(light, LightSource::Environment(_) | LightSource::Sky(_)));
+                    vertex.pdf_fwd = pdf_fwd;
+                    path.push(vertex);
+                }
+                break;
+            };
+
+            let material = &scene.materials()[hit.material_index as usize];
+            let tex_coord = hit.intersection.tex_coord;
+            let mut cached_textures = CachedTextureLookups::new(material, tex_coord);
+            let albedo = cached_textures.albedo();
+            let normal = material.apply_normal_map(hit.intersection.normal, hit.intersection.tangent, tex_coord);
+
+            let mut vertex = Vertex::new(VertexKind::Surface, ray.origin() + ray.direction() * hit.intersection.dist, beta);
+            vertex.normal = normal;
+            vertex.wo = -ray.direction().normalize();
+            vertex.tex_coord = tex_coord;
+            vertex.material_index = hit.material_index;
+            vertex.light_index = scene.meshes()[hit.mesh_index as usize].light_index();
+            vertex.pdf_fwd = path[previous].convert_density(pdf_fwd, &vertex);
+            path.push(vertex);
+
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let current = path.len() - 1;
+            let sample = material.sample_bsdf(ray.direction(), normal, &mut cached_textures, rng, &mut eta_stack, ctx);
+
+            let cos_theta = if sample.is_transmission {
+                sample.direction.dot(&normal).abs()
+            } else {
+                sample.direction.dot(&normal).max(0.0)
+            };
+            if sample.pdf <= MIN_PDF || cos_theta <= 0.0 {
+                break;
+            }
+
+            let scale = sample.bsdf_value * (cos_theta / sample.pdf);
+            beta = beta.component_mul(&scale);
+            if !math::is_greater_than_zero(beta) {
+                break;
+            }
+
+            let pdf_rev = if sample.is_transmission {
+                // Refraction is a delta lobe; connections can't go through it.
+                path[current].delta = true;
+                pdf_fwd = 0.0;
+                0.0
+            } else {
+                let view_dir = path[current].wo;
+                pdf_fwd = material.pdf_bsdf(&sample.direction, &view_dir, &normal, &albedo, &mut cached_textures);
+                material.pdf_bsdf(&view_dir, &sample.direction, &normal, &albedo, &mut cached_textures)
+            };
+            path[previous].pdf_rev = path[current].convert_density(pdf_rev, &path[previous]);
+
+            path[current].lobe = Some(sample.lobe);
+            if !bounces.record(sample.lobe, &options.bounce_limits) {
+                break;
+            }
+
+            let bounce = (current - 1) as u32;
+            let survival_prob = path_termination::survival_probability(&options.russian_roulette, bounce, scale.max());
+            if survival_prob <= 0.0 || rng.random::<f32>() > survival_prob {
+                break;
+            }
+            beta /= survival_prob;
+
+            ray = Ray::new(path[current].offset_towards(&sample.direction), sample.direction).with_time(ray.time());
+        }
+    }
+
+    fn generate_camera_subpath<S: Sampler>(path_context: &mut PathContext<S>, ray: Ray, max_vertices: usize) -> Vec<Vertex> {
+        let camera = path_context.camera;
+        let mut path = Vec::with_capacity(max_vertices);
+        let mut vertex = Vertex::new(VertexKind::Camera, ray.origin(), Vector3::repeat(1.0));
+        vertex.delta = !camera.can_connect();
+        path.push(vertex);
+
+        let pdf_direction = camera.pdf_direction(&ray.direction());
+        Self::random_walk(path_context, ray, Vector3::repeat(1.0), pdf_direction, max_vertices, TransportMode::Radiance, &mut path);
+        path
+    }
+
+    fn generate_light_subpath<S: Sampler>(path_context: &mut PathContext<S>, max_vertices: usize) -> Vec<Vertex> {
+        let scene = path_context.scene;
+        let mut path = Vec::with_capacity(max_vertices);
+        let Some(emission) = scene.sample_light_emission(&mut *path_context.sampler) else {
+            return path;
         };
 
-        let mut length = 0;
-        let mut current_ray = camera_ray;
-        scene.intersect(&current_ray, ctx).map(|hit| {
-            subpath.vertices[length].position = current_ray.origin() + (current_ray.direction() * hit.intersection.dist);
-            subpath.vertices[length].normal = hit.intersection.normal;
-            subpath.vertices[length].tex_coords = hit.intersection.tex_coord;
-            subpath.vertices[length].tangent = hit.intersection.tangent;
-            length += 1;
+        let light = &scene.lights()[emission.light_index];
+        let mut vertex = Vertex::new(VertexKind::Light, emission.ray.origin(), emission.radiance);
+        vertex.normal = emission.normal;
+        vertex.light_index = Some(emission.light_index);
+        vertex.infinite = matches!(light, LightSource::Directional(_) | LightSource::Environment(_) | LightSource::Sky(_));
+        vertex.delta_light = matches!(light, LightSource::Point(_) | LightSource::Spot(_) | LightSource::Directional(_));
+        vertex.wo = -emission.ray.direction();
+        vertex.pdf_fwd = emission.pdf_position;
+        path.push(vertex);
+
+        let cos_theta = if emission.normal == Vector3::zeros() {
+            1.0
+        } else {
+            emission.normal.dot(&emission.ray.direction()).abs()
+        };
+        let beta = emission.radiance * (cos_theta / (emission.pdf_position * emission.pdf_direction));
+        let direction = emission.ray.direction();
+        let ray = emission.ray.with_time(path_context.time);
+        Self::random_walk(path_context, ray, beta, emission.pdf_direction, max_vertices, TransportMode::Importance, &mut path);
+
+        // Infinite lights pick a direction first and a position second, so their pdfs are the other way around.
+        if path[0].infinite {
+            if path.len() > 1 {
+                let radius = scene.bounding_sphere().1;
+                let mut pdf = 1.0 / (std::f32::consts::PI * radius * radius);
+                if path[1].is_on_surface() {
+                    pdf *= path[1].normal.dot(&direction).abs();
+                }
+                path[1].pdf_fwd = pdf;
+            }
+            path[0].pdf_fwd = scene.environment_emission_pdf(&path[0].wo);
+            if path[0].delta_light {
+                path[0].pdf_fwd = 0.0;
+            }
+        }
+
+        path
+    }
+
+    /// Sample a point on a light to connect to the camera subpath vertex `pt`.
+    /// Returns the light vertex and its unoccluded contribution.
+    fn sample_light_vertex(scene: &Scene, pt: &Vertex, time: f32, rng: &mut impl Sampler, ctx: &Context) -> Option<(Vertex, Vector3<f32>)> {
+        let context = LightSampleContext { position: pt.position, normal: pt.normal };
+        let sample = scene.sample_light(&context, rng)?;
+        let light = &scene.lights()[sample.light_index];
+
+        let mut vertex = Vertex::new(VertexKind::Light, Point3::origin(), Vector3::zeros());
+        vertex.light_index = Some(sample.light_index);
+        vertex.delta_light = matches!(light, LightSource::Point(_) | LightSource::Spot(_) | LightSource::Directional(_));
+
+        let (wi, transmission) = match sample.position {
+            Some(light_point) => {
+                let to_light = light_point - pt.position;
+                let distance_sq = to_light.norm_squared();
+                if distance_sq <= 1e-12 {
+                    return None;
+                }
+                let wi = to_light / distance_sq.sqrt();
+
+                vertex.position = light_point;
+                vertex.beta = if sample.is_delta {
+                    sample.radiance / (distance_sq * sample.pdf)
+                } else {
+                    let cos_theta_light = sample.wi.dot(&-wi);
+                    if cos_theta_light <= 0.0 {
+                        return None;
+                    }
+                    vertex.normal = sample.wi;
+                    sample.radiance * (cos_theta_light / (distance_sq * sample.pdf))
+                };
+
+                (wi, scene.transmissions_along_path_2(pt.offset_towards(&wi), light_point, time, ctx))
+            }
+            None => {
+                let wi = sample.wi;
+                let (center, radius) = scene.bounding_sphere();
+                vertex.position = center + wi * (2.0 * radius + 1.0);
+                vertex.wo = wi;
+                vertex.infinite = true;
+                vertex.beta = sample.radiance / sample.pdf;
+
+                let shadow_ray = Ray::new(pt.offset_towards(&wi), wi).with_time(time);
+                let visible = scene.intersect(&shadow_ray, ctx).is_none();
+                (wi, if visible { Vector3::repeat(1.0) } else { Vector3::zeros() })
+            }
+        };
+
+        if !math::is_greater_than_zero(transmission) {
+            return None;
+        }
+
+        vertex.pdf_fwd = vertex.pdf_light_origin(scene, pt);
+        let contribution = pt
+            .beta
+            .component_mul(&pt.f(scene, &vertex, TransportMode::Radiance))
+            .component_mul(&vertex.beta)
+            .component_mul(&transmission)
+            * pt.normal.dot(&wi).abs();
+
+        Some((vertex, contribution))
+    }
+
+    /// Geometry term between two vertices, including how much light makes it from one to the other.
+    fn geometry(scene: &Scene, v0: &Vertex, v1: &Vertex, time: f32, ctx: &Context) -> Vector3<f32> {
+        let d = v0.position - v1.position;
+        let distance_sq = d.norm_squared();
+        if distance_sq <= 1e-12 {
+            return Vector3::zeros();
+        }
+        let direction = d / distance_sq.sqrt();
+
+        let mut g = 1.0 / distance_sq;
+        if v0.is_on_surface() {
+            g *= v0.normal.dot(&direction).abs();
+        }
+        if v1.is_on_surface() {
+            g *= v1.normal.dot(&direction).abs();
+        }
+        if g <= 0.0 {
+            return Vector3::zeros();
+        }
+
+        let transmission = scene.transmissions_along_path_2(v0.offset_towards(&-direction), v1.offset_towards(&direction), time, ctx);
+        transmission * g
+    }
+
+    /// Contribution of the path made of the first `s` light vertices and `t` camera vertices,
+    /// and the pixel it lands on for light tracing.
+    fn connect<S: Sampler>(
+        path_context: &mut PathContext<S>,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        t: usize,
+    ) -> Option<(Vector3<f32>, Option<usize>)> {
+        let PathContext { scene, camera, options, time, image_size, ctx, .. } = *path_context;
+        let rng = &mut *path_context.sampler;
+        // Escaped camera subpaths can only be used as they are
+        if t > 1 && s != 0 && camera_path[t - 1].kind == VertexKind::Light {
+            return None;
+        }
+
+        // Each subpath keeps to the bounce limits, but together they can go over them
+        let mut lobes = Self::path_lobes(light_path, camera_path, s, t);
+        if !Self::within_bounce_limits(&lobes, t, &options.bounce_limits) {
+            return None;
+        }
+
+        let mut sampled = None;
+        let mut pixel_index = None;
+
+        let contribution = if s == 0 {
+            let pt = &camera_path[t - 1];
+            pt.beta.component_mul(&pt.emitted(scene, &camera_path[t - 2]))
+        } else if t == 1 {
+            let qs = &light_path[s - 1];
+            if qs.kind != VertexKind::Surface {
+                return None;
+            }
+
+            let connection = camera.connect(&qs.position, rng)?;
+            let wi = (connection.lens_point - qs.position).normalize();
+            let transmission = scene.transmissions_along_path_2(qs.offset_towards(&wi), connection.lens_point, time, ctx);
+            if !math::is_greater_than_zero(transmission) {
+                return None;
+            }
+
+            let (width, height) = image_size;
+            let x = ((connection.image_position.x * width as f32) as usize).min(width - 1);
+            let y = ((connection.image_position.y * height as f32) as usize).min(height - 1);
+            pixel_index = Some(y * width + x);
+
+            let vertex = Vertex::new(VertexKind::Camera, connection.lens_point, Vector3::repeat(connection.weight));
+            let contribution = qs
+                .beta
+                .component_mul(&qs.f(scene, &vertex, TransportMode::Importance))
+                .component_mul(&transmission)
+                * (connection.weight * qs.normal.dot(&wi).abs());
+            sampled = Some(vertex);
+            contribution
+        } else if s == 1 {
+            let (vertex, contribution) = Self::sample_light_vertex(scene, &camera_path[t - 1], time, rng, ctx)?;
+            sampled = Some(vertex);
+            contribution
+        } else {
+            let qs = &light_path[s - 1];
+            let pt = &camera_path[t - 1];
+            let f = qs
+                .beta
+                .component_mul(&qs.f(scene, pt, TransportMode::Importance))
+                .component_mul(&pt.f(scene, qs, TransportMode::Radiance))
+                .component_mul(&pt.beta);
+            if !math::is_greater_than_zero(f) {
+                return None;
+            }
+            f.component_mul(&Self::geometry(scene, qs, pt, time, ctx))
+        };
+
+        if !math::is_greater_than_zero(contribution) {
+            return None;
+        }
+
+        // Strategies that go over the bounce limits are left out of the weights. The connection
+        // vertices are inside the prefixes of the other strategies.
+        if s > 0 && t > 1 {
+            let qs = if s == 1 { sampled.as_ref()? } else { &light_path[s - 1] };
+            lobes[t - 1] = camera_path[t - 1].connection_lobe(qs);
+        }
+        if s > 1 {
+            let pt = if t == 1 { sampled.as_ref()? } else { &camera_path[t - 1] };
+            lobes[t] = light_path[s - 1].connection_lobe(pt);
+        }
+        let allowed: Vec<bool> = (0..=s + t).map(|t| Self::within_bounce_limits(&lobes, t, &options.bounce_limits)).collect();
+
+        let weight = Self::mis_weight(path_context, light_path, camera_path, sampled.as_ref(), s, t, &allowed);
+        Some((contribution * weight, pixel_index))
+    }
+
+    /// Lobes the path made of the first `s` light and `t` camera vertices scatters through, at each
+    /// of its vertices from the camera, as its subpaths sampled them. The connection vertices left
+    /// their subpaths through some other lobe, so they have none.
+    fn path_lobes(light_path: &[Vertex], camera_path: &[Vertex], s: usize, t: usize) -> Vec<Option<BsdfLobe>> {
+        let mut lobes: Vec<Option<BsdfLobe>> = camera_path[..t]
+            .iter()
+            .chain(light_path[..s].iter().rev())
+            .map(|vertex| vertex.lobe)
+            .collect();
+        lobes[t - 1] = None;
+        if s > 0 {
+            lobes[t] = None;
+        }
+        lobes
+    }
+
+    /// Whether the strategy with `t` camera vertices keeps to `limits` building a path with `lobes`
+    /// at its vertices. It counts the bounces of both its prefixes, but not those of the vertices
+    /// it connects, like light sampling in the path tracer.
+    fn within_bounce_limits(lobes: &[Option<BsdfLobe>], t: usize, limits: &BounceLimits) -> bool {
+        let mut bounces = BounceCounter::default();
+        lobes
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| index + 1 != t && index != t)
+            .filter_map(|(_, lobe)| *lobe)
+            .all(|lobe| bounces.record(lobe, limits))
+    }
+
+    /// Balance heuristic weight of the strategy that built the path from `s` light and `t` camera vertices.
+    /// `sampled` replaces the endpoint that was sampled specifically for the connection, if any.
+    /// Strategies with `t` camera vertices where `allowed[t]` is false don't count.
+    fn mis_weight<S: Sampler>(
+        path_context: &PathContext<S>,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+        allowed: &[bool],
+    ) -> f32 {
+        let PathContext { scene, camera, .. } = *path_context;
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        let mut light: Vec<Vertex> = light_path[..s].to_vec();
+        let mut cam: Vec<Vertex> = camera_path[..t].to_vec();
+        if let Some(sampled) = sampled {
+            if s == 1 {
+                light[0] = sampled.clone();
+            } else if t == 1 {
+                cam[0] = sampled.clone();
+            }
+        }
+
+        // The connection endpoints are never delta, whatever lobe their subpath continued with
+        cam[t - 1].delta = false;
+        if s > 0 {
+            light[s - 1].delta = false;
+        }
+
+        // Pdfs of the connection endpoints and their predecessors being sampled from the other side
+        let pt_pdf_rev = if s > 0 {
+            light[s - 1].pdf(scene, camera, s.checked_sub(2).map(|i| &light[i]), &cam[t - 1])
+        } else {
+            cam[t - 1].pdf_light_origin(scene, &cam[t - 2])
+        };
+        let pt_minus_pdf_rev = (t > 1).then(|| {
+            if s > 0 {
+                cam[t - 1].pdf(scene, camera, Some(&light[s - 1]), &cam[t - 2])
+            } else {
+                cam[t - 1].pdf_light(scene, &cam[t - 2])
+            }
         });
+        let qs_pdf_rev = (s > 0).then(|| cam[t - 1].pdf(scene, camera, t.checked_sub(2).map(|i| &cam[i]), &light[s - 1]));
+        let qs_minus_pdf_rev = (s > 1).then(|| light[s - 1].pdf(scene, camera, Some(&cam[t - 1]), &light[s - 2]));
+
+        cam[t - 1].pdf_rev = pt_pdf_rev;
+        if let Some(pdf) = pt_minus_pdf_rev {
+            cam[t - 2].pdf_rev = pdf;
+        }
+        if let Some(pdf) = qs_pdf_rev {
+            light[s - 1].pdf_rev = pdf;
+        }
+        if let Some(pdf) = qs_minus_pdf_rev {
+            light[s - 2].pdf_rev = pdf;
+        }
+
+        let remap = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+
+        let mut sum_ri = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap(cam[i].pdf_rev) / remap(cam[i].pdf_fwd);
+            if !cam[i].delta && !cam[i - 1].delta && allowed[i] {
+                sum_ri += ri;
+            }
+        }
+
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap(light[i].pdf_rev) / remap(light[i].pdf_fwd);
+            let delta_light_vertex = if i > 0 { light[i - 1].delta } else { light[0].delta_light };
+            if !light[i].delta && !delta_light_vertex && allowed[s + t - i] {
+                sum_ri += ri;
+            }
+        }
+
+        1.0 / (1.0 + sum_ri)
     }
 }
 
 impl Integrator for BidirectionalPathTracingIntegrator {
-    fn integrate(&self, scene: &Scene, frame: &mut Frame, samples: u32, ctx: &Context) {
-        // TODO: Can this "threading boilerplate" be moved outside the integrator, so every dont have to do the same thing?
+    fn integrate(&self, scene: &Scene, camera: &CameraImpl, frame: &mut Frame, pass: SampleIndex, options: &RenderOptions, ctx: &Context) {
+        let SampleIndex { index: sample, count: samples } = pass;
         let width = frame.width() as usize;
         let height = frame.height() as usize;
 
         let height_inv = 1.0 / height as f32;
         let width_inv = 1.0 / width as f32;
+        let region = PixelRect::rendered(options, width, height);
         let samples_inv = 1.0 / samples as f32;
+        let max_depth = options.max_bounces as usize;
 
+        let filter = Filter::new(options.filter);
 
-        frame.pixels_mut().par_chunks_mut(width).enumerate().for_each(|(y, row)| {
-            let mut rng = rand::rng();
-            let v = y as f32 * height_inv;
-            for x in 0..width {
-                let u = x as f32 * width_inv;
+        let splats = Mutex::new(Vec::new());
+        frame.add_rows(region.rows(), &filter, |y, strip| {
+            let mut sampler = sampler::create(options, samples);
+            let mut row_splats = Vec::new();
+            for x in region.columns() {
+                sampler.start_pixel_sample(x as u32, y as u32, sample);
+                let position = Vector2::new(x as f32, y as f32) + sampler.get_2d();
+                let u = position.x * width_inv;
+                let v = position.y * height_inv;
 
-                let ray = scene.active_camera().generate_ray(1.0 - u, 1.0 - v);
-                //let ray = scene.camera.generate_offset_ray(1.0 - u, 1.0 - v, 0.4, 16.0, &mut rng);
+                sampler.start(Dimensions::Time);
+                let time = shutter::sample_time(options.shutter.as_ref(), sampler.get_1d());
+                // Light tracing connects to the camera where it is at the same time
+                let camera = camera.at_time(time);
+                sampler.start(Dimensions::Lens);
+                let ray = camera.generate_offset_ray(1.0 - u, 1.0 - v, &mut sampler).with_time(time);
+                // The subpaths take the dimensions after the camera's in order
+                sampler.start(Dimensions::Light(0));
+                let mut path_context = PathContext { scene, camera: &camera, options, time, image_size: (width, height), sampler: &mut sampler, ctx };
+                let camera_path = Self::generate_camera_subpath(&mut path_context, ray, max_depth + 2);
+                let light_path = Self::generate_light_subpath(&mut path_context, max_depth + 1);
 
-                // Assume initial eta = 1.000277 (Air) for all rays
-                let mut eta_stack = StaticStack::<f32, 8>::new_with_default(IOR_AIR);
+                let mut radiance = Vector3::zeros();
+                for t in 1..=camera_path.len() {
+                    for s in 0..=light_path.len() {
+                        let depth = s + t;
+                        if (s == 1 && t == 1) || depth < 2 || depth - 2 > max_depth {
+                            continue;
+                        }
 
+                        match Self::connect(&mut path_context, &light_path, &camera_path, s, t) {
+                            Some((value, Some(index))) => row_splats.push(Splat { index, value }),
+                            Some((value, None)) => radiance += value,
+                            None => {}
+                        }
+                    }
+                }
 
-                row[x] += result * samples_inv;
+                strip.add_sample(position, radiance);
             }
-
+            splats.lock().unwrap().append(&mut row_splats);
         });
+
+        // Light paths are normalized by the number of pixels, but only the region starts them
+        let splat_scale = samples_inv * (width * height) as f32 / region.area() as f32;
+        for splat in splats.into_inner().unwrap() {
+            if region.contains(splat.index % width, splat.index / width) {
+                frame.add_splat(splat.index, splat.value * splat_scale);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::LightSampling;
+    use crate::scene::light::PointLight;
+
+    fn create_scene() -> Scene {
+        let lights = vec![LightSource::Point(PointLight::new(Point3::new(0.0, 2.0, 0.0), Vector3::new(1.0, 1.0, 1.0), 4.0, 0.0))];
+        Scene::new(Vec::new(), Vec::new(), Vec::new(), lights, LightSampling::Power)
+    }
+
+    fn surface_vertex(position: Point3<f32>, normal: Vector3<f32>) -> Vertex {
+        let mut vertex = Vertex::new(VertexKind::Surface, position, Vector3::repeat(1.0));
+        vertex.normal = normal;
+        vertex
+    }
+
+    #[test]
+    fn convert_density_applies_distance_and_cosine() {
+        let from = surface_vertex(Point3::origin(), Vector3::y());
+        let to = surface_vertex(Point3::new(0.0, 2.0, 0.0), Vector3::new(0.0, -1.0, 1.0).normalize());
+
+        let pdf = from.convert_density(1.0, &to);
+        assert!((pdf - std::f32::consts::FRAC_1_SQRT_2 / 4.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn joined_prefixes_keep_to_the_bounce_limits() {
+        let limits = BounceLimits { diffuse: Some(1), glossy: None, transmission: None };
+        // Camera, two surfaces from the camera, two from the light, light
+        let mut camera_path = vec![Vertex::new(VertexKind::Camera, Point3::origin(), Vector3::repeat(1.0))];
+        let mut light_path = vec![Vertex::new(VertexKind::Light, Point3::origin(), Vector3::repeat(1.0))];
+        for _ in 0..2 {
+            let mut vertex = surface_vertex(Point3::origin(), Vector3::y());
+            vertex.lobe = Some(BsdfLobe::Diffuse);
+            camera_path.push(vertex.clone());
+            light_path.push(vertex);
+        }
+
+        // Each prefix bounces diffusely once before the connection, two together
+        let lobes = BidirectionalPathTracingIntegrator::path_lobes(&light_path, &camera_path, 3, 3);
+        assert!(!BidirectionalPathTracingIntegrator::within_bounce_limits(&lobes, 3, &limits));
+        // Connecting next to either end leaves one bounce
+        assert!(BidirectionalPathTracingIntegrator::within_bounce_limits(&lobes, 2, &limits));
+        assert!(BidirectionalPathTracingIntegrator::within_bounce_limits(&lobes, 4, &limits));
+
+        let limits = BounceLimits { diffuse: Some(2), ..limits };
+        assert!(BidirectionalPathTracingIntegrator::within_bounce_limits(&lobes, 3, &limits));
     }
-}*/
\ No newline at end of file
+
+    #[test]
+    fn point_light_vertex_pdfs() {
+        let scene = create_scene();
+        let mut light = Vertex::new(VertexKind::Light, Point3::new(0.0, 2.0, 0.0), Vector3::repeat(1.0));
+        light.light_index = Some(0);
+        light.delta_light = true;
+        let surface = surface_vertex(Point3::origin(), Vector3::y());
+
+        // Only one light, so picking it is certain and the position is a delta.
+        assert!((light.pdf_light_origin(&scene, &surface) - 1.0).abs() < 1e-5);
+        // Uniform over the sphere, converted to area at distance 2 facing the light.
+        let expected = 1.0 / (4.0 * std::f32::consts::PI) / 4.0;
+        assert!((light.pdf_light(&scene, &surface) - expected).abs() < 1e-6);
+    }
+}
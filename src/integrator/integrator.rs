@@ -1,42 +1,63 @@
-use crate::camera::perspective_camera::PerspectiveCamera;
+use crate::camera::viewpoint::CameraImpl;
 use crate::context::Context;
 use crate::frame::Frame;
 use crate::integrator::albedo::AlbedoIntegrator;
+use crate::integrator::bdpt::BidirectionalPathTracingIntegrator;
 use crate::integrator::normal::NormalIntegrator;
 use crate::integrator::pathtracing::PathTracingIntegrator;
+use crate::integrator::sppm::SppmIntegrator;
 use crate::options::RenderOptions;
+use crate::sampler::SampleIndex;
 use crate::scene::scene::Scene;
 
 pub trait Integrator {
-    fn integrate(&self, scene: &Scene, camera: &PerspectiveCamera, frame: &mut Frame, samples: u32, options: &RenderOptions, ctx: &Context);
+    /// Add the sample `pass` of every pixel to `frame`.
+    fn integrate(&self, scene: &Scene, camera: &CameraImpl, frame: &mut Frame, pass: SampleIndex, options: &RenderOptions, ctx: &Context);
 }
 
 pub enum IntegratorImpl {
     Normal(NormalIntegrator),
     Pathtracing(PathTracingIntegrator),
     Albedo(AlbedoIntegrator),
+    Bdpt(BidirectionalPathTracingIntegrator),
+    Sppm(SppmIntegrator),
 }
 
 impl Integrator for IntegratorImpl {
-    fn integrate(&self, scene: &Scene, camera: &PerspectiveCamera, frame: &mut Frame, samples: u32, options: &RenderOptions, ctx: &Context) {
+    fn integrate(&self, scene: &Scene, camera: &CameraImpl, frame: &mut Frame, pass: SampleIndex, options: &RenderOptions, ctx: &Context) {
         match self {
             IntegratorImpl::Normal(i) => {
-                i.integrate(scene, camera, frame, samples, options, ctx);
+                i.integrate(scene, camera, frame, pass, options, ctx);
             }
             IntegratorImpl::Pathtracing(i) => {
-                i.integrate(scene, camera, frame, samples, options, ctx);
+                i.integrate(scene, camera, frame, pass, options, ctx);
             },
             IntegratorImpl::Albedo(i) => {
-                i.integrate(scene, camera, frame, samples, options, ctx);
+                i.integrate(scene, camera, frame, pass, options, ctx);
+            }
+            IntegratorImpl::Bdpt(i) => {
+                i.integrate(scene, camera, frame, pass, options, ctx);
+            }
+            IntegratorImpl::Sppm(i) => {
+                i.integrate(scene, camera, frame, pass, options, ctx);
             }
         }
     }
 }
 
 pub fn create(options: &RenderOptions) -> IntegratorImpl {
+    if !options.aovs.is_empty() && !matches!(options.integrator, crate::options::Integrator::Pathtracing) {
+        eprintln!("Warning: only the path tracer fills AOVs, they will be empty");
+    }
+    if options.adaptive_sampling.is_some() && !matches!(options.integrator, crate::options::Integrator::Pathtracing) {
+        eprintln!("Warning: only the path tracer samples adaptively, every pixel takes all samples");
+    }
+
     match options.integrator {
         crate::options::Integrator::Pathtracing => IntegratorImpl::Pathtracing(PathTracingIntegrator::new()),
         crate::options::Integrator::Albedo => IntegratorImpl::Albedo(AlbedoIntegrator {}),
         crate::options::Integrator::Debug => IntegratorImpl::Normal(NormalIntegrator {}),
+        crate::options::Integrator::Bdpt => IntegratorImpl::Bdpt(BidirectionalPathTracingIntegrator::new()),
+        crate::options::Integrator::Sppm(settings) => IntegratorImpl::Sppm(SppmIntegrator::new(settings)),
     }
 }
\ No newline at end of file
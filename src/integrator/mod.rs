@@ -2,4 +2,6 @@ pub mod integrator;
 pub mod pathtracing;
 pub mod normal;
 pub mod bdpt;
-pub mod albedo;
\ No newline at end of file
+pub mod sppm;
+pub mod albedo;
+pub mod path_termination;
\ No newline at end of file
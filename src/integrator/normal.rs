@@ -1,13 +1,13 @@
-use crate::camera::perspective_camera::PerspectiveCamera;
-use crate::camera::viewpoint::Viewpoint;
+use crate::camera::shutter;
+use crate::camera::viewpoint::CameraImpl;
 use crate::context::Context;
-use crate::frame::Frame;
+use crate::filter::Filter;
+use crate::frame::{Frame, PixelRect};
 use crate::integrator::integrator::Integrator;
 use crate::options::RenderOptions;
+use crate::sampler::{self, Dimensions, Sampler, SampleIndex};
 use crate::scene::scene::Scene;
 use nalgebra::{Vector2, Vector3};
-use rayon::prelude::ParallelSliceMut;
-use rayon::prelude::*;
 
 pub struct NormalIntegrator {}
 
@@ -18,37 +18,42 @@ impl NormalIntegrator {
 }
 
 impl Integrator for NormalIntegrator {
-    fn integrate(&self, scene: &Scene, camera: &PerspectiveCamera, frame: &mut Frame, _samples: u32, options: &RenderOptions, ctx: &Context) {
+    fn integrate(&self, scene: &Scene, camera: &CameraImpl, frame: &mut Frame, pass: SampleIndex, options: &RenderOptions, ctx: &Context) {
+        let SampleIndex { index: sample, count: samples } = pass;
         let width = frame.width() as usize;
         let height = frame.height() as usize;
 
         let height_inv = 1.0 / height as f32;
         let width_inv = 1.0 / width as f32;
+        let region = PixelRect::rendered(options, width, height);
 
-        frame
-            .pixels_mut()
-            .par_chunks_mut(width)
-            .enumerate()
-            .for_each(|(y, row)| {
-                let v = y as f32 * height_inv;
-                for x in 0..width {
-                    let mut rng = rand::rng();
-                    let u = x as f32 * width_inv;
-
-                    let ray = camera.generate_offset_ray(1.0 - u, 1.0 - v, &mut rng);
-                    if let Some(hit) = scene.intersect(&ray, ctx) {
-                        let u = hit.intersection.tex_coord.x;
-                        let v = hit.intersection.tex_coord.y;
-                        let tex_coord = Vector2::new(u, v);
-                        let material = &scene.materials()[hit.material_index as usize];
-                        let normal = material.apply_normal_map(hit.intersection.normal, hit.intersection.tangent, tex_coord);
-
-                        row[x] = normal;
-                    }
-                    else {
-                        row[x] = Vector3::zeros();
-                    }
+        let filter = Filter::new(options.filter);
+
+        frame.add_rows(region.rows(), &filter, |y, strip| {
+            let mut sampler = sampler::create(options, samples);
+            for x in region.columns() {
+                sampler.start_pixel_sample(x as u32, y as u32, sample);
+                let position = Vector2::new(x as f32, y as f32) + sampler.get_2d();
+                let u = position.x * width_inv;
+                let v = position.y * height_inv;
+
+                sampler.start(Dimensions::Time);
+                let time = shutter::sample_time(options.shutter.as_ref(), sampler.get_1d());
+                sampler.start(Dimensions::Lens);
+                let ray = camera.generate_ray_at_time(1.0 - u, 1.0 - v, time, &mut sampler);
+                if let Some(hit) = scene.intersect(&ray, ctx) {
+                    let u = hit.intersection.tex_coord.x;
+                    let v = hit.intersection.tex_coord.y;
+                    let tex_coord = Vector2::new(u, v);
+                    let material = &scene.materials()[hit.material_index as usize];
+                    let normal = material.apply_normal_map(hit.intersection.normal, hit.intersection.tangent, tex_coord);
+
+                    strip.add_sample(position, normal);
+                }
+                else {
+                    strip.add_sample(position, Vector3::zeros());
                 }
-            });
+            }
+        });
     }
 }
\ No newline at end of file
@@ -0,0 +1,64 @@
+use crate::options::{BounceLimits, RussianRoulette};
+use crate::scene::material::BsdfLobe;
+
+/// Bounces a path has taken through each kind of lobe.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BounceCounter {
+    diffuse: u32,
+    glossy: u32,
+    transmission: u32,
+}
+
+impl BounceCounter {
+    /// Count a bounce through `lobe`. Returns false if the path now has more bounces through
+    /// that lobe than `limits` allow, and should be terminated.
+    pub fn record(&mut self, lobe: BsdfLobe, limits: &BounceLimits) -> bool {
+        let (count, limit) = match lobe {
+            BsdfLobe::Diffuse => (&mut self.diffuse, limits.diffuse),
+            BsdfLobe::Glossy => (&mut self.glossy, limits.glossy),
+            BsdfLobe::Transmission => (&mut self.transmission, limits.transmission),
+        };
+
+        *count += 1;
+        limit.is_none_or(|limit| *count <= limit)
+    }
+}
+
+/// Probability of continuing a path after `bounce` bounces, given a proxy `weight` of how much
+/// of its throughput the last bounce kept.
+pub fn survival_probability(settings: &RussianRoulette, bounce: u32, weight: f32) -> f32 {
+    if bounce < settings.start_bounce {
+        return 1.0;
+    }
+
+    if weight.is_nan() {
+        return 0.0;
+    }
+    weight.max(settings.min_survival).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounce_counter_respects_per_lobe_limits() {
+        let limits = BounceLimits { diffuse: Some(1), glossy: None, transmission: Some(0) };
+        let mut counter = BounceCounter::default();
+
+        assert!(counter.record(BsdfLobe::Diffuse, &limits));
+        assert!(!counter.record(BsdfLobe::Diffuse, &limits));
+        assert!((0..10).all(|_| counter.record(BsdfLobe::Glossy, &limits)));
+        assert!(!counter.record(BsdfLobe::Transmission, &limits));
+    }
+
+    #[test]
+    fn survival_probability_starts_after_warmup_and_is_clamped() {
+        let settings = RussianRoulette { start_bounce: 2, min_survival: 0.1 };
+
+        assert_eq!(survival_probability(&settings, 1, 0.0), 1.0);
+        assert_eq!(survival_probability(&settings, 2, 0.0), 0.1);
+        assert_eq!(survival_probability(&settings, 2, 0.5), 0.5);
+        assert_eq!(survival_probability(&settings, 5, 3.0), 1.0);
+    }
+}
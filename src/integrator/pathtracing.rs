@@ -1,30 +1,90 @@
-use crate::camera::perspective_camera::PerspectiveCamera;
-use crate::camera::viewpoint::Viewpoint;
+use crate::aov::AovSample;
+use crate::camera::shutter;
+use crate::camera::viewpoint::CameraImpl;
 use crate::consts::ETA_STACK_SIZE;
 use crate::context::Context;
 use crate::core::Ray;
-use crate::frame::Frame;
+use crate::filter::Filter;
+use crate::frame::{Frame, PixelRect};
 use crate::integrator::integrator::Integrator;
+use crate::integrator::path_termination::{self, BounceCounter};
 use crate::math;
 use crate::options::RenderOptions;
-use crate::scene::material::{CachedTextureLookups, IOR_AIR};
+use crate::sampler::{self, Dimensions, PixelSampler, Sampler, SampleIndex};
+use crate::scene::material::{BsdfLobe, CachedTextureLookups, IOR_AIR};
 use crate::scene::scene::Scene;
+use crate::scene::light_sampler::LightSampleContext;
 use crate::scene::ShadingContext;
 use crate::static_stack::StaticStack;
-use nalgebra::Vector3;
-use rand::Rng;
-use rayon::prelude::*;
+use nalgebra::{Vector2, Vector3};
 
 pub struct PathTracingIntegrator {}
 
+/// Light reflected or transmitted at one vertex, by lobe.
+#[derive(Default)]
+struct LobeRadiance {
+    diffuse: Vector3<f32>,
+    specular: Vector3<f32>,
+    transmission: Vector3<f32>,
+}
+
+impl LobeRadiance {
+    /// `light` reflected by the diffuse and specular parts of a BRDF.
+    fn reflected(light: Vector3<f32>, (diffuse, specular): (Vector3<f32>, Vector3<f32>)) -> Self {
+        Self { diffuse: light.component_mul(&diffuse), specular: light.component_mul(&specular), ..Default::default() }
+    }
+
+    fn total(&self) -> Vector3<f32> {
+        self.diffuse + self.specular + self.transmission
+    }
+}
+
 struct ShadeResult {
-    radiance: Vector3<f32>,
+    /// MIS weighted emission of the hit surface.
+    emission: Vector3<f32>,
+    direct_light: LobeRadiance,
     next_ray: Option<Ray>,
     throughput: Vector3<f32>,
+    /// Solid angle pdf of the BSDF sample that produced `next_ray`, used to MIS-weight
+    /// emission found by it. `None` for delta lobes, which light sampling can't reproduce.
+    bsdf_pdf: Option<f32>,
+    /// Where `next_ray` leaves from, for evaluating light selection probabilities of what it hits.
+    light_context: LightSampleContext,
+    /// The lobe `next_ray` was sampled from.
+    lobe: Option<BsdfLobe>,
 }
 
-const MAX_BOUNCES: u32 = 32;
-const RR_WARMUP_BOUNCES: u32 = 3;
+/// State of the path traced for one sample of a pixel, carried from vertex to vertex.
+struct PathState<'a> {
+    options: &'a RenderOptions,
+    sampler: &'a mut PixelSampler,
+    eta_stack: StaticStack<f32, ETA_STACK_SIZE>,
+    aov: AovSample,
+    ctx: &'a Context,
+    /// Solid angle pdf of the BSDF sample that led to the current vertex, see
+    /// [`ShadeResult::bsdf_pdf`]. `None` at the camera.
+    bsdf_pdf: Option<f32>,
+    /// Where the ray to the current vertex left from.
+    light_context: LightSampleContext,
+}
+
+impl<'a> PathState<'a> {
+    fn new(ray: &Ray, options: &'a RenderOptions, sampler: &'a mut PixelSampler, ctx: &'a Context) -> Self {
+        Self {
+            options,
+            sampler,
+            // Assume initial eta = 1.000277 (Air) for all rays
+            eta_stack: StaticStack::new_with_default(IOR_AIR),
+            aov: AovSample::default(),
+            ctx,
+            bsdf_pdf: None,
+            light_context: LightSampleContext {
+                position: ray.origin(),
+                normal: Vector3::zeros(),
+            },
+        }
+    }
+}
 
 impl PathTracingIntegrator {
     pub fn new() -> Self {
@@ -37,10 +97,11 @@ impl PathTracingIntegrator {
         scene: &Scene,
         remaining_depth: u32,
         bounce_index: u32,
-        rng: &mut impl Rng,
-        eta_stack: &mut StaticStack<f32, ETA_STACK_SIZE>,
-        ctx: &Context,
+        path: &mut PathState,
     ) -> ShadeResult {
+        let sampler = &mut *path.sampler;
+        let eta_stack = &mut path.eta_stack;
+        let ctx = path.ctx;
         let tex_coords = hit.intersection.tex_coord;
         let material = &scene.materials()[hit.material_index as usize];
         let mut cached_textures = CachedTextureLookups::new(&material, tex_coords);
@@ -55,8 +116,13 @@ impl PathTracingIntegrator {
         );
 
         // Direct lighting: explicitly sample light sources
-        let mut direct_light = Vector3::zeros();
-        if let Some(light_sample) = scene.sample_light(rng) {
+        let light_context = LightSampleContext {
+            position: surface_point,
+            normal,
+        };
+        let mut direct_light = LobeRadiance::default();
+        sampler.start(Dimensions::Light(bounce_index));
+        if let Some(light_sample) = scene.sample_light(&light_context, sampler) {
             if light_sample.is_delta {
                 if let Some(light_point) = light_sample.position {
                     // Delta point light contribution.
@@ -70,23 +136,26 @@ impl PathTracingIntegrator {
 
                         if n_dot_l > 0.0 || (n_dot_l < 0.0 && material.transmission_factor() > 0.0)  {
                             let transmission =
-                                scene.transmissions_along_path_2(surface_point, light_point, ctx);
+                                scene.transmissions_along_path_2(surface_point, light_point, ray.time(), ctx);
                             if math::is_greater_than_zero(transmission) {
 
                                 let view_dir = -ray.direction();
-                                let contribution = if n_dot_l > 0.0 {
+                                let light = (light_sample.radiance / (distance_sq * light_sample.pdf))
+                                    .component_mul(&transmission)
+                                    * n_dot_l.abs();
 
-                                    material.evaluate_bsdf(
+                                direct_light = if n_dot_l > 0.0 {
+                                    LobeRadiance::reflected(light, material.evaluate_bsdf_lobes(
                                         &light_dir,
                                         &view_dir,
                                         &normal,
                                         &albedo,
                                         &mut cached_textures,
-                                    )
+                                    ))
                                 } else {
                                     let eta_i = eta_stack.peek();
                                     let eta_t = material.ior();
-                                    material.evaluate_btdf(
+                                    let btdf = material.evaluate_btdf(
                                         &light_dir,
                                         &view_dir,
                                         &normal,
@@ -94,14 +163,9 @@ impl PathTracingIntegrator {
                                         &mut cached_textures,
                                         eta_i,
                                         eta_t
-                                    )
+                                    );
+                                    LobeRadiance { transmission: light.component_mul(&btdf), ..Default::default() }
                                 };
-
-
-                                direct_light = (light_sample.radiance / distance_sq)
-                                    .component_mul(&contribution)
-                                    .component_mul(&transmission)
-                                    * n_dot_l.abs();
                             }
                         }
                     }
@@ -111,19 +175,17 @@ impl PathTracingIntegrator {
                     let cos_theta = normal.dot(&light_dir).max(0.0);
                     if cos_theta > 0.0 {
                         // Cast shadow ray to check visibility
-                        let shadow_ray = Ray::new(surface_point, light_dir);
+                        let shadow_ray = Ray::new(surface_point, light_dir).with_time(ray.time());
                         if scene.intersect(&shadow_ray, ctx).is_none() {
                             let view_dir = -ray.direction();
-                            let brdf = material.evaluate_bsdf(
+                            let brdf = material.evaluate_bsdf_lobes(
                                 &light_dir,
                                 &view_dir,
                                 &normal,
                                 &albedo,
                                 &mut cached_textures,
                             );
-                            direct_light = (light_sample.radiance / light_sample.pdf)
-                                .component_mul(&brdf)
-                                * cos_theta;
+                            direct_light = LobeRadiance::reflected(light_sample.radiance / light_sample.pdf * cos_theta, brdf);
                         }
                     }
                 }
@@ -138,45 +200,94 @@ impl PathTracingIntegrator {
                 let cos_theta_light = light_sample.wi.dot(&(-light_dir)).max(0.0);
 
                 if cos_theta > 0.0 && cos_theta_light > 0.0 {
-                    let transmission =
-                        scene.transmissions_along_path_2(surface_point, light_point, ctx);
+                    // BSDF sampled paths find intersectable lights through transmissive surfaces
+                    // unweighted, so light sampling stops at them or that light counts twice
+                    let transmission = if !light_sample.intersectable {
+                        scene.transmissions_along_path_2(surface_point, light_point, ray.time(), ctx)
+                    } else if scene.is_visible(surface_point, light_point, ray.time(), ctx) {
+                        Vector3::repeat(1.0)
+                    } else {
+                        Vector3::zeros()
+                    };
                     if math::is_greater_than_zero(transmission) {
                         let view_dir = -ray.direction();
-                        let brdf = material.evaluate_bsdf(
+                        let brdf = material.evaluate_bsdf_lobes(
                             &light_dir,
                             &view_dir,
                             &normal,
                             &albedo,
                             &mut cached_textures,
                         );
-                        direct_light = (light_sample.radiance
-                            * (cos_theta_light / (distance_sq * light_sample.pdf)))
-                            .component_mul(&brdf)
+
+                        let weight = if light_sample.intersectable {
+                            let light_pdf = light_sample.pdf * distance_sq / cos_theta_light;
+                            let bsdf_pdf = material.pdf_bsdf(&light_dir, &view_dir, &normal, &albedo, &mut cached_textures);
+                            math::power_heuristic(light_pdf, bsdf_pdf)
+                        } else {
+                            1.0
+                        };
+
+                        let light = (light_sample.radiance
+                            * (weight * cos_theta_light / (distance_sq * light_sample.pdf)))
                             .component_mul(&transmission)
                             * cos_theta;
+                        direct_light = LobeRadiance::reflected(light, brdf);
+                    }
+                }
+            } else {
+                // Infinite area light (environment). wi points towards the light and pdf is per solid angle.
+                let light_dir = light_sample.wi;
+                let cos_theta = normal.dot(&light_dir).max(0.0);
+                if cos_theta > 0.0 {
+                    let shadow_ray = Ray::new(surface_point, light_dir).with_time(ray.time());
+                    if scene.intersect(&shadow_ray, ctx).is_none() {
+                        let view_dir = -ray.direction();
+                        let brdf = material.evaluate_bsdf_lobes(
+                            &light_dir,
+                            &view_dir,
+                            &normal,
+                            &albedo,
+                            &mut cached_textures,
+                        );
+                        let bsdf_pdf = material.pdf_bsdf(&light_dir, &view_dir, &normal, &albedo, &mut cached_textures);
+                        let weight = math::power_heuristic(light_sample.pdf, bsdf_pdf);
+
+                        direct_light = LobeRadiance::reflected(light_sample.radiance * (weight / light_sample.pdf) * cos_theta, brdf);
                     }
                 }
             }
         }
 
+        // Emission found by BSDF sampling was also reachable through light sampling at the
+        // previous vertex, so weight it against that strategy.
         let emissive = cached_textures.emissive();
-        let radiance = emissive + direct_light;
+        let emissive_weight = match path.bsdf_pdf {
+            Some(bsdf_pdf) if math::is_greater_than_zero(emissive) => {
+                math::power_heuristic(bsdf_pdf, scene.light_pdf(&path.light_context, ray, hit))
+            }
+            _ => 1.0,
+        };
+        let emission = emissive * emissive_weight;
 
         if remaining_depth <= 1 {
             return ShadeResult {
-                radiance,
+                emission,
+                direct_light,
                 next_ray: None,
                 throughput: Vector3::zeros(),
+                bsdf_pdf: None,
+                light_context,
+                lobe: None,
             };
         }
 
         // Indirect lighting: BSDF sampling for next bounce.
+        sampler.start(Dimensions::Bsdf(bounce_index));
         let sample = material.sample_bsdf(
             ray.direction(),
             normal,
-            albedo,
             &mut cached_textures,
-            rng,
+            sampler,
             eta_stack,
             ctx,
         );
@@ -200,37 +311,52 @@ impl PathTracingIntegrator {
 
         if sample.pdf <= MIN_PDF || cos_theta <= 0.0 {
             return ShadeResult {
-                radiance,
+                emission,
+                direct_light,
                 next_ray: None,
                 throughput: Vector3::zeros(),
+                bsdf_pdf: None,
+                light_context,
+                lobe: None,
             };
         }
 
         let indirect_origin = hit_point + n * (0.001 * offset_sign);
-        let next_ray = Ray::new(indirect_origin, sample.direction);
+        let next_ray = Ray::new(indirect_origin, sample.direction).with_time(ray.time());
 
         // Compute survival probability for Russian roulette.
         // Use max component of (BSDF * cos_theta) as a proxy for path importance.
         let bsdf_weighted = sample.bsdf_value * cos_theta;
         let max_component = bsdf_weighted.x.max(bsdf_weighted.y).max(bsdf_weighted.z);
-        let survival_prob = if bounce_index < RR_WARMUP_BOUNCES {
-            1.0
-        } else {
-            max_component.min(1.0)
-        };
+        let survival_prob = path_termination::survival_probability(&path.options.russian_roulette, bounce_index, max_component);
 
-        if survival_prob <= 0.0 || rng.random::<f32>() > survival_prob {
+        if survival_prob <= 0.0 || sampler.get_1d() > survival_prob {
             return ShadeResult {
-                radiance,
+                emission,
+                direct_light,
                 next_ray: None,
                 throughput: Vector3::zeros(),
+                bsdf_pdf: None,
+                light_context,
+                lobe: None,
             };
         }
 
+        let bsdf_pdf = if sample.is_transmission {
+            None
+        } else {
+            let view_dir = -ray.direction();
+            Some(material.pdf_bsdf(&sample.direction, &view_dir, &normal, &albedo, &mut cached_textures))
+        };
+
         ShadeResult {
-            radiance,
+            emission,
+            direct_light,
             next_ray: Some(next_ray),
             throughput: sample.bsdf_value * (cos_theta / (sample.pdf * survival_prob)),
+            bsdf_pdf,
+            light_context,
+            lobe: Some(sample.lobe),
         }
     }
 
@@ -239,9 +365,7 @@ impl PathTracingIntegrator {
         scene: &Scene,
         remaining_depth: u32,
         bounce_index: u32,
-        rng: &mut impl Rng,
-        eta_stack: &mut StaticStack<f32, ETA_STACK_SIZE>,
-        ctx: &Context,
+        path: &mut PathState,
     ) -> Vector3<f32> {
         if remaining_depth == 0 {
             return Vector3::zeros();
@@ -252,31 +376,61 @@ impl PathTracingIntegrator {
         let mut bounce_index = bounce_index;
         let mut throughput = Vector3::new(1.0, 1.0, 1.0);
         let mut radiance = Vector3::zeros();
+        let mut bounces = BounceCounter::default();
+        // Vertices along the path so far, and the lobe the path left the first one through
+        let mut vertex = 0;
+        let mut first_lobe = None;
 
         while remaining_depth > 0 {
-            let Some(hit) = scene.intersect(&ray, ctx) else {
-                radiance += throughput.component_mul(&scene.environment(&ray));
+            let Some(hit) = scene.intersect(&ray, path.ctx) else {
+                let weight = path.bsdf_pdf
+                    .map(|pdf| math::power_heuristic(pdf, scene.environment_pdf(&path.light_context, &ray.direction())))
+                    .unwrap_or(1.0);
+                let environment = throughput.component_mul(&scene.environment(&ray)) * weight;
+                radiance += environment;
+                if vertex == 0 {
+                    path.aov.albedo = scene.environment(&ray);
+                }
+                Self::add_aov_light(&mut path.aov, vertex, first_lobe, environment, &LobeRadiance::default());
                 break;
             };
 
-            let shade = Self::shade(
-                &hit,
-                &ray,
-                scene,
-                remaining_depth,
-                bounce_index,
-                rng,
-                eta_stack,
-                ctx,
-            );
+            let shade = Self::shade(&hit, &ray, scene, remaining_depth, bounce_index, path);
 
-            radiance += throughput.component_mul(&shade.radiance);
+            let emission = throughput.component_mul(&shade.emission);
+            let direct_light = LobeRadiance {
+                diffuse: throughput.component_mul(&shade.direct_light.diffuse),
+                specular: throughput.component_mul(&shade.direct_light.specular),
+                transmission: throughput.component_mul(&shade.direct_light.transmission),
+            };
+            radiance += emission + direct_light.total();
+            Self::add_aov_light(&mut path.aov, vertex, first_lobe, emission, &direct_light);
+
+            if vertex == 0 {
+                let aov = &mut path.aov;
+                aov.depth = hit.intersection.dist * ray.direction().norm();
+                aov.position = ray.origin() + ray.direction() * hit.intersection.dist;
+                aov.shading_normal = shade.light_context.normal;
+                aov.geometric_normal = hit.intersection.geometric_normal;
+                aov.uv = hit.intersection.tex_coord;
+                aov.albedo = scene.materials()[hit.material_index as usize].sample_color(aov.uv.x, aov.uv.y);
+                aov.object_id = scene.meshes()[hit.mesh_index as usize].scene_index() as u32 + 1;
+                aov.material_id = hit.material_index + 1;
+                first_lobe = shade.lobe;
+            }
 
             let Some(next_ray) = shade.next_ray else {
                 break;
             };
+            if let Some(lobe) = shade.lobe
+                && !bounces.record(lobe, &path.options.bounce_limits)
+            {
+                break;
+            }
 
             throughput = throughput.component_mul(&shade.throughput);
+            path.bsdf_pdf = shade.bsdf_pdf;
+            path.light_context = shade.light_context;
             if !math::is_greater_than_zero(throughput) {
                 break;
             }
@@ -284,42 +438,75 @@ impl PathTracingIntegrator {
             ray = next_ray;
             remaining_depth -= 1;
             bounce_index += 1;
+            vertex += 1;
         }
 
         radiance
     }
+
+    /// Add light found at `vertex` of a path to the light AOVs, by the lobe the path left its
+    /// first vertex through. Emission found from the first vertex is direct light of that lobe.
+    fn add_aov_light(aov: &mut AovSample, vertex: u32, first_lobe: Option<BsdfLobe>, emission: Vector3<f32>, direct_light: &LobeRadiance) {
+        match (vertex, first_lobe) {
+            (0, _) | (_, None) => {
+                aov.emission += emission;
+                aov.direct_diffuse += direct_light.diffuse;
+                aov.direct_specular += direct_light.specular;
+                aov.transmission += direct_light.transmission;
+            }
+            (_, Some(BsdfLobe::Transmission)) => aov.transmission += emission + direct_light.total(),
+            (_, Some(BsdfLobe::Diffuse)) => {
+                match vertex {
+                    1 => aov.direct_diffuse += emission,
+                    _ => aov.indirect_diffuse += emission,
+                }
+                aov.indirect_diffuse += direct_light.total();
+            }
+            (_, Some(BsdfLobe::Glossy)) => {
+                match vertex {
+                    1 => aov.direct_specular += emission,
+                    _ => aov.indirect_specular += emission,
+                }
+                aov.indirect_specular += direct_light.total();
+            }
+        }
+    }
 }
 
 impl Integrator for PathTracingIntegrator {
-    fn integrate(&self, scene: &Scene, camera: &PerspectiveCamera, frame: &mut Frame, samples: u32, options: &RenderOptions, ctx: &Context) {
+    fn integrate(&self, scene: &Scene, camera: &CameraImpl, frame: &mut Frame, pass: SampleIndex, options: &RenderOptions, ctx: &Context) {
+        let SampleIndex { index: sample, count: samples } = pass;
         // TODO: Can this "threading boilerplate" be moved outside the integrator, so every dont have to do the same thing?
         let width = frame.width() as usize;
         let height = frame.height() as usize;
 
         let height_inv = 1.0 / height as f32;
         let width_inv = 1.0 / width as f32;
-        let samples_inv = 1.0 / samples as f32;
-
-        frame
-            .pixels_mut()
-            .par_chunks_mut(width)
-            .enumerate()
-            .for_each(|(y, row)| {
-                let mut rng = rand::rng();
-                let v = y as f32 * height_inv;
-                for x in 0..width {
-                    let u = x as f32 * width_inv;
-
-                    let ray = camera.generate_offset_ray(1.0 - u, 1.0 - v, &mut rng);
+        let region = PixelRect::rendered(options, width, height);
+        let filter = Filter::new(options.filter);
+
+        frame.add_rows(region.rows(), &filter, |y, strip| {
+            let mut sampler = sampler::create(options, samples);
+            for x in region.columns() {
+                if strip.is_converged(x, y) {
+                    continue;
+                }
+                sampler.start_pixel_sample(x as u32, y as u32, sample);
+                let position = Vector2::new(x as f32, y as f32) + sampler.get_2d();
+                let u = position.x * width_inv;
+                let v = position.y * height_inv;
 
-                    // Assume initial eta = 1.000277 (Air) for all rays
-                    let mut eta_stack = StaticStack::<f32, ETA_STACK_SIZE>::new_with_default(IOR_AIR);
+                sampler.start(Dimensions::Time);
+                let time = shutter::sample_time(options.shutter.as_ref(), sampler.get_1d());
+                sampler.start(Dimensions::Lens);
+                let ray = camera.generate_ray_at_time(1.0 - u, 1.0 - v, time, &mut sampler);
 
-                    let result =
-                        Self::trace(&ray, scene, MAX_BOUNCES, 0, &mut rng, &mut eta_stack, ctx);
+                let mut path = PathState::new(&ray, options, &mut sampler, ctx);
+                let result = Self::trace(&ray, scene, options.max_bounces, 0, &mut path);
 
-                    row[x] += result * samples_inv;
-                }
-            });
+                strip.add_sample(position, result);
+                strip.add_aovs(position, &path.aov);
+            }
+        });
     }
 }
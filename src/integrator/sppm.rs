@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::sync::Mutex;
+use crate::camera::shutter;
+use crate::camera::viewpoint::CameraImpl;
+use crate::consts::ETA_STACK_SIZE;
+use crate::context::Context;
+use crate::core::Ray;
+use crate::frame::{Frame, PixelRect};
+use crate::integrator::integrator::Integrator;
+use crate::integrator::path_termination::{self, BounceCounter};
+use crate::math;
+use crate::options::{RenderOptions, SppmSettings};
+use crate::sampler::{self, Dimensions, Sampler, SampleIndex};
+use crate::scene::light_sampler::LightSampleContext;
+use crate::scene::material::{CachedTextureLookups, Material, IOR_AIR};
+use crate::scene::scene::Scene;
+use crate::static_stack::StaticStack;
+use nalgebra::{Point3, Vector2, Vector3};
+use rand::Rng;
+use rayon::prelude::*;
+
+/// Stochastic progressive photon mapping. Every pass traces camera rays through specular
+/// surfaces to the first diffuse one, shoots photons from the lights, and gathers the photons
+/// that land close to each of those visible points. The gather radius shrinks every pass, so
+/// the estimate converges even for caustics from delta lights, which path tracing can't find.
+pub struct SppmIntegrator {
+    settings: SppmSettings,
+    /// Statistics carried between the passes of one frame.
+    state: Mutex<Option<SppmState>>,
+}
+
+struct SppmState {
+    pixels: Vec<SppmPixel>,
+    pass: u32,
+    passes: u32,
+    photons_per_pass: usize,
+}
+
+#[derive(Clone)]
+struct SppmPixel {
+    radius: f32,
+    /// Photons gathered so far, reduced along with the radius.
+    photon_count: f32,
+    /// Flux of the gathered photons, weighted by the throughput of the visible point.
+    tau: Vector3<f32>,
+    /// Emitted and directly lit radiance, summed over all passes.
+    direct: Vector3<f32>,
+}
+
+/// The first diffuse surface seen through a pixel.
+struct VisiblePoint {
+    position: Point3<f32>,
+    normal: Vector3<f32>,
+    wo: Vector3<f32>,
+    material_index: u32,
+    tex_coord: Vector2<f32>,
+    beta: Vector3<f32>,
+    /// Time in the shutter interval of the camera ray.
+    time: f32,
+}
+
+struct Photon {
+    position: Point3<f32>,
+    /// Towards where the photon came from.
+    wi: Vector3<f32>,
+    power: Vector3<f32>,
+}
+
+/// Photons hashed by the cell of a uniform grid they are in.
+struct PhotonGrid {
+    photons: Vec<Photon>,
+    cells: HashMap<(i32, i32, i32), Vec<usize>>,
+    cell_size: f32,
+}
+
+const MIN_PDF: f32 = 1e-5;
+const RAY_OFFSET: f32 = 0.001;
+
+impl PhotonGrid {
+    /// `cell_size` has to be at least the largest radius that will be queried.
+    fn new(photons: Vec<Photon>, cell_size: f32) -> Self {
+        let mut cells: HashMap<(i32, i32, i32), Vec<usize>> = HashMap::new();
+        for (index, photon) in photons.iter().enumerate() {
+            cells.entry(Self::cell(&photon.position, cell_size)).or_default().push(index);
+        }
+
+        Self {
+            photons,
+            cells,
+            cell_size,
+        }
+    }
+
+    fn cell(position: &Point3<f32>, cell_size: f32) -> (i32, i32, i32) {
+        (
+            (position.x / cell_size).floor() as i32,
+            (position.y / cell_size).floor() as i32,
+            (position.z / cell_size).floor() as i32,
+        )
+    }
+
+    fn for_each_within(&self, position: &Point3<f32>, radius: f32, mut f: impl FnMut(&Photon)) {
+        let (cx, cy, cz) = Self::cell(position, self.cell_size);
+        let radius_sq = radius * radius;
+
+        for x in cx - 1..=cx + 1 {
+            for y in cy - 1..=cy + 1 {
+                for z in cz - 1..=cz + 1 {
+                    let Some(indices) = self.cells.get(&(x, y, z)) else {
+                        continue;
+                    };
+                    for &index in indices {
+                        let photon = &self.photons[index];
+                        if (photon.position - position).norm_squared() <= radius_sq {
+                            f(photon);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl SppmIntegrator {
+    pub fn new(settings: SppmSettings) -> Self {
+        Self {
+            settings,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Photons are only stored on, and gathered at, surfaces that aren't purely specular.
+    fn is_diffuse(material: &Material) -> bool {
+        material.transmission_factor() < 1.0
+    }
+
+    fn evaluate(material: &Material, tex_coord: Vector2<f32>, normal: &Vector3<f32>, wo: &Vector3<f32>, wi: &Vector3<f32>) -> Vector3<f32> {
+        let mut cached_textures = CachedTextureLookups::new(material, tex_coord);
+        let albedo = cached_textures.albedo();
+
+        let n_dot_v = normal.dot(wo);
+        let n_dot_l = normal.dot(wi);
+        if n_dot_v > 0.0 && n_dot_l > 0.0 {
+            material.evaluate_bsdf(wi, wo, normal, &albedo, &mut cached_textures)
+        } else if material.transmission_factor() > 0.0 && n_dot_v > 0.0 && n_dot_l < 0.0 {
+            material.evaluate_btdf(wi, wo, normal, &albedo, &mut cached_textures, IOR_AIR, material.ior())
+        } else {
+            Vector3::zeros()
+        }
+    }
+
+    /// Light arriving straight from a light source at the visible point. Shadow rays are blocked by
+    /// transmissive surfaces since light through them arrives as photons.
+    fn direct_lighting(scene: &Scene, point: &VisiblePoint, rng: &mut impl Sampler, ctx: &Context) -> Vector3<f32> {
+        let origin = point.position + point.normal * RAY_OFFSET;
+        let context = LightSampleContext { position: origin, normal: point.normal };
+        let Some(sample) = scene.sample_light(&context, rng) else {
+            return Vector3::zeros();
+        };
+
+        let (wi, radiance) = match sample.position {
+            Some(light_point) => {
+                let to_light = light_point - origin;
+                let distance_sq = to_light.norm_squared();
+                if distance_sq <= 1e-12 || !scene.is_visible(origin, light_point, point.time, ctx) {
+                    return Vector3::zeros();
+                }
+                let wi = to_light / distance_sq.sqrt();
+
+                let cos_theta_light = if sample.is_delta { 1.0 } else { sample.wi.dot(&-wi).max(0.0) };
+                (wi, sample.radiance * (cos_theta_light / (distance_sq * sample.pdf)))
+            }
+            None => {
+                if scene.intersect(&Ray::new(origin, sample.wi).with_time(point.time), ctx).is_some() {
+                    return Vector3::zeros();
+                }
+                (sample.wi, sample.radiance / sample.pdf)
+            }
+        };
+
+        let cos_theta = point.normal.dot(&wi).max(0.0);
+        let material = &scene.materials()[point.material_index as usize];
+        Self::evaluate(material, point.tex_coord, &point.normal, &point.wo, &wi).component_mul(&radiance) * cos_theta
+    }
+
+    /// Follow a camera ray through specular surfaces. Returns the radiance found on the way,
+    /// including direct lighting at the visible point, and the visible point if there was one.
+    fn trace_camera_ray(scene: &Scene, ray: Ray, options: &RenderOptions, rng: &mut impl Sampler, ctx: &Context) -> (Vector3<f32>, Option<VisiblePoint>) {
+        let mut ray = ray;
+        let mut beta = Vector3::repeat(1.0);
+        let mut radiance = Vector3::zeros();
+        // Assume initial eta = 1.000277 (Air) for all rays
+        let mut eta_stack = StaticStack::<f32, ETA_STACK_SIZE>::new_with_default(IOR_AIR);
+        let mut bounces = BounceCounter::default();
+
+        for _ in 0..options.max_bounces {
+            let Some(hit) = scene.intersect(&ray, ctx) else {
+                radiance += beta.component_mul(&scene.environment(&ray));
+                break;
+            };
+
+            let material = &scene.materials()[hit.material_index as usize];
+            let tex_coord = hit.intersection.tex_coord;
+            let mut cached_textures = CachedTextureLookups::new(material, tex_coord);
+            let normal = material.apply_normal_map(hit.intersection.normal, hit.intersection.tangent, tex_coord);
+            let position = ray.origin() + ray.direction() * hit.intersection.dist;
+
+            radiance += beta.component_mul(&cached_textures.emissive());
+
+            if Self::is_diffuse(material) {
+                let point = VisiblePoint {
+                    position,
+                    normal,
+                    wo: -ray.direction().normalize(),
+                    material_index: hit.material_index,
+                    tex_coord,
+                    beta,
+                    time: ray.time(),
+                };
+                radiance += beta.component_mul(&Self::direct_lighting(scene, &point, rng, ctx));
+                return (radiance, Some(point));
+            }
+
+            let sample = material.sample_bsdf(ray.direction(), normal, &mut cached_textures, rng, &mut eta_stack, ctx);
+            let cos_theta = if sample.is_transmission {
+                sample.direction.dot(&normal).abs()
+            } else {
+                sample.direction.dot(&normal).max(0.0)
+            };
+            if sample.pdf <= MIN_PDF || cos_theta <= 0.0 {
+                break;
+            }
+
+            if !bounces.record(sample.lobe, &options.bounce_limits) {
+                break;
+            }
+
+            beta = beta.component_mul(&(sample.bsdf_value * (cos_theta / sample.pdf)));
+            let offset_sign = if sample.direction.dot(&normal) >= 0.0 { 1.0 } else { -1.0 };
+            ray = Ray::new(position + normal * (RAY_OFFSET * offset_sign), sample.direction).with_time(ray.time());
+        }
+
+        (radiance, None)
+    }
+
+    /// Shoot a photon from a light and store it on every diffuse surface it bounces off, except the
+    /// first one, whose lighting is direct and already sampled at the visible points.
+    fn trace_photon(scene: &Scene, options: &RenderOptions, rng: &mut impl Sampler, ctx: &Context) -> Vec<Photon> {
+        let mut photons = Vec::new();
+        let Some(emission) = scene.sample_light_emission(rng) else {
+            return photons;
+        };
+
+        let cos_theta = if emission.normal == Vector3::zeros() {
+            1.0
+        } else {
+            emission.normal.dot(&emission.ray.direction()).abs()
+        };
+        let mut beta = emission.radiance * (cos_theta / (emission.pdf_position * emission.pdf_direction));
+        let mut ray = emission.ray.with_time(shutter::sample_time(options.shutter.as_ref(), rng.random()));
+        // Assume initial eta = 1.000277 (Air) for all rays
+        let mut eta_stack = StaticStack::<f32, ETA_STACK_SIZE>::new_with_default(IOR_AIR);
+        let mut bounces = BounceCounter::default();
+
+        for depth in 0..options.max_bounces {
+            let Some(hit) = scene.intersect(&ray, ctx) else {
+                break;
+            };
+
+            let material = &scene.materials()[hit.material_index as usize];
+            let tex_coord = hit.intersection.tex_coord;
+            let mut cached_textures = CachedTextureLookups::new(material, tex_coord);
+            let normal = material.apply_normal_map(hit.intersection.normal, hit.intersection.tangent, tex_coord);
+            let position = ray.origin() + ray.direction() * hit.intersection.dist;
+
+            if depth > 0 && Self::is_diffuse(material) {
+                photons.push(Photon {
+                    position,
+                    wi: -ray.direction().normalize(),
+                    power: beta,
+                });
+            }
+
+            if depth + 1 == options.max_bounces {
+                break;
+            }
+
+            let sample = material.sample_bsdf(ray.direction(), normal, &mut cached_textures, rng, &mut eta_stack, ctx);
+            let cos_theta = if sample.is_transmission {
+                sample.direction.dot(&normal).abs()
+            } else {
+                sample.direction.dot(&normal).max(0.0)
+            };
+            if sample.pdf <= MIN_PDF || cos_theta <= 0.0 {
+                break;
+            }
+
+            if !bounces.record(sample.lobe, &options.bounce_limits) {
+                break;
+            }
+
+            // Russian roulette on how much of its power the photon keeps
+            let new_beta = beta.component_mul(&(sample.bsdf_value * (cos_theta / sample.pdf)));
+            let weight = math::luminance(&new_beta) / math::luminance(&beta);
+            let survival_prob = path_termination::survival_probability(&options.russian_roulette, depth, weight);
+            if survival_prob <= 0.0 || rng.random::<f32>() > survival_prob {
+                break;
+            }
+            beta = new_beta / survival_prob;
+
+            let offset_sign = if sample.direction.dot(&normal) >= 0.0 { 1.0 } else { -1.0 };
+            ray = Ray::new(position + normal * (RAY_OFFSET * offset_sign), sample.direction).with_time(ray.time());
+        }
+
+        photons
+    }
+
+    fn create_state(&self, scene: &Scene, pixel_count: usize, passes: u32) -> SppmState {
+        let radius = self.settings.initial_radius.unwrap_or(scene.bounding_sphere().1 * 0.01).max(1e-4);
+        let photons_per_pass = self.settings.photons_per_pass.map_or(pixel_count, |photons| photons as usize);
+
+        SppmState {
+            pixels: vec![
+                SppmPixel {
+                    radius,
+                    photon_count: 0.0,
+                    tau: Vector3::zeros(),
+                    direct: Vector3::zeros(),
+                };
+                pixel_count
+            ],
+            pass: 0,
+            passes,
+            photons_per_pass: photons_per_pass.max(1),
+        }
+    }
+}
+
+impl Integrator for SppmIntegrator {
+    fn integrate(&self, scene: &Scene, camera: &CameraImpl, frame: &mut Frame, pass: SampleIndex, options: &RenderOptions, ctx: &Context) {
+        let SampleIndex { index: sample, count: samples } = pass;
+        let width = frame.width() as usize;
+        let height = frame.height() as usize;
+
+        let height_inv = 1.0 / height as f32;
+        let width_inv = 1.0 / width as f32;
+        let region = PixelRect::rendered(options, width, height);
+
+        let mut state_guard = self.state.lock().unwrap();
+        // A new frame starts after the last pass of the previous one
+        let state = match state_guard.take() {
+            Some(state) if state.pass < state.passes && state.pixels.len() == width * height => state,
+            _ => self.create_state(scene, width * height, samples),
+        };
+        let state = state_guard.insert(state);
+        state.pass += 1;
+
+        // Camera pass: find the visible point of every pixel
+        let visible_points: Vec<Option<VisiblePoint>> = state
+            .pixels
+            .par_chunks_mut(width)
+            .enumerate()
+            .flat_map_iter(|(y, row)| {
+                let mut sampler = sampler::create(options, samples);
+                let mut points = Vec::with_capacity(width);
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    if !region.contains(x, y) {
+                        points.push(None);
+                        continue;
+                    }
+
+                    // Visible points are averaged per pixel, so jittered within it without a filter
+                    sampler.start_pixel_sample(x as u32, y as u32, sample);
+                    let position = Vector2::new(x as f32, y as f32) + sampler.get_2d();
+                    let u = position.x * width_inv;
+                    let v = position.y * height_inv;
+                    sampler.start(Dimensions::Time);
+                    let time = shutter::sample_time(options.shutter.as_ref(), sampler.get_1d());
+                    sampler.start(Dimensions::Lens);
+                    let ray = camera.generate_ray_at_time(1.0 - u, 1.0 - v, time, &mut sampler);
+                    sampler.start(Dimensions::Light(0));
+                    let (radiance, point) = Self::trace_camera_ray(scene, ray, options, &mut sampler, ctx);
+                    pixel.direct += radiance;
+                    points.push(point);
+                }
+                points
+            })
+            .collect();
+
+        // Photon pass
+        let photons: Vec<Photon> = (0..state.photons_per_pass)
+            .into_par_iter()
+            .map_init(rand::rng, |rng, _| Self::trace_photon(scene, options, rng, ctx))
+            .flatten_iter()
+            .collect();
+
+        let max_radius = state.pixels.iter().map(|pixel| pixel.radius).fold(0.0, f32::max);
+        let grid = PhotonGrid::new(photons, max_radius);
+
+        // Gather photons at the visible points, shrink the radii and update the frame
+        let alpha = self.settings.alpha.clamp(0.0, 1.0);
+        let photons_per_pass = state.photons_per_pass as f32;
+        let pass_inv = 1.0 / state.pass as f32;
+        let estimates: Vec<Vector3<f32>> = state
+            .pixels
+            .par_iter_mut()
+            .zip(visible_points.par_iter())
+            .map(|(pixel, point)| {
+                if let Some(point) = point {
+                    let material = &scene.materials()[point.material_index as usize];
+                    let mut phi = Vector3::zeros();
+                    let mut gathered = 0.0;
+                    grid.for_each_within(&point.position, pixel.radius, |photon| {
+                        let f = Self::evaluate(material, point.tex_coord, &point.normal, &point.wo, &photon.wi);
+                        phi += f.component_mul(&photon.power);
+                        gathered += 1.0;
+                    });
+
+                    if gathered > 0.0 {
+                        let photon_count = pixel.photon_count + alpha * gathered;
+                        let radius = pixel.radius * (photon_count / (pixel.photon_count + gathered)).sqrt();
+                        pixel.tau = (pixel.tau + point.beta.component_mul(&phi)) * ((radius * radius) / (pixel.radius * pixel.radius));
+                        pixel.photon_count = photon_count;
+                        pixel.radius = radius;
+                    }
+                }
+
+                let total = pixel.direct + pixel.tau / (photons_per_pass * PI * pixel.radius * pixel.radius);
+                total * pass_inv
+            })
+            .collect();
+
+        for (index, estimate) in estimates.into_iter().enumerate() {
+            frame.set_pixel(index % width, index / width, estimate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn photon_grid_finds_photons_within_radius() {
+        let photon = |x: f32| Photon {
+            position: Point3::new(x, 0.0, 0.0),
+            wi: Vector3::y(),
+            power: Vector3::repeat(1.0),
+        };
+        let grid = PhotonGrid::new(vec![photon(0.1), photon(0.45), photon(-0.3), photon(2.0)], 0.5);
+
+        let mut found = Vec::new();
+        grid.for_each_within(&Point3::new(0.1, 0.0, 0.0), 0.4, |photon| found.push(photon.position.x));
+        found.sort_by(f32::total_cmp);
+        assert_eq!(found, vec![-0.3, 0.1, 0.45]);
+    }
+}
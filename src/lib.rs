@@ -1,15 +1,21 @@
 pub mod acceleration;
 pub mod animation;
+pub mod aov;
 pub mod camera;
+pub mod color;
 pub mod content;
 pub mod core;
 pub mod frame;
+pub mod filter;
+pub mod image_io;
 pub mod integrator;
 pub mod options;
 pub mod render_controller;
+pub mod sampler;
 pub mod scene;
 pub mod static_stack;
 pub mod context;
 pub mod math;
 pub mod consts;
-pub mod denoise;
\ No newline at end of file
+pub mod denoise;
+pub mod display;
\ No newline at end of file
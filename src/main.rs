@@ -11,6 +11,7 @@ use raytracer::content::gltf::loader::GltfLoader;
 use raytracer::content::scene_loader::SceneLoader;
 use raytracer::context::Context;
 use raytracer::denoise::create_denoiser;
+use raytracer::frame::PixelRect;
 use raytracer::integrator::integrator::create;
 use raytracer::options::RenderOptions;
 use raytracer::render_controller::RenderController;
@@ -25,6 +26,8 @@ struct App {
     latest_rgba: Vec<u8>,
     current_sample: u32,
     is_done: bool,
+    /// Render region outlined in the preview.
+    region: Option<PixelRect>,
 }
 
 impl App {
@@ -59,10 +62,38 @@ impl App {
         }
     }
 
+    /// Draw a line just outside the edges of `region`.
+    fn outline_region(rgba: &mut [u8], width: usize, region: &PixelRect) {
+        let height = rgba.len() / (width * 4);
+        let left = region.x as isize - 1;
+        let top = region.y as isize - 1;
+        let right = (region.x + region.width) as isize;
+        let bottom = (region.y + region.height) as isize;
+
+        let mut set = |x: isize, y: isize| {
+            if (0..width as isize).contains(&x) && (0..height as isize).contains(&y) {
+                let index = (x as usize + y as usize * width) * 4;
+                rgba[index..index + 4].copy_from_slice(&[255, 200, 0, 255]);
+            }
+        };
+
+        for x in left..=right {
+            set(x, top);
+            set(x, bottom);
+        }
+        for y in top..=bottom {
+            set(left, y);
+            set(right, y);
+        }
+    }
+
     fn draw_latest_frame(&mut self, event_loop: &ActiveEventLoop) {
         if let Some(pixels) = self.pixels.as_mut() {
             if pixels.frame_mut().len() == self.latest_rgba.len() {
                 pixels.frame_mut().copy_from_slice(&self.latest_rgba);
+                if let Some(region) = &self.region {
+                    Self::outline_region(pixels.frame_mut(), self.width as usize, region);
+                }
             }
 
             if let Err(err) = pixels.render() {
@@ -139,6 +170,7 @@ impl ApplicationHandler for App {
 
 fn read_options() -> anyhow::Result<RenderOptions> {
     let launch_file: RenderOptions = ron::de::from_reader(std::fs::File::open("launch.ron")?)?;
+    launch_file.validate()?;
 
     Ok(launch_file)
 }
@@ -156,11 +188,12 @@ fn main() {
         return;
     }
 
-    let denoise_filter = create_denoiser(&options.denoise);
+    let denoise_filter = create_denoiser(&options);
     let integrator = create(&options);
     let width = options.resolution.width;
     let height = options.resolution.height;
     let total_samples = options.samples;
+    let region = options.region.map(|_| PixelRect::rendered(&options, width as usize, height as usize));
 
     let event_loop = EventLoop::new().unwrap();
 
@@ -178,6 +211,7 @@ fn main() {
         latest_rgba: vec![0; (width * height * 4) as usize],
         current_sample: 0,
         is_done: false,
+        region,
     };
 
     event_loop.run_app(&mut app).expect("Failed to run app");
@@ -1,3 +1,6 @@
+pub mod distribution;
+pub mod motion;
+
 use nalgebra::Vector3;
 
 #[inline(always)]
@@ -8,4 +11,33 @@ pub fn lerp<T: std::ops::Add<Output = T> + std::ops::Sub<Output = T> + std::ops:
 #[inline(always)]
 pub fn is_greater_than_zero(v: Vector3<f32>) -> bool {
     v.x > 0.0 || v.y > 0.0 || v.z > 0.0
-}
\ No newline at end of file
+}
+
+/// Relative luminance of a linear Rec. 709 color.
+#[inline(always)]
+pub fn luminance(color: &Vector3<f32>) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+/// Balance heuristic weight for a sample drawn from strategy `f` when strategy `g` could also have produced it.
+#[inline(always)]
+pub fn balance_heuristic(f_pdf: f32, g_pdf: f32) -> f32 {
+    if f_pdf <= 0.0 {
+        return 0.0;
+    }
+    f_pdf / (f_pdf + g_pdf)
+}
+
+/// Power heuristic (beta = 2) weight for a sample drawn from strategy `f` when strategy `g` could also have produced it.
+#[inline(always)]
+pub fn power_heuristic(f_pdf: f32, g_pdf: f32) -> f32 {
+    if f_pdf <= 0.0 {
+        return 0.0;
+    }
+    let f2 = f_pdf * f_pdf;
+    let g2 = g_pdf * g_pdf;
+    if f2.is_infinite() {
+        return 1.0;
+    }
+    f2 / (f2 + g2)
+}
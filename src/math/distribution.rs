@@ -0,0 +1,163 @@
+/// Piecewise-constant 1D distribution over [0, 1), sampled by inverting its CDF.
+#[derive(Clone)]
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    func_int: f32,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f32>) -> Self {
+        assert!(!func.is_empty(), "distribution must have at least one entry");
+
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1].max(0.0) / n as f32;
+        }
+
+        let func_int = cdf[n];
+        if func_int <= 0.0 {
+            // Degenerate function: fall back to a uniform distribution.
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f32 / n as f32;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= func_int;
+            }
+        }
+
+        Self { func, cdf, func_int }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.func_int
+    }
+
+    fn find_interval(&self, u: f32) -> usize {
+        // Largest index i such that cdf[i] <= u, clamped to a valid segment.
+        self.cdf
+            .partition_point(|c| *c <= u)
+            .saturating_sub(1)
+            .min(self.count() - 1)
+    }
+
+    /// Returns (x, pdf, offset) where `x` lies in [0, 1) and `offset` is the segment it fell into.
+    pub fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+        let offset = self.find_interval(u);
+
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let pdf = self.pdf_at(offset);
+        let x = ((offset as f32 + du.clamp(0.0, 1.0)) / self.count() as f32).min(1.0 - f32::EPSILON);
+
+        (x, pdf, offset)
+    }
+
+    /// Returns (index, pmf) of a discrete sample.
+    pub fn sample_discrete(&self, u: f32) -> (usize, f32) {
+        let offset = self.find_interval(u);
+        (offset, self.pmf(offset))
+    }
+
+    /// Probability of `sample_discrete` returning `index`.
+    pub fn pmf(&self, index: usize) -> f32 {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+
+    /// Density of `sample_continuous` over [0, 1) in the segment `index`.
+    pub fn pdf_at(&self, index: usize) -> f32 {
+        self.pmf(index) * self.count() as f32
+    }
+}
+
+/// Piecewise-constant 2D distribution over [0, 1)², built from a row-major grid of values.
+#[derive(Clone)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f32], width: usize, height: usize) -> Self {
+        assert_eq!(func.len(), width * height);
+
+        let conditional: Vec<Distribution1D> = func
+            .chunks_exact(width)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+
+        let marginal = Distribution1D::new(conditional.iter().map(|c| c.integral()).collect());
+
+        Self {
+            conditional,
+            marginal,
+        }
+    }
+
+    /// Returns ((x, y), pdf) with both coordinates in [0, 1).
+    pub fn sample_continuous(&self, u: (f32, f32)) -> ((f32, f32), f32) {
+        let (y, pdf_y, row) = self.marginal.sample_continuous(u.1);
+        let (x, pdf_x, _) = self.conditional[row].sample_continuous(u.0);
+
+        ((x, y), pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, x: f32, y: f32) -> f32 {
+        let width = self.conditional[0].count();
+        let height = self.marginal.count();
+        let column = ((x * width as f32) as usize).min(width - 1);
+        let row = ((y * height as f32) as usize).min(height - 1);
+
+        self.marginal.pdf_at(row) * self.conditional[row].pdf_at(column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_discrete_is_proportional_to_function() {
+        let distribution = Distribution1D::new(vec![1.0, 3.0, 0.0, 4.0]);
+
+        assert!((distribution.pmf(0) - 0.125).abs() < 1e-6);
+        assert!((distribution.pmf(1) - 0.375).abs() < 1e-6);
+        assert_eq!(distribution.pmf(2), 0.0);
+        assert!((distribution.pmf(3) - 0.5).abs() < 1e-6);
+
+        assert_eq!(distribution.sample_discrete(0.05).0, 0);
+        assert_eq!(distribution.sample_discrete(0.2).0, 1);
+        assert_eq!(distribution.sample_discrete(0.6).0, 3);
+        assert_eq!(distribution.sample_discrete(0.9999).0, 3);
+    }
+
+    #[test]
+    fn zero_function_falls_back_to_uniform() {
+        let distribution = Distribution1D::new(vec![0.0, 0.0]);
+
+        assert_eq!(distribution.integral(), 0.0);
+        assert!((distribution.pmf(0) - 0.5).abs() < 1e-6);
+        assert!((distribution.pmf(1) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn distribution_2d_pdf_matches_sampled_pdf() {
+        let func = [1.0, 2.0, 3.0, 4.0, 0.5, 0.5];
+        let distribution = Distribution2D::new(&func, 3, 2);
+
+        for u in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.8)] {
+            let ((x, y), pdf) = distribution.sample_continuous(u);
+            assert!((distribution.pdf(x, y) - pdf).abs() < 1e-4);
+        }
+    }
+}
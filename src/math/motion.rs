@@ -0,0 +1,120 @@
+use nalgebra::{Matrix3, Matrix4, Rotation3, UnitQuaternion, Vector3};
+use crate::acceleration::bounds::AABB;
+
+/// Transform decomposed into translation, rotation and scale, so it can be interpolated without
+/// shearing. Shear in the original matrix is lost.
+#[derive(Copy, Clone, Debug)]
+struct Decomposed {
+    translation: Vector3<f32>,
+    rotation: UnitQuaternion<f32>,
+    scale: Vector3<f32>,
+}
+
+impl Decomposed {
+    fn new(transform: &Matrix4<f32>) -> Self {
+        let linear: Matrix3<f32> = transform.fixed_view::<3, 3>(0, 0).into_owned();
+        let mut scale = Vector3::new(linear.column(0).norm(), linear.column(1).norm(), linear.column(2).norm());
+        if linear.determinant() < 0.0 {
+            scale.x = -scale.x;
+        }
+
+        let mut rotation = linear;
+        for axis in 0..3 {
+            if scale[axis] != 0.0 {
+                rotation.set_column(axis, &(linear.column(axis) / scale[axis]));
+            }
+        }
+
+        Self {
+            translation: transform.fixed_view::<3, 1>(0, 3).into_owned(),
+            rotation: UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix(&rotation)),
+            scale,
+        }
+    }
+
+    fn to_matrix(self) -> Matrix4<f32> {
+        Matrix4::new_translation(&self.translation)
+            * self.rotation.to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&self.scale)
+    }
+}
+
+/// Transform moving between the shutter opening (time 0) and closing (time 1).
+#[derive(Copy, Clone, Debug)]
+pub struct TransformMotion {
+    start: Decomposed,
+    end: Decomposed,
+}
+
+/// Number of intervals the swept bounds are evaluated at.
+const BOUNDS_STEPS: usize = 16;
+
+impl TransformMotion {
+    /// None if the transforms are the same, and nothing moves.
+    pub fn new(start: Matrix4<f32>, end: Matrix4<f32>) -> Option<Self> {
+        (start != end).then(|| Self {
+            start: Decomposed::new(&start),
+            end: Decomposed::new(&end),
+        })
+    }
+
+    /// Translation and scale are interpolated linearly, rotation spherically.
+    pub fn interpolate(&self, time: f32) -> Matrix4<f32> {
+        Decomposed {
+            translation: self.start.translation.lerp(&self.end.translation, time),
+            rotation: self.start.rotation.slerp(&self.end.rotation, time),
+            scale: self.start.scale.lerp(&self.end.scale, time),
+        }.to_matrix()
+    }
+
+    /// Bounds of `bounds` swept over the whole motion.
+    pub fn swept_bounds(&self, bounds: &AABB) -> AABB {
+        let mut swept = AABB::compound((0..=BOUNDS_STEPS).map(|step| {
+            bounds.transform(&self.interpolate(step as f32 / BOUNDS_STEPS as f32))
+        }));
+
+        // Between two steps, rotating points move along an arc that can bulge out of the boxes
+        // by at most radius * (1 - cos(half the angle of a step)).
+        let angle = self.start.rotation.angle_to(&self.end.rotation) / BOUNDS_STEPS as f32;
+        let scale = self.start.scale.abs().sup(&self.end.scale.abs());
+        let radius = bounds.corners().iter()
+            .map(|corner| corner.coords.component_mul(&scale).norm())
+            .fold(0.0, f32::max);
+        swept.inflate(radius * (1.0 - (angle / 2.0).cos()));
+        swept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Point3;
+
+    #[test]
+    fn interpolates_between_transforms() {
+        let start = Matrix4::new_translation(&Vector3::new(0.0, 0.0, 0.0));
+        let end = Matrix4::new_translation(&Vector3::new(2.0, 0.0, 0.0))
+            * Matrix4::new_rotation(Vector3::new(0.0, std::f32::consts::FRAC_PI_2, 0.0))
+            * Matrix4::new_scaling(3.0);
+        let motion = TransformMotion::new(start, end).unwrap();
+
+        assert!((motion.interpolate(0.0) - start).norm() < 1e-5);
+        assert!((motion.interpolate(1.0) - end).norm() < 1e-5);
+
+        let middle = motion.interpolate(0.5).transform_point(&Point3::new(1.0, 0.0, 0.0));
+        let angle = std::f32::consts::FRAC_PI_4;
+        assert!((middle - Point3::new(1.0 + 2.0 * angle.cos(), 0.0, -2.0 * angle.sin())).norm() < 1e-4);
+    }
+
+    #[test]
+    fn swept_bounds_contain_motion() {
+        let start = Matrix4::identity();
+        let end = Matrix4::new_translation(&Vector3::new(5.0, 0.0, 0.0));
+        let motion = TransformMotion::new(start, end).unwrap();
+
+        let bounds = motion.swept_bounds(&AABB::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)));
+        assert!((bounds.min() - Point3::new(-1.0, -1.0, -1.0)).norm() < 1e-5);
+        assert!((bounds.max() - Point3::new(6.0, 1.0, 1.0)).norm() < 1e-5);
+        assert!(TransformMotion::new(start, start).is_none());
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
 use serde::Deserialize;
 /*
@@ -40,12 +41,72 @@ pub enum FocalDistance {
     Fixed(f32),
     Auto(f32, f32),
     Object(String),
+    /// Pulls focus from one keyframe to the next, easing in and out of each. Before the first and
+    /// after the last keyframe, focus stays on that keyframe.
+    Pull(Vec<FocusKeyframe>),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FocusKeyframe {
+    /// Seconds into the animation.
+    pub time: f32,
+    pub focus: FocalDistance,
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub enum ApertureShape {
+    #[default]
+    Circle,
+    /// Polygon formed by `blades` straight blades, rotated by `rotation` degrees.
+    Polygon { blades: u32, rotation: f32 },
+    /// Image of the aperture. Light passes through in proportion to its brightness.
+    Mask(String),
+}
+
+impl Display for ApertureShape {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApertureShape::Circle => write!(f, "Circle"),
+            ApertureShape::Polygon { blades, rotation } => write!(f, "Polygon(blades: {}, rotation: {})", blades, rotation),
+            ApertureShape::Mask(file) => write!(f, "Mask({})", file),
+        }
+    }
+}
+
+/// Physical description of the lens, in millimeters. Scene units are taken to be meters, as in
+/// glTF.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LensSettings {
+    /// Overrides the field of view of the camera. Derived from the field of view when left out.
+    pub focal_length: Option<f32>,
+    /// Focal length divided by the aperture diameter.
+    pub f_stop: f32,
+    /// Height of the sensor, which the vertical field of view covers.
+    pub sensor_height: f32,
+}
+
+impl Default for LensSettings {
+    fn default() -> Self {
+        Self {
+            focal_length: None,
+            f_stop: 2.8,
+            sensor_height: 24.0,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct DofSettings {
+    /// Cameras with focus set in their glTF extras use that instead.
     pub focal_distance: FocalDistance,
+    /// Aperture radius, in scene units. Ignored when `lens` is given.
+    #[serde(default)]
     pub aperture_size: f32,
+    #[serde(default)]
+    pub aperture_shape: ApertureShape,
+    #[serde(default)]
+    pub lens: Option<LensSettings>,
 }
 
 impl Default for DofSettings {
@@ -53,7 +114,577 @@ impl Default for DofSettings {
         Self {
             focal_distance: FocalDistance::Fixed(1.0),
             aperture_size: 0.0,
+            aperture_shape: ApertureShape::Circle,
+            lens: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EnvironmentMapSettings {
+    /// Equirectangular .hdr or .exr image.
+    pub file: String,
+    /// Rotation around the up axis, in degrees.
+    pub rotation: f32,
+    pub intensity: f32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum SunPosition {
+    /// Degrees above the horizon, and around the up axis from -Z towards +X.
+    Angles { elevation: f32, azimuth: f32 },
+    /// Follow the first directional light of the scene, which the sun replaces.
+    DirectionalLight,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SkySettings {
+    pub sun: SunPosition,
+    /// Haziness of the atmosphere, from 1.7 (very clear) to 10 (hazy).
+    pub turbidity: f32,
+    /// Angular diameter of the sun disk, in degrees.
+    pub sun_angular_diameter: f32,
+    /// Albedo of the ground reflecting the sky below the horizon.
+    pub ground_albedo: f32,
+    pub intensity: f32,
+}
+
+impl Default for SkySettings {
+    fn default() -> Self {
+        Self {
+            sun: SunPosition::Angles { elevation: 45.0, azimuth: 0.0 },
+            turbidity: 3.0,
+            sun_angular_diameter: 0.53,
+            ground_albedo: 0.3,
+            intensity: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub enum Environment {
+    #[default]
+    None,
+    Map(EnvironmentMapSettings),
+    Sky(SkySettings),
+}
+
+impl Display for Environment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Environment::None => write!(f, "None"),
+            Environment::Map(settings) => write!(f, "Map({})", settings.file),
+            Environment::Sky(settings) => write!(f, "Sky(turbidity: {})", settings.turbidity),
+        }
+    }
+}
+
+/// How the light to shoot a shadow ray towards is picked at each shading point.
+#[derive(Copy, Clone, Debug, Deserialize, Default)]
+pub enum LightSampling {
+    #[default]
+    Uniform,
+    /// Proportional to emitted power.
+    Power,
+    /// Proportional to estimated contribution, using a light BVH.
+    Bvh,
+}
+
+impl Display for LightSampling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LightSampling::Uniform => write!(f, "Uniform"),
+            LightSampling::Power => write!(f, "Power"),
+            LightSampling::Bvh => write!(f, "Bvh"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub enum EquirectangularLayout {
+    Mono,
+    /// Omni-directional stereo with the left eye in the top half of the image and the right eye
+    /// in the bottom half. `eye_separation` is in scene units.
+    OverUnder { eye_separation: f32 },
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub enum FisheyeMapping {
+    /// Distance from the image center is proportional to the angle from the view direction.
+    Equidistant,
+    /// Preserves solid angle, so every pixel covers the same part of the sphere.
+    Equisolid,
+}
+
+/// Projection that replaces the one a camera was exported with.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub enum CameraProjection {
+    /// Full 360 by 180 degree panorama, centered on the view direction.
+    Equirectangular(EquirectangularLayout),
+    /// `fov` is in degrees across the width of the image, and may go up to 360.
+    Fisheye { mapping: FisheyeMapping, fov: f32 },
+}
+
+impl Display for CameraProjection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CameraProjection::Equirectangular(EquirectangularLayout::Mono) => write!(f, "Equirectangular"),
+            CameraProjection::Equirectangular(EquirectangularLayout::OverUnder { eye_separation }) => write!(f, "Equirectangular(over/under, eye_separation: {})", eye_separation),
+            CameraProjection::Fisheye { mapping, fov } => write!(f, "Fisheye({:?}, fov: {})", mapping, fov),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub enum ShutterCurve {
+    /// Fully open for the whole interval.
+    #[default]
+    Box,
+    /// Opens and closes linearly, fully open halfway through.
+    Triangle,
+    /// How far the shutter is open at evenly spaced times from opening to closing, in between
+    /// which it is interpolated linearly.
+    Custom(Vec<f32>),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ShutterSettings {
+    /// When the shutter opens, in frames relative to the frame being rendered.
+    pub open: f32,
+    /// When the shutter closes, in frames relative to the frame being rendered.
+    pub close: f32,
+    pub curve: ShutterCurve,
+}
+
+impl Default for ShutterSettings {
+    fn default() -> Self {
+        Self {
+            open: 0.0,
+            close: 0.5,
+            curve: ShutterCurve::Box,
+        }
+    }
+}
+
+impl Display for ShutterSettings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let curve = match &self.curve {
+            ShutterCurve::Box => "Box",
+            ShutterCurve::Triangle => "Triangle",
+            ShutterCurve::Custom(_) => "Custom",
+        };
+        write!(f, "open: {}, close: {}, curve: {}", self.open, self.close, curve)
+    }
+}
+
+/// Picks one of the cameras in the scene.
+#[derive(Clone, Debug, Deserialize)]
+pub enum CameraSelector {
+    /// Position in the order cameras are found in the glTF node hierarchy.
+    Index(usize),
+    /// Name of the node holding the camera.
+    Node(String),
+    /// Name of the glTF camera.
+    Camera(String),
+}
+
+impl CameraSelector {
+    /// Name to tell the outputs of this camera apart, safe to use in file names. Different
+    /// selectors have different labels.
+    pub fn label(&self) -> String {
+        match self {
+            CameraSelector::Index(index) => format!("idx{}", index),
+            CameraSelector::Node(name) => format!("node-{}", escape_label(name)),
+            CameraSelector::Camera(name) => format!("cam-{}", escape_label(name)),
+        }
+    }
+}
+
+/// `name` with the bytes of every character other than ASCII letters, digits and `-` written as
+/// `_` and two hex digits, so different names stay different.
+fn escape_label(name: &str) -> String {
+    let mut label = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            label.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                label.push_str(&format!("_{:02x}", byte));
+            }
+        }
+    }
+    label
+}
+
+impl Display for CameraSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CameraSelector::Index(index) => write!(f, "Index({})", index),
+            CameraSelector::Node(name) => write!(f, "Node({})", name),
+            CameraSelector::Camera(name) => write!(f, "Camera({})", name),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub enum StereoConvergence {
+    /// The eyes look straight ahead, and only meet at infinity.
+    Parallel,
+    /// The eyes look straight ahead, with their images shifted to meet at this distance.
+    OffAxis(f32),
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Default)]
+pub enum StereoLayout {
+    /// Left eye on the left half of the image.
+    #[default]
+    SideBySide,
+    /// Left eye on the top half of the image.
+    OverUnder,
+    /// Left eye in red, right eye in green and blue.
+    Anaglyph,
+    /// Each eye to its own files.
+    Separate,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct StereoSettings {
+    /// Distance between the eyes, in scene units.
+    pub interocular_distance: f32,
+    pub convergence: StereoConvergence,
+    pub layout: StereoLayout,
+}
+
+impl Default for StereoSettings {
+    fn default() -> Self {
+        Self {
+            interocular_distance: 0.064,
+            convergence: StereoConvergence::OffAxis(2.0),
+            layout: StereoLayout::SideBySide,
+        }
+    }
+}
+
+impl Display for StereoSettings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}, interocular_distance: {}, convergence: {:?}", self.layout, self.interocular_distance, self.convergence)
+    }
+}
+
+/// File format of the written images.
+#[derive(Copy, Clone, Debug, Deserialize, Default, PartialEq)]
+pub enum ImageFormat {
+    /// 8 bit, through the display transform.
+    #[default]
+    Png,
+    /// Linear floats in OpenEXR.
+    Exr,
+    /// Linear floats in a portable float map.
+    Pfm,
+    /// Linear Radiance RGBE.
+    Hdr,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Exr => "exr",
+            ImageFormat::Pfm => "pfm",
+            ImageFormat::Hdr => "hdr",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct OutputSettings {
+    pub format: ImageFormat,
+    /// Write all images of a frame, like the denoised and auxiliary ones, as layers of one EXR
+    /// file, whatever the format.
+    pub multilayer: bool,
+}
+
+impl Display for OutputSettings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.format)?;
+        if self.multilayer {
+            write!(f, ", multilayer EXR")?;
+        }
+        Ok(())
+    }
+}
+
+/// Linear RGB color space, by its primaries and white point.
+#[derive(Copy, Clone, Debug, Deserialize, Default, PartialEq)]
+pub enum RgbSpace {
+    /// The primaries of sRGB and glTF, with a D65 white point.
+    #[default]
+    LinearRec709,
+    /// The wide gamut ACES AP1 primaries, with the ACES white point.
+    AcesCg,
+}
+
+/// Color spaces light is rendered and written in.
+#[derive(Copy, Clone, Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct ColorSettings {
+    /// Space colors of the scene are converted to and light is traced in.
+    pub working_space: RgbSpace,
+    /// Space of the linear images, like EXR files. 8 bit images and the preview are always sRGB.
+    pub output_space: RgbSpace,
+    /// Temperature in kelvin of the light that should look white. Leaves colors as they are
+    /// without it.
+    pub white_balance: Option<f32>,
+}
+
+impl Display for ColorSettings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "working space {:?}, output space {:?}", self.working_space, self.output_space)?;
+        if let Some(temperature) = self.white_balance {
+            write!(f, ", white balance {}K", temperature)?;
+        }
+        Ok(())
+    }
+}
+
+/// Curve compressing scene light into the range of a display.
+#[derive(Copy, Clone, Debug, Deserialize, Default, PartialEq)]
+pub enum ToneMapper {
+    /// Clips everything brighter than display white.
+    #[default]
+    None,
+    Reinhard,
+    /// Hable's filmic curve from Uncharted 2.
+    Hable,
+    /// Narkowicz's fit of the ACES reference rendering and output transforms.
+    Aces,
+    /// Sobotka's AgX, which desaturates bright colors instead of skewing their hue.
+    AgX,
+}
+
+/// Encoding of display light in the 8 bit values of images and the preview.
+#[derive(Copy, Clone, Debug, Deserialize, Default, PartialEq)]
+pub enum TransferFunction {
+    #[default]
+    Srgb,
+    Rec709,
+}
+
+/// How the linear light of a frame is shown in the preview and written to 8 bit images.
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DisplaySettings {
+    /// In stops, each doubling the light.
+    pub exposure: f32,
+    pub tone_mapper: ToneMapper,
+    pub transfer: TransferFunction,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            tone_mapper: ToneMapper::None,
+            transfer: TransferFunction::Srgb,
+        }
+    }
+}
+
+impl Display for DisplaySettings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "exposure {:+} EV, {:?} tone mapping, {:?}", self.exposure, self.tone_mapper, self.transfer)
+    }
+}
+
+/// Buffer written alongside the beauty image, filled by the path tracer from the same samples.
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+pub enum Aov {
+    /// Distance along the camera ray to the first hit, infinite where nothing is hit.
+    Depth,
+    /// World position of the first hit.
+    Position,
+    /// Normal at the first hit, after normal mapping.
+    ShadingNormal,
+    /// Normal at the first hit, before normal mapping.
+    GeometricNormal,
+    /// Texture coordinates of the first hit, in red and green.
+    Uv,
+    /// Base color of the first hit, or the environment where nothing is hit.
+    Albedo,
+    /// Index of the first hit mesh plus one, 0 where nothing is hit. Not filtered.
+    ObjectId,
+    /// Index of the material of the first hit plus one, 0 where nothing is hit. Not filtered.
+    MaterialId,
+    /// Light reflected once off a diffuse lobe at the first hit.
+    DirectDiffuse,
+    /// Light reflected more than once, after a diffuse lobe at the first hit.
+    IndirectDiffuse,
+    /// Light reflected once off a glossy lobe at the first hit.
+    DirectSpecular,
+    /// Light reflected more than once, after a glossy lobe at the first hit.
+    IndirectSpecular,
+    /// Emission of what the camera sees, including the environment.
+    Emission,
+    /// Light transmitted through the first hit.
+    Transmission,
+    /// Samples taken in each pixel, as a heatmap from blue for none to red for the most.
+    SampleCount,
+}
+
+impl Aov {
+    /// File name safe name of the buffer.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::ShadingNormal => "shading_normal",
+            Aov::GeometricNormal => "geometric_normal",
+            Aov::Uv => "uv",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::DirectDiffuse => "direct_diffuse",
+            Aov::IndirectDiffuse => "indirect_diffuse",
+            Aov::DirectSpecular => "direct_specular",
+            Aov::IndirectSpecular => "indirect_specular",
+            Aov::Emission => "emission",
+            Aov::Transmission => "transmission",
+            Aov::SampleCount => "sample_count",
+        }
+    }
+}
+
+/// Stops sampling tiles of pixels once their noise is low enough, rather than taking `samples` in
+/// every pixel.
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AdaptiveSampling {
+    /// Standard error of the mean luminance of a pixel, relative to the mean, that it is converged
+    /// below.
+    pub threshold: f32,
+    /// Samples every pixel takes before its noise is trusted.
+    pub min_samples: u32,
+    /// Width and height of the square tiles that stop together, in pixels.
+    pub tile_size: u32,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        Self {
+            threshold: 0.01,
+            min_samples: 16,
+            tile_size: 16,
+        }
+    }
+}
+
+impl Display for AdaptiveSampling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "threshold: {}, min_samples: {}, tile_size: {}", self.threshold, self.min_samples, self.tile_size)
+    }
+}
+
+/// Where in a pixel, and everywhere else along its paths, samples are taken.
+#[derive(Copy, Clone, Debug, Deserialize, Default)]
+pub enum Sampler {
+    /// Uncorrelated random samples.
+    #[default]
+    Independent,
+    /// Jittered samples in a grid of strata, about one per sample of the pixel.
+    Stratified,
+    /// Owen scrambled Sobol points, which converge fastest.
+    Sobol,
+    /// Sobol points shifted per pixel so the remaining noise is spread evenly as blue noise.
+    BlueNoise,
+}
+
+/// Weights samples for the pixels around them. Sizes are in pixels.
+#[derive(Copy, Clone, Debug, Deserialize, Default)]
+pub enum PixelFilter {
+    /// Samples only count for the pixel they land in.
+    #[default]
+    Box,
+    Tent { radius: f32 },
+    Gaussian { radius: f32, sigma: f32 },
+    /// Mitchell-Netravali, sharper than the others but with negative lobes that can ring.
+    /// `b` and `c` of 1/3 are the usual choice.
+    Mitchell { radius: f32, b: f32, c: f32 },
+    BlackmanHarris { radius: f32 },
+}
+
+/// Part of the image, from its top left corner.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub enum RenderRegion {
+    Pixels { x: u32, y: u32, width: u32, height: u32 },
+    /// In fractions of the image size.
+    Normalized { x: f32, y: f32, width: f32, height: f32 },
+}
+
+impl Display for RenderRegion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenderRegion::Pixels { x, y, width, height } => write!(f, "Pixels({}, {}, {}x{})", x, y, width, height),
+            RenderRegion::Normalized { x, y, width, height } => write!(f, "Normalized({}, {}, {}x{})", x, y, width, height),
+        }
+    }
+}
+
+impl RenderRegion {
+    /// Fails unless the region covers at least one pixel and lies inside a `width` by `height`
+    /// image.
+    pub fn validate(&self, width: u32, height: u32) -> anyhow::Result<()> {
+        match *self {
+            RenderRegion::Pixels { x, y, width: region_width, height: region_height } => {
+                if region_width == 0 || region_height == 0 {
+                    anyhow::bail!("render region {} is empty", self);
+                }
+                if x as u64 + region_width as u64 > width as u64 || y as u64 + region_height as u64 > height as u64 {
+                    anyhow::bail!("render region {} is not inside the {}x{} image", self, width, height);
+                }
+            }
+            RenderRegion::Normalized { x, y, width: region_width, height: region_height } => {
+                let inside = |start: f32, length: f32| start >= 0.0 && length >= 0.0 && start + length <= 1.0;
+                if !inside(x, region_width) || !inside(y, region_height) {
+                    anyhow::bail!("render region {} is not inside the image", self);
+                }
+                // Rounded to pixels the same way as it is rendered
+                let pixels = |start: f32, length: f32, size: u32| {
+                    ((start + length) * size as f32).round() as i64 - (start * size as f32).round() as i64
+                };
+                if pixels(x, region_width, width) <= 0 || pixels(y, region_height, height) <= 0 {
+                    anyhow::bail!("render region {} covers no pixels of the {}x{} image", self, width, height);
+                }
+            }
         }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Default, PartialEq)]
+pub enum RegionOutput {
+    /// Write images of only the region.
+    #[default]
+    Crop,
+    /// Write images of the whole frame, with the pixels outside the region kept from the image
+    /// already at the output path, like a previous render of the full frame.
+    Composite,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct RegionSettings {
+    pub region: RenderRegion,
+    #[serde(default)]
+    pub output: RegionOutput,
+}
+
+impl Display for RegionSettings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:?})", self.region, self.output)
     }
 }
 
@@ -69,13 +700,120 @@ pub struct RenderOptions {
     pub denoise: DenoiseAlgorithm,
     pub integrator: Integrator,
     pub depth_of_field: Option<DofSettings>,
+    #[serde(default)]
+    pub environment: Environment,
+    #[serde(default)]
+    pub light_sampling: LightSampling,
+    #[serde(default)]
+    pub bounce_limits: BounceLimits,
+    #[serde(default)]
+    pub russian_roulette: RussianRoulette,
+    /// Projections keyed by glTF camera name or by the name of the node holding the camera.
+    #[serde(default)]
+    pub camera_projections: BTreeMap<String, CameraProjection>,
+    /// Cameras to render, one after another, each to its own output files. Renders the first
+    /// camera of the scene when empty.
+    #[serde(default)]
+    pub cameras: Vec<CameraSelector>,
+    /// Blurs whatever moves while the shutter is open. Everything is sharp without it.
+    #[serde(default)]
+    pub shutter: Option<ShutterSettings>,
+    /// Only traces pixels inside the region. Renders the whole image without it.
+    #[serde(default)]
+    pub region: Option<RegionSettings>,
+    /// Renders perspective cameras once for each eye.
+    #[serde(default)]
+    pub stereo: Option<StereoSettings>,
+    /// Reconstruction filter of the samples in each pixel.
+    #[serde(default)]
+    pub filter: PixelFilter,
+    #[serde(default)]
+    pub sampler: Sampler,
+    /// Only the path tracer samples adaptively. Every pixel takes `samples` without it.
+    #[serde(default)]
+    pub adaptive_sampling: Option<AdaptiveSampling>,
+    #[serde(default)]
+    pub output: OutputSettings,
+    /// Buffers to write next to the beauty image. Only the path tracer fills them.
+    #[serde(default)]
+    pub aovs: Vec<Aov>,
+    #[serde(default)]
+    pub display: DisplaySettings,
+    #[serde(default)]
+    pub color: ColorSettings,
+}
+
+/// Bounces allowed through each kind of BSDF lobe, on top of the total `max_bounces`.
+/// A limit left out only restricts paths through `max_bounces`.
+#[derive(Copy, Clone, Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct BounceLimits {
+    pub diffuse: Option<u32>,
+    pub glossy: Option<u32>,
+    pub transmission: Option<u32>,
+}
+
+impl Display for BounceLimits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let limit = |limit: Option<u32>| limit.map_or("-".to_string(), |limit| limit.to_string());
+        write!(f, "diffuse: {}, glossy: {}, transmission: {}", limit(self.diffuse), limit(self.glossy), limit(self.transmission))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RussianRoulette {
+    /// Bounces every path is traced for before it may be terminated.
+    pub start_bounce: u32,
+    /// Lower bound of the survival probability once paths may be terminated.
+    pub min_survival: f32,
+}
+
+impl Default for RussianRoulette {
+    fn default() -> Self {
+        Self {
+            start_bounce: 3,
+            min_survival: 0.0,
+        }
+    }
+}
+
+impl Display for RussianRoulette {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "start_bounce: {}, min_survival: {}", self.start_bounce, self.min_survival)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SppmSettings {
+    /// Photons shot per pass. Defaults to one per pixel.
+    pub photons_per_pass: Option<u32>,
+    /// Photon gather radius of the first pass, in scene units. Defaults to 1% of the scene radius.
+    pub initial_radius: Option<f32>,
+    /// Fraction of each pass's photons kept when shrinking the radius, between 0 and 1.
+    pub alpha: f32,
+}
+
+impl Default for SppmSettings {
+    fn default() -> Self {
+        Self {
+            photons_per_pass: None,
+            initial_radius: None,
+            alpha: 2.0 / 3.0,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub enum Integrator {
     Pathtracing,
     Albedo,
-    Debug
+    Debug,
+    /// Bidirectional path tracing
+    Bdpt,
+    /// Stochastic progressive photon mapping
+    Sppm(SppmSettings),
 }
 
 impl Display for Integrator {
@@ -84,6 +822,8 @@ impl Display for Integrator {
             Integrator::Pathtracing => write!(f, "Pathtracing"),
             Integrator::Albedo => write!(f, "Albedo"),
             Integrator::Debug => write!(f, "Debug"),
+            Integrator::Bdpt => write!(f, "Bdpt"),
+            Integrator::Sppm(settings) => write!(f, "Sppm(alpha: {})", settings.alpha),
         }
     }
 }
@@ -109,17 +849,89 @@ impl Display for DenoiseAlgorithm {
     }
 }
 
+impl RenderOptions {
+    /// Fails on settings that can not be rendered.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(region) = &self.region {
+            region.region.validate(self.resolution.width, self.resolution.height)?;
+        }
+        Ok(())
+    }
+}
+
 impl Display for RenderOptions {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "RenderOptions:")?;
         writeln!(f, "  scene_file: {}", self.scene_file)?;
         writeln!(f, "  output_folder: {}", self.output_folder)?;
         writeln!(f, "  resolution: {}", self.resolution)?;
+        if let Some(region) = &self.region {
+            writeln!(f, "  region: {}", region)?;
+        }
+        writeln!(f, "  output: {}", self.output)?;
+        writeln!(f, "  display: {}", self.display)?;
+        writeln!(f, "  color: {}", self.color)?;
+        if !self.aovs.is_empty() {
+            let aovs: Vec<&str> = self.aovs.iter().map(|aov| aov.name()).collect();
+            writeln!(f, "  aovs: {}", aovs.join(", "))?;
+        }
         writeln!(f, "  samples: {}", self.samples)?;
+        writeln!(f, "  sampler: {:?}", self.sampler)?;
+        if let Some(adaptive_sampling) = &self.adaptive_sampling {
+            writeln!(f, "  adaptive_sampling: {}", adaptive_sampling)?;
+        }
+        writeln!(f, "  filter: {:?}", self.filter)?;
         writeln!(f, "  max_bounces: {}", self.max_bounces)?;
+        writeln!(f, "  bounce_limits: {}", self.bounce_limits)?;
+        writeln!(f, "  russian_roulette: {}", self.russian_roulette)?;
         writeln!(f, "  video: {}", self.video)?;
         writeln!(f, "  frame_rate: {}", self.frame_rate)?;
+        if let Some(stereo) = &self.stereo {
+            writeln!(f, "  stereo: {}", stereo)?;
+        }
+        if let Some(shutter) = &self.shutter {
+            writeln!(f, "  shutter: {}", shutter)?;
+        }
         writeln!(f, "  denoise: {}", self.denoise)?;
+        writeln!(f, "  environment: {}", self.environment)?;
+        writeln!(f, "  light_sampling: {}", self.light_sampling)?;
+        if !self.cameras.is_empty() {
+            let cameras: Vec<String> = self.cameras.iter().map(|camera| camera.to_string()).collect();
+            writeln!(f, "  cameras: {}", cameras.join(", "))?;
+        }
+        for (camera, projection) in &self.camera_projections {
+            writeln!(f, "  camera_projection: {}: {}", camera, projection)?;
+        }
         write!(f, "  integrator: {}", self.integrator)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camera_labels_are_unambiguous() {
+        assert_eq!(CameraSelector::Index(0).label(), "idx0");
+        assert_eq!(CameraSelector::Node("camera0".to_string()).label(), "node-camera0");
+        assert_eq!(CameraSelector::Camera("camera0".to_string()).label(), "cam-camera0");
+        assert_eq!(CameraSelector::Node("left eye/1".to_string()).label(), "node-left_20eye_2f1");
+
+        assert_ne!(CameraSelector::Node("a b".to_string()).label(), CameraSelector::Node("a_b".to_string()).label());
+        assert_ne!(CameraSelector::Node("a_20".to_string()).label(), CameraSelector::Node("a 0".to_string()).label());
+    }
+
+    #[test]
+    fn regions_must_cover_pixels_inside_the_image() {
+        assert!(RenderRegion::Pixels { x: 10, y: 20, width: 30, height: 40 }.validate(40, 60).is_ok());
+        assert!(RenderRegion::Pixels { x: 10, y: 20, width: 0, height: 40 }.validate(40, 60).is_err());
+        assert!(RenderRegion::Pixels { x: 30, y: 0, width: 20, height: 10 }.validate(40, 60).is_err());
+        assert!(RenderRegion::Pixels { x: u32::MAX, y: 0, width: 1, height: 1 }.validate(40, 60).is_err());
+
+        assert!(RenderRegion::Normalized { x: 0.25, y: 0.0, width: 0.5, height: 1.0 }.validate(40, 60).is_ok());
+        assert!(RenderRegion::Normalized { x: 0.75, y: 0.0, width: 0.5, height: 1.0 }.validate(40, 60).is_err());
+        assert!(RenderRegion::Normalized { x: -0.1, y: 0.0, width: 0.5, height: 1.0 }.validate(40, 60).is_err());
+        assert!(RenderRegion::Normalized { x: 0.5, y: 0.5, width: 0.001, height: 0.5 }.validate(40, 60).is_err());
+        assert!(RenderRegion::Normalized { x: f32::NAN, y: 0.0, width: 0.5, height: 1.0 }.validate(40, 60).is_err());
+    }
+}
@@ -1,19 +1,70 @@
 use crate::animation::controller::{AnimationController, AnimationState};
+use crate::color;
 use crate::context::Context;
-use crate::denoise::{Denoiser};
-use crate::frame::Frame;
+use crate::denoise::{DenoiseResult, Denoiser};
+use crate::display::DisplayTransform;
+use crate::frame::{Frame, PixelRect};
+use crate::image_io;
 use crate::integrator::integrator::{Integrator, IntegratorImpl};
-use crate::options::{FocalDistance, RenderOptions};
+use crate::options::{Aov, ImageFormat, RegionOutput, RenderOptions, StereoLayout};
+use crate::sampler::SampleIndex;
 use crate::scene::scene::Scene;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
-use nalgebra::Point3;
-use crate::camera::perspective_camera::PerspectiveCamera;
-use crate::camera::viewpoint::Viewpoint;
-use crate::scene::node_graph::{NodeGraph, SceneNode};
+use crate::camera::focus;
+use crate::camera::stereo::{self, Eye};
+use crate::camera::viewpoint::CameraImpl;
+use crate::scene::node_graph::NodeGraph;
+
+/// What the values of an image are, which decides how it is encoded in 8 bit formats.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ImageKind {
+    /// Light, exposed, tone mapped and encoded for display.
+    Color,
+    /// Values like depths and normals, written as they are.
+    Data,
+}
+
+/// Images written to files with the same prefix.
+struct OutputImages {
+    prefix: String,
+    /// Images with the suffix of their file names.
+    images: Vec<(String, ImageKind, Frame)>,
+    /// Where the images go over the images already written, when compositing a render region.
+    composite_rects: Option<Vec<PixelRect>>,
+}
+
+/// A camera, or one eye of it, finished rendering a frame.
+struct FinishedView {
+    eye: Option<Eye>,
+    image: Frame,
+    denoised: DenoiseResult,
+    aovs: Vec<(Aov, Frame)>,
+}
+
+impl FinishedView {
+    /// Images to write, with the suffix of their file names.
+    fn images(&self) -> Vec<(String, ImageKind, &Frame)> {
+        let mut images = vec![
+            (String::new(), ImageKind::Color, &self.image),
+            ("_denoised".to_string(), ImageKind::Color, &self.denoised.denoised_frame),
+        ];
+        if let Some(albedo) = &self.denoised.auxiliary_albedo {
+            images.push(("_albedo".to_string(), ImageKind::Color, albedo));
+        }
+        if let Some(normal) = &self.denoised.auxiliary_normal {
+            images.push(("_normal".to_string(), ImageKind::Data, normal));
+        }
+        images.extend(self.aovs.iter().map(|(aov, frame)| {
+            let kind = if aov.is_color() { ImageKind::Color } else { ImageKind::Data };
+            (format!("_{}", aov.name()), kind, frame)
+        }));
+        images
+    }
+}
 
 pub struct RenderUpdate {
     pub sample: u32,
@@ -47,104 +98,134 @@ impl RenderController {
         let (command_tx, command_rx) = mpsc::channel();
 
         let worker = thread::spawn(move || {
-            let mut frame = Frame::new(options.resolution.width, options.resolution.height);
+            // The denoiser takes its auxiliary images from AOVs of the beauty
+            let mut frame_aovs = options.aovs.clone();
+            frame_aovs.extend(denoiser.auxiliary_aovs().into_iter().filter(|aov| !options.aovs.contains(aov)));
+            let mut frame = Frame::with_aovs(options.resolution.width, options.resolution.height, &frame_aovs);
             let render_start = Instant::now();
+            let display = DisplayTransform::new(&options.display).with_color_matrix(color::display_matrix(&options.color));
+            let output_matrix = color::output_matrix(&options.color);
+            let adaptive_sampling = options.adaptive_sampling.filter(|_| matches!(options.integrator, crate::options::Integrator::Pathtracing));
+            let region = PixelRect::rendered(&options, options.resolution.width as usize, options.resolution.height as usize);
 
             let frame_duration = 1.0 / options.frame_rate as f32;
             let mut stop_video = false;
             let mut frame_index = 0;
 
-            let mut camera = scene.active_camera().clone();
+            if options.video {
+                animation_controller.apply(&mut node_graph, &mut scene, options.shutter.as_ref(), frame_duration);
+            }
 
-            Self::update_depth_of_field(&options, &mut scene, &mut node_graph, &ctx, &mut camera);
+            let selected_cameras = scene.selected_cameras().to_vec();
+            let mut stopped = false;
 
             loop {
-                for sample in 1..=options.samples {
-                    if Self::should_stop(&command_rx) {
-                        break;
-                    }
-
-                    integrator.integrate(&scene, &camera, &mut frame, options.samples, &options, &ctx);
-
-                    let mut rgba = vec![0_u8; (frame.width() * frame.height() * 4) as usize];
-                    frame.write_rgba(&mut rgba);
-
-                    let is_done = sample == options.samples;
-                    let output_path = if is_done {
-                        let folder = std::path::Path::new(&options.output_folder);
-                        println!("Writing to output folder {:?}", folder);
-                        if !folder.exists() {
-                            std::fs::create_dir_all(folder)
-                                .expect("failed to create output folder");
-                        }
-
-                        let path = std::path::Path::new(&options.output_folder)
-                            .join(format!("out{:04}.png", frame_index));
-                        frame.save(path.clone());
-
-                        let denoise_result = denoiser.denoise(&frame, &scene, &camera, options.samples, &options, &ctx);
-
-                        let path = std::path::Path::new(&options.output_folder)
-                            .join(format!("out{:04}_denoised.png", frame_index));
-                        denoise_result.denoised_frame.save(path.clone());
-
-                        if let Some(auxiliary_albedo) = denoise_result.auxiliary_albedo {
-                            let path = std::path::Path::new(&options.output_folder)
-                                .join(format!("out{:04}_albedo.png", frame_index));
-                            auxiliary_albedo.save(path.clone());
+                for (batch_index, &camera_index) in selected_cameras.iter().enumerate() {
+                    scene.set_active_camera(camera_index);
+                    let mut camera = scene.active_camera().clone();
+
+                    Self::update_depth_of_field(&options, &scene, &node_graph, camera_index, animation_controller.time(), &ctx, &mut camera);
+
+                    let views = Self::views(&options, camera);
+                    let mut finished_views = Vec::with_capacity(views.len());
+
+                    'views: for (eye, camera) in &views {
+                        for sample in 1..=options.samples {
+                            if Self::should_stop(&command_rx) {
+                                stopped = true;
+                                break 'views;
+                            }
+
+                            integrator.integrate(&scene, camera, &mut frame, SampleIndex { index: sample - 1, count: options.samples }, &options, &ctx);
+
+                            let mut rgba = vec![0_u8; (frame.width() * frame.height() * 4) as usize];
+                            frame.write_rgba(&mut rgba, &display);
+
+                            let converged = adaptive_sampling.is_some_and(|settings| sample >= settings.min_samples && frame.update_convergence(&region, &settings));
+                            if converged && sample < options.samples {
+                                println!("Converged after {} samples", sample);
+                            }
+
+                            if sample == options.samples || converged {
+                                let mut denoised = denoiser.denoise(&frame, &scene, camera, &options, &ctx);
+                                let image = std::mem::replace(&mut frame, Frame::with_aovs(options.resolution.width, options.resolution.height, &frame_aovs));
+                                // Light AOVs go to the output space with the beauty, so they still add up to it
+                                let aovs: Vec<(Aov, Frame)> = image
+                                    .aovs()
+                                    .into_iter()
+                                    .filter(|(aov, _)| options.aovs.contains(aov))
+                                    .map(|(aov, frame)| match aov.is_color() {
+                                        true => (aov, frame.transform_colors(&output_matrix)),
+                                        false => (aov, frame),
+                                    })
+                                    .collect();
+                                let image = image.transform_colors(&output_matrix);
+                                denoised.denoised_frame = denoised.denoised_frame.transform_colors(&output_matrix);
+                                denoised.auxiliary_albedo = denoised.auxiliary_albedo.map(|albedo| albedo.transform_colors(&output_matrix));
+                                finished_views.push(FinishedView { eye: *eye, image, denoised, aovs });
+                            }
+
+                            let is_done = finished_views.len() == views.len();
+                            let output_path = is_done.then(|| Self::write_outputs(&finished_views, batch_index, frame_index, &options));
+
+                            let update = RenderUpdate {
+                                sample,
+                                rgba,
+                                is_done,
+                                elapsed: render_start.elapsed(),
+                                output_path,
+                            };
+
+                            if update_tx.send(update).is_err() {
+                                stopped = true;
+                                break 'views;
+                            }
+
+                            if converged {
+                                break;
+                            }
                         }
+                    }
 
-                        if let Some(auxiliary_normal) = denoise_result.auxiliary_normal {
-                            let path = std::path::Path::new(&options.output_folder)
-                                .join(format!("out{:04}_normal.png", frame_index));
-                            auxiliary_normal.save(path.clone());
-                        }
-
-
-                        frame.clear();
-                        frame_index += 1;
-
-                        Some(path)
-                    } else {
-                        None
-                    };
-
-                    let update = RenderUpdate {
-                        sample,
-                        rgba,
-                        is_done,
-                        elapsed: render_start.elapsed(),
-                        output_path,
-                    };
-
-                    if update_tx.send(update).is_err() {
+                    if stopped {
                         break;
                     }
-
                 }
 
                 ctx.finalize();
+                frame_index += 1;
+
+                if stopped || !options.video {
+                    break;
+                }
 
-                if !options.video {
+                if stop_video && (options.output.multilayer || options.output.format != ImageFormat::Png) {
+                    println!("Warning: videos are only made from PNG frames, not {}", options.output);
                     break;
                 }
 
                 if stop_video {
-                    let _ = Command::new("ffmpeg")
-                        .current_dir("output") // 👈 only ffmpeg runs here
-                        .args([
-                            "-framerate", "30",
-                            "-i", "out%04d.png",
-                            "-pix_fmt", "yuv420p",
-                            "out.mp4",
-                        ])
-                        .status()
-                        .expect("failed to run ffmpeg");
+                    let prefixes = (0..selected_cameras.len()).flat_map(|batch_index| {
+                        Self::output_eyes(&options).into_iter().map(move |eye| (batch_index, eye))
+                    });
+                    for (batch_index, eye) in prefixes {
+                        let prefix = Self::output_prefix(&options, batch_index, eye);
+                        let _ = Command::new("ffmpeg")
+                            .current_dir("output") // 👈 only ffmpeg runs here
+                            .args([
+                                "-framerate", "30",
+                                "-i", &format!("{}%04d.png", prefix),
+                                "-pix_fmt", "yuv420p",
+                                &format!("{}.mp4", prefix.trim_end_matches('_')),
+                            ])
+                            .status()
+                            .expect("failed to run ffmpeg");
+                    }
 
                     break;
                 }
 
-                if animation_controller.step(frame_duration, &mut node_graph, &mut scene) == AnimationState::Finished {
+                if animation_controller.step(frame_duration, &mut node_graph, &mut scene, options.shutter.as_ref()) == AnimationState::Finished {
                     stop_video = true;
                 }
             }
@@ -157,28 +238,164 @@ impl RenderController {
         }
     }
 
-    fn update_depth_of_field(options: &RenderOptions, mut scene: &mut Scene, mut node_graph: &mut NodeGraph, ctx: &Context, camera: &mut PerspectiveCamera) {
-        if let Some(dof) = &options.depth_of_field {
-            let focal_distance = match dof.focal_distance.clone() {
-                FocalDistance::Fixed(val) => Some(val),
-                FocalDistance::Auto(u, v) => {
-                    let focus_ray = scene.active_camera().generate_ray(u, v);
-                    scene.intersect(&focus_ray, &ctx).map(|hit| hit.intersection.dist)
-                },
-                FocalDistance::Object(name) => {
-                    match node_graph.get_node_by_name(&name) {
-                        None => {
-                            eprintln!("Warning: could not find object '{}' for focal distance", name);
-                            None
-                        },
-                        Some(node) => {
-                            Some((Point3::from(node.local_transform.translation) - camera.origin()).norm())
-                        }
-                    }
+    /// The cameras to render `camera` with: one for each eye in stereo, otherwise just `camera`.
+    fn views(options: &RenderOptions, camera: CameraImpl) -> Vec<(Option<Eye>, CameraImpl)> {
+        match options.stereo.as_ref().map(|stereo| stereo::eye_cameras(&camera, stereo)) {
+            None => vec![(None, camera)],
+            Some(Some(eyes)) => eyes.into_iter().map(|(eye, camera)| (Some(eye), camera)).collect(),
+            Some(None) => {
+                eprintln!("Warning: only perspective cameras render in stereo");
+                vec![(None, camera)]
+            }
+        }
+    }
+
+    /// Eyes written to their own files.
+    fn output_eyes(options: &RenderOptions) -> Vec<Option<Eye>> {
+        match &options.stereo {
+            Some(stereo) if stereo.layout == StereoLayout::Separate => vec![Some(Eye::Left), Some(Eye::Right)],
+            _ => vec![None],
+        }
+    }
+
+    /// Write the images of the views of the `batch_index`th selected camera. Returns the path of
+    /// the denoised image.
+    fn write_outputs(views: &[FinishedView], batch_index: usize, frame_index: usize, options: &RenderOptions) -> PathBuf {
+        let folder = Path::new(&options.output_folder);
+        println!("Writing to output folder {:?}", folder);
+        if !folder.exists() {
+            std::fs::create_dir_all(folder)
+                .expect("failed to create output folder");
+        }
+
+        // The colors of the images are already in the output space
+        let display = DisplayTransform::new(&options.display).with_color_matrix(color::to_rec709(options.color.output_space));
+        let mut denoised_path = PathBuf::new();
+        for output in Self::output_images(views, batch_index, options) {
+            if options.output.multilayer {
+                let path = folder.join(format!("{}{:04}.exr", output.prefix, frame_index));
+                Self::save_layers(&output, &path);
+                denoised_path = path;
+                continue;
+            }
+
+            for (suffix, kind, image) in &output.images {
+                let display = match kind {
+                    ImageKind::Color => display,
+                    ImageKind::Data => DisplayTransform::identity(),
+                };
+                let path = folder.join(format!("{}{:04}{}.{}", output.prefix, frame_index, suffix, options.output.format.extension()));
+                match &output.composite_rects {
+                    Some(rects) => image.save_over(&path, rects, &display),
+                    None => image.save(&path, &display),
+                }
+                if suffix == "_denoised" {
+                    denoised_path = path;
+                }
+            }
+        }
+
+        denoised_path
+    }
+
+    /// The images of `views` as they are written: both eyes combined, unless they are written
+    /// separately, and cropped to the render region.
+    fn output_images(views: &[FinishedView], batch_index: usize, options: &RenderOptions) -> Vec<OutputImages> {
+        let region = options.region.as_ref().map(|settings| settings.output);
+        let layout = options.stereo.map(|stereo| stereo.layout);
+        match (views, layout) {
+            ([left, right], Some(layout)) if layout != StereoLayout::Separate => {
+                let (width, height) = (left.image.width() as usize, left.image.height() as usize);
+                let rect = PixelRect::rendered(options, width, height);
+                let images = left
+                    .images()
+                    .into_iter()
+                    .zip(right.images())
+                    .map(|((suffix, kind, left), (_, _, right))| match region {
+                        Some(RegionOutput::Crop) => (suffix, kind, stereo::combine(&left.crop(&rect), &right.crop(&rect), layout)),
+                        _ => (suffix, kind, stereo::combine(left, right, layout)),
+                    })
+                    .collect();
+                let composite_rects = (region == Some(RegionOutput::Composite)).then(|| stereo::combined_rects(&rect, layout, width, height));
+                vec![OutputImages { prefix: Self::output_prefix(options, batch_index, None), images, composite_rects }]
+            }
+            _ => views
+                .iter()
+                .map(|view| {
+                    let rect = PixelRect::rendered(options, view.image.width() as usize, view.image.height() as usize);
+                    let images = view
+                        .images()
+                        .into_iter()
+                        .map(|(suffix, kind, image)| match region {
+                            Some(RegionOutput::Crop) => (suffix, kind, image.crop(&rect)),
+                            _ => (suffix, kind, image.clone()),
+                        })
+                        .collect();
+                    let composite_rects = (region == Some(RegionOutput::Composite)).then(|| vec![rect]);
+                    OutputImages { prefix: Self::output_prefix(options, batch_index, view.eye), images, composite_rects }
+                })
+                .collect(),
+        }
+    }
+
+    /// Save the images of `output` as layers of one EXR file, named by their suffixes.
+    fn save_layers(output: &OutputImages, path: &Path) {
+        let previous = match &output.composite_rects {
+            Some(_) => image_io::read_layers(path).unwrap_or_else(|_| {
+                eprintln!("Warning: no previous frame at {:?} to composite the render region into", path);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+
+        let layers: Vec<(&str, Frame)> = output
+            .images
+            .iter()
+            .map(|(suffix, _, image)| {
+                let name = match suffix.trim_start_matches('_') {
+                    "" => "beauty",
+                    name => name,
+                };
+                let previous = previous.iter().find(|(previous_name, previous)| {
+                    previous_name == name && previous.width() == image.width() && previous.height() == image.height()
+                });
+                match (previous, &output.composite_rects) {
+                    (Some((_, previous)), Some(rects)) => (name, image.composite_over(previous, rects)),
+                    _ => (name, image.clone()),
                 }
-            };
+            })
+            .collect();
+
+        let layers: Vec<(&str, &Frame)> = layers.iter().map(|(name, image)| (*name, image)).collect();
+        image_io::write_layers(&layers, path).expect("Failed to save image");
+    }
+
+    /// File name prefix of the outputs of the `batch_index`th selected camera, and of `eye` if it
+    /// has files of its own. Only batches of several cameras include the camera in the name.
+    fn output_prefix(options: &RenderOptions, batch_index: usize, eye: Option<Eye>) -> String {
+        let mut prefix = "out".to_string();
+        if options.cameras.len() > 1 {
+            prefix = format!("{}_{}", prefix, options.cameras[batch_index].label());
+        }
+        if let Some(eye) = eye {
+            prefix = format!("{}_{}", prefix, eye.label());
+        }
+
+        if prefix.len() > "out".len() {
+            prefix.push('_');
+        }
+        prefix
+    }
+
+    /// Focus `camera`, the scene camera with index `camera_index`, on what it should be focusing on
+    /// at `time`. Cameras with focus set in the scene use that over the options.
+    fn update_depth_of_field(options: &RenderOptions, scene: &Scene, node_graph: &NodeGraph, camera_index: usize, time: f32, ctx: &Context, camera: &mut CameraImpl) {
+        if let Some(dof) = &options.depth_of_field {
+            let focus = node_graph.camera_node(camera_index)
+                .and_then(|node| node.camera_focus.as_ref())
+                .unwrap_or(&dof.focal_distance);
 
-            if let Some(focal_distance) = focal_distance {
+            if let Some(focal_distance) = focus::focal_distance(focus, time, camera, scene, node_graph, ctx) {
                 camera.set_focal_distance(focal_distance);
             }
         }
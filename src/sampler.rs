@@ -0,0 +1,230 @@
+pub mod blue_noise;
+pub mod independent;
+pub mod sobol;
+pub mod stratified;
+
+use nalgebra::Vector2;
+use rand::RngCore;
+use rand::rngs::ThreadRng;
+use crate::options::RenderOptions;
+use crate::sampler::blue_noise::BlueNoiseSequence;
+use crate::sampler::independent::IndependentSequence;
+use crate::sampler::sobol::SobolSequence;
+use crate::sampler::stratified::StratifiedSequence;
+
+/// Which sample of every pixel a pass of an integrator takes.
+#[derive(Copy, Clone, Debug)]
+pub struct SampleIndex {
+    /// From 0.
+    pub index: u32,
+    /// Samples every pixel takes in total, which the sequences are laid out for.
+    pub count: u32,
+}
+
+/// Source of the random numbers of a sample, one dimension at a time.
+pub trait Sampler: RngCore {
+    fn get_1d(&mut self) -> f32 {
+        to_float(self.next_u32())
+    }
+
+    fn get_2d(&mut self) -> Vector2<f32> {
+        Vector2::new(self.get_1d(), self.get_1d())
+    }
+}
+
+impl Sampler for ThreadRng {}
+
+/// Values of the sample dimensions of every pixel, generated two dimensions at a time.
+pub trait Sequence {
+    /// Values of `dimension` and the one after it in sample `index` of the pixel at `x`, `y`, as
+    /// fractions of 2^32. `dimension` is even.
+    fn generate(&self, x: u32, y: u32, index: u32, dimension: u32) -> [u32; 2];
+}
+
+pub enum SequenceImpl {
+    Independent(IndependentSequence),
+    Stratified(StratifiedSequence),
+    Sobol(SobolSequence),
+    BlueNoise(BlueNoiseSequence),
+}
+
+impl Sequence for SequenceImpl {
+    fn generate(&self, x: u32, y: u32, index: u32, dimension: u32) -> [u32; 2] {
+        match self {
+            SequenceImpl::Independent(s) => s.generate(x, y, index, dimension),
+            SequenceImpl::Stratified(s) => s.generate(x, y, index, dimension),
+            SequenceImpl::Sobol(s) => s.generate(x, y, index, dimension),
+            SequenceImpl::BlueNoise(s) => s.generate(x, y, index, dimension),
+        }
+    }
+}
+
+/// First dimensions of the parts of a path, so every sample of a pixel uses the same dimensions
+/// for the same thing however many the parts before it used.
+#[derive(Copy, Clone, Debug)]
+pub enum Dimensions {
+    Pixel,
+    Time,
+    Lens,
+    /// Light selection and the point on the light, at a bounce.
+    Light(u32),
+    /// BSDF direction, lobe and Russian roulette, at a bounce.
+    Bsdf(u32),
+}
+
+impl Dimensions {
+    fn first(&self) -> u32 {
+        const CAMERA_DIMENSIONS: u32 = 8;
+        const LIGHT_DIMENSIONS: u32 = 4;
+        const BOUNCE_DIMENSIONS: u32 = 8;
+        match self {
+            Dimensions::Pixel => 0,
+            Dimensions::Time => 2,
+            Dimensions::Lens => 4,
+            Dimensions::Light(bounce) => CAMERA_DIMENSIONS + bounce * BOUNCE_DIMENSIONS,
+            Dimensions::Bsdf(bounce) => CAMERA_DIMENSIONS + bounce * BOUNCE_DIMENSIONS + LIGHT_DIMENSIONS,
+        }
+    }
+}
+
+/// Sampler of the samples of one pixel at a time, from a sequence.
+pub struct PixelSampler {
+    sequence: SequenceImpl,
+    x: u32,
+    y: u32,
+    index: u32,
+    dimension: u32,
+    /// Second dimension of the last generated pair, not yet used.
+    pending: Option<u32>,
+}
+
+impl PixelSampler {
+    pub fn new(sequence: SequenceImpl) -> Self {
+        Self { sequence, x: 0, y: 0, index: 0, dimension: 0, pending: None }
+    }
+
+    /// Start sample `index` of the pixel at `x`, `y`, from its first dimension.
+    pub fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.x = x;
+        self.y = y;
+        self.index = index;
+        self.start(Dimensions::Pixel);
+    }
+
+    /// Continue from the first of `dimensions`.
+    pub fn start(&mut self, dimensions: Dimensions) {
+        self.dimension = dimensions.first();
+        self.pending = None;
+    }
+}
+
+impl RngCore for PixelSampler {
+    fn next_u32(&mut self) -> u32 {
+        self.dimension += 1;
+        if let Some(value) = self.pending.take() {
+            return value;
+        }
+
+        let [first, second] = self.sequence.generate(self.x, self.y, self.index, self.dimension - 1);
+        self.pending = Some(second);
+        first
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand::rand_core::impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        rand::rand_core::impls::fill_bytes_via_next(self, dst)
+    }
+}
+
+impl Sampler for PixelSampler {
+    fn get_2d(&mut self) -> Vector2<f32> {
+        // Both dimensions of one pair, which sequences stratify together
+        if self.pending.take().is_some() {
+            self.dimension += 1;
+        }
+        Vector2::new(self.get_1d(), self.get_1d())
+    }
+}
+
+/// Sampler of the sequence chosen in `options`, for `samples` samples per pixel.
+pub fn create(options: &RenderOptions, samples: u32) -> PixelSampler {
+    let sequence = match options.sampler {
+        crate::options::Sampler::Independent => SequenceImpl::Independent(IndependentSequence),
+        crate::options::Sampler::Stratified => SequenceImpl::Stratified(StratifiedSequence::new(samples)),
+        crate::options::Sampler::Sobol => SequenceImpl::Sobol(SobolSequence),
+        crate::options::Sampler::BlueNoise => SequenceImpl::BlueNoise(BlueNoiseSequence::new()),
+    };
+    PixelSampler::new(sequence)
+}
+
+/// Fraction of 2^32 as a float below 1.
+pub fn to_float(value: u32) -> f32 {
+    (value >> 8) as f32 * (1.0 / (1 << 24) as f32)
+}
+
+/// Hash of `values`, for seeding the randomization of samples.
+pub(crate) fn hash(values: &[u32]) -> u32 {
+    values.iter().fold(0x811c9dc5, |hash, &value| mix(hash ^ mix(value.wrapping_add(0x9e3779b9))))
+}
+
+fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^ (x >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_of_a_pixel_are_uniform_and_repeatable() {
+        let samples = 64;
+        let sequences = [
+            SequenceImpl::Independent(IndependentSequence),
+            SequenceImpl::Stratified(StratifiedSequence::new(samples)),
+            SequenceImpl::Sobol(SobolSequence),
+            SequenceImpl::BlueNoise(BlueNoiseSequence::new()),
+        ];
+
+        for sequence in sequences {
+            let mut sampler = PixelSampler::new(sequence);
+            let mut mean = Vector2::zeros();
+            for index in 0..samples {
+                sampler.start_pixel_sample(3, 5, index);
+                let pixel = sampler.get_2d();
+                sampler.start(Dimensions::Bsdf(2));
+                let direction = sampler.get_2d();
+                assert!(pixel.x < 1.0 && pixel.y < 1.0 && direction.x < 1.0);
+                mean += direction;
+
+                sampler.start_pixel_sample(3, 5, index);
+                assert_eq!(sampler.get_2d(), pixel);
+            }
+
+            mean /= samples as f32;
+            assert!((mean.x - 0.5).abs() < 0.1 && (mean.y - 0.5).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn pairs_are_stratified() {
+        let samples = 16;
+        for sequence in [SequenceImpl::Stratified(StratifiedSequence::new(samples)), SequenceImpl::Sobol(SobolSequence)] {
+            let mut sampler = PixelSampler::new(sequence);
+            let mut cells = [false; 16];
+            for index in 0..samples {
+                sampler.start_pixel_sample(7, 2, index);
+                let point = sampler.get_2d();
+                cells[(point.x * 4.0) as usize + (point.y * 4.0) as usize * 4] = true;
+            }
+            // Every cell of a 4 by 4 grid has one of the 16 points
+            assert!(cells.iter().all(|&cell| cell));
+        }
+    }
+}
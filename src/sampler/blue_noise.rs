@@ -0,0 +1,77 @@
+use std::sync::OnceLock;
+use crate::sampler::sobol::scrambled_sobol_2d;
+use crate::sampler::{hash, Sequence};
+
+const SIZE: usize = 64;
+
+/// The same scrambled Sobol points in every pixel, shifted by a blue noise texture so that the
+/// error of neighbouring pixels differs as much as possible. From Georgiev and Fajardo,
+/// "Blue-noise Dithered Sampling".
+pub struct BlueNoiseSequence {
+    texture: &'static [u32],
+}
+
+impl BlueNoiseSequence {
+    pub fn new() -> Self {
+        static TEXTURE: OnceLock<Vec<u32>> = OnceLock::new();
+        Self { texture: TEXTURE.get_or_init(generate_texture) }
+    }
+
+    /// Texel of the texture at the pixel, offset differently for every dimension.
+    fn shift(&self, x: u32, y: u32, dimension: u32) -> u32 {
+        let offset = hash(&[dimension]);
+        let tx = (x as usize + (offset & 0xffff) as usize) % SIZE;
+        let ty = (y as usize + (offset >> 16) as usize) % SIZE;
+        self.texture[tx + ty * SIZE]
+    }
+}
+
+impl Default for BlueNoiseSequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sequence for BlueNoiseSequence {
+    fn generate(&self, x: u32, y: u32, index: u32, dimension: u32) -> [u32; 2] {
+        let [first, second] = scrambled_sobol_2d(index, hash(&[dimension]));
+        // Adding wraps around, like shifting the points over the unit square
+        [first.wrapping_add(self.shift(x, y, dimension)), second.wrapping_add(self.shift(x, y, dimension + 1))]
+    }
+}
+
+/// Texture of values spread evenly over 0 to 2^32, with similar values far apart. Made by the
+/// void and cluster method, adding texels in the largest voids of the ones before them.
+fn generate_texture() -> Vec<u32> {
+    const SIGMA: f32 = 1.9;
+    let texel_count = SIZE * SIZE;
+
+    // Energy the texel at an offset, wrapping around the texture, adds to another
+    let kernel: Vec<f32> = (0..texel_count)
+        .map(|index| {
+            let distance = |d: usize| d.min(SIZE - d) as f32;
+            let (dx, dy) = (distance(index % SIZE), distance(index / SIZE));
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+
+    let mut energy = vec![0.0_f32; texel_count];
+    let mut texture = vec![None; texel_count];
+    for rank in 0..texel_count {
+        let void = (0..texel_count)
+            .filter(|&index| texture[index].is_none())
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap();
+        texture[void] = Some(rank);
+
+        let (vx, vy) = (void % SIZE, void / SIZE);
+        for (index, energy) in energy.iter_mut().enumerate() {
+            let dx = (index % SIZE + SIZE - vx) % SIZE;
+            let dy = (index / SIZE + SIZE - vy) % SIZE;
+            *energy += kernel[dx + dy * SIZE];
+        }
+    }
+
+    let step = (1u64 << 32) / texel_count as u64;
+    texture.into_iter().map(|rank| (rank.unwrap() as u64 * step) as u32).collect()
+}
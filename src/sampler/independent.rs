@@ -0,0 +1,11 @@
+use crate::sampler::{hash, Sequence};
+
+/// Uncorrelated random values, the same for the same pixel, sample and dimension.
+pub struct IndependentSequence;
+
+impl Sequence for IndependentSequence {
+    fn generate(&self, x: u32, y: u32, index: u32, dimension: u32) -> [u32; 2] {
+        let first = hash(&[x, y, index, dimension]);
+        [first, hash(&[first, dimension])]
+    }
+}
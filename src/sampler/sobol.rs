@@ -0,0 +1,47 @@
+use crate::sampler::{hash, Sequence};
+
+/// The first two dimensions of the Sobol sequence with Owen scrambling, randomized for every
+/// pixel and pair of dimensions. From Burley, "Practical Hash-based Owen Scrambling".
+pub struct SobolSequence;
+
+impl Sequence for SobolSequence {
+    fn generate(&self, x: u32, y: u32, index: u32, dimension: u32) -> [u32; 2] {
+        scrambled_sobol_2d(index, hash(&[x, y, dimension]))
+    }
+}
+
+/// Point `index` of the Sobol sequence, with the order of the points shuffled and the values
+/// scrambled by `seed`.
+pub(crate) fn scrambled_sobol_2d(index: u32, seed: u32) -> [u32; 2] {
+    // Shuffling keeps every power of two prefix of the points well distributed
+    let index = nested_uniform_scramble(index, seed);
+    let (x, y) = (index.reverse_bits(), sobol_dimension_1(index));
+    [nested_uniform_scramble(x, hash(&[seed, 0])), nested_uniform_scramble(y, hash(&[seed, 1]))]
+}
+
+fn sobol_dimension_1(mut index: u32) -> u32 {
+    let mut value = 0;
+    let mut direction = 1 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            value ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    value
+}
+
+/// Owen scrambling of the bits of `value`, every bit flipped by a hash of the bits above it.
+fn nested_uniform_scramble(value: u32, seed: u32) -> u32 {
+    laine_karras_permutation(value.reverse_bits(), seed).reverse_bits()
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
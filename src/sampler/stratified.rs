@@ -0,0 +1,68 @@
+use crate::sampler::{hash, Sequence};
+
+/// Jittered samples in a grid of cells over each pair of dimensions, one sample per cell. The
+/// samples of a pixel visit the cells in a different order for every pair.
+pub struct StratifiedSequence {
+    columns: u32,
+    rows: u32,
+}
+
+impl StratifiedSequence {
+    /// Grid of about `samples` cells. Samples past the cell count start over in new places.
+    pub fn new(samples: u32) -> Self {
+        let columns = (samples.max(1) as f32).sqrt() as u32;
+        Self { columns, rows: samples.max(1) / columns }
+    }
+}
+
+impl Sequence for StratifiedSequence {
+    fn generate(&self, x: u32, y: u32, index: u32, dimension: u32) -> [u32; 2] {
+        let cells = self.columns * self.rows;
+        let round = index / cells;
+        let cell = permutation_element(index % cells, cells, hash(&[x, y, dimension, round]));
+
+        let jitter = |cell: u32, count: u32, seed: u32| {
+            let width = (1u64 << 32) / count as u64;
+            let offset = (hash(&[x, y, index, dimension, seed]) as u64 * width) >> 32;
+            (cell as u64 * width + offset) as u32
+        };
+        [jitter(cell % self.columns, self.columns, 0), jitter(cell / self.columns, self.rows, 1)]
+    }
+}
+
+/// Element `index` of a random permutation of `0..length`, chosen by `seed`. From Kensler,
+/// "Correlated Multi-Jittered Sampling".
+pub(crate) fn permutation_element(index: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+
+    let mut i = index;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= mask;
+        i ^= i >> 5;
+        // Elements past the length are permuted again until they land inside it
+        if i < length {
+            return (i + seed) % length;
+        }
+    }
+}
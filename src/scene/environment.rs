@@ -0,0 +1,160 @@
+use std::f32::consts::PI;
+use nalgebra::Vector3;
+use rand::Rng;
+use crate::math::distribution::Distribution2D;
+use crate::math::luminance;
+
+/// Infinitely distant light defined by an equirectangular (latitude/longitude) radiance map.
+///
+/// The map is oriented with +Y up and the image centre looking down -Z. `rotation` spins
+/// the map around the Y axis.
+pub struct EnvironmentLight {
+    pixels: Vec<Vector3<f32>>,
+    width: u32,
+    height: u32,
+    rotation: f32,
+    intensity: f32,
+    distribution: Distribution2D,
+    /// Luminance averaged over the sphere of directions.
+    average_luminance: f32,
+}
+
+impl EnvironmentLight {
+    /// `rotation` is given in radians.
+    pub fn new(pixels: Vec<Vector3<f32>>, width: u32, height: u32, rotation: f32, intensity: f32) -> Self {
+        assert!(width > 0 && height > 0, "environment map dimensions must be non-zero");
+        assert_eq!(pixels.len(), width as usize * height as usize);
+
+        // Weight every texel by its luminance and by sin(theta) to account for the
+        // area distortion of the equirectangular projection near the poles.
+        let mut func = Vec::with_capacity(pixels.len());
+        let mut total_weight = 0.0;
+        for (y, row) in pixels.chunks_exact(width as usize).enumerate() {
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            func.extend(row.iter().map(|p| luminance(p).max(0.0) * sin_theta));
+            total_weight += sin_theta * width as f32;
+        }
+
+        let average_luminance = if total_weight > 0.0 {
+            func.iter().sum::<f32>() / total_weight
+        } else {
+            0.0
+        };
+        let distribution = Distribution2D::new(&func, width as usize, height as usize);
+
+        Self {
+            pixels,
+            width,
+            height,
+            rotation,
+            intensity,
+            distribution,
+            average_luminance,
+        }
+    }
+
+    /// Power arriving at a scene bounded by a sphere of `scene_radius`.
+    pub fn phi(&self, scene_radius: f32) -> f32 {
+        4.0 * PI * PI * scene_radius * scene_radius * self.average_luminance * self.intensity
+    }
+
+    fn direction_to_uv(&self, direction: &Vector3<f32>) -> (f32, f32) {
+        let d = direction.normalize();
+        let phi = d.x.atan2(-d.z) - self.rotation;
+        let theta = d.y.clamp(-1.0, 1.0).acos();
+
+        let u = (phi / (2.0 * PI) + 0.5).rem_euclid(1.0);
+        let v = theta / PI;
+        (u, v)
+    }
+
+    fn uv_to_direction(&self, u: f32, v: f32) -> Vector3<f32> {
+        Self::direction_at(u, v, self.rotation)
+    }
+
+    /// Direction of the texel at (`u`, `v`) of a map rotated by `rotation` radians.
+    pub fn direction_at(u: f32, v: f32, rotation: f32) -> Vector3<f32> {
+        let phi = (u - 0.5) * 2.0 * PI + rotation;
+        let theta = v * PI;
+        let sin_theta = theta.sin();
+
+        Vector3::new(sin_theta * phi.sin(), theta.cos(), -sin_theta * phi.cos())
+    }
+
+    fn lookup(&self, u: f32, v: f32) -> Vector3<f32> {
+        let x = ((u * self.width as f32) as usize).min(self.width as usize - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height as usize - 1);
+        self.pixels[x + y * self.width as usize] * self.intensity
+    }
+
+    /// Radiance arriving from `direction` (pointing away from the scene).
+    pub fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let (u, v) = self.direction_to_uv(direction);
+        self.lookup(u, v)
+    }
+
+    /// Importance sample a direction proportional to the map's luminance.
+    /// Returns (direction, radiance, solid angle pdf).
+    pub fn sample(&self, rng: &mut impl Rng) -> (Vector3<f32>, Vector3<f32>, f32) {
+        let ((u, v), map_pdf) = self.distribution.sample_continuous((rng.random(), rng.random()));
+        let sin_theta = (v * PI).sin();
+        if map_pdf <= 0.0 || sin_theta <= 0.0 {
+            return (Vector3::y(), Vector3::zeros(), 0.0);
+        }
+
+        let direction = self.uv_to_direction(u, v);
+        let pdf = map_pdf / (2.0 * PI * PI * sin_theta);
+
+        (direction, self.lookup(u, v), pdf)
+    }
+
+    /// Solid angle pdf of `sample` returning `direction`.
+    pub fn pdf(&self, direction: &Vector3<f32>) -> f32 {
+        let (u, v) = self.direction_to_uv(direction);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use super::*;
+
+    fn make_environment(rotation: f32) -> EnvironmentLight {
+        let width = 8;
+        let height = 4;
+        let mut pixels = vec![Vector3::new(0.1, 0.1, 0.1); width * height];
+        pixels[5 + 2 * width] = Vector3::new(50.0, 40.0, 30.0);
+        EnvironmentLight::new(pixels, width as u32, height as u32, rotation, 1.0)
+    }
+
+    #[test]
+    fn uv_direction_mapping_round_trips() {
+        let environment = make_environment(0.7);
+        for (u, v) in [(0.1, 0.2), (0.5, 0.5), (0.85, 0.9)] {
+            let direction = environment.uv_to_direction(u, v);
+            let (u2, v2) = environment.direction_to_uv(&direction);
+            assert!((u - u2).abs() < 1e-4, "u: {} != {}", u, u2);
+            assert!((v - v2).abs() < 1e-4, "v: {} != {}", v, v2);
+        }
+    }
+
+    #[test]
+    fn sampled_pdf_matches_pdf_query() {
+        let environment = make_environment(0.0);
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for _ in 0..64 {
+            let (direction, radiance, pdf) = environment.sample(&mut rng);
+            assert!(pdf > 0.0);
+            assert_eq!(radiance, environment.radiance(&direction));
+            assert!((environment.pdf(&direction) - pdf).abs() / pdf < 1e-2);
+        }
+    }
+}
@@ -1,24 +1,99 @@
-use nalgebra::{Point3, Vector3};
+use std::f32::consts::PI;
+use nalgebra::{Matrix4, Point3, Vector3};
+use crate::acceleration::bounds::AABB;
+use crate::acceleration::light_bvh::LightBounds;
 use crate::content::mesh::MeshInstance;
+use crate::math::luminance;
+use crate::scene::environment::EnvironmentLight;
+use crate::scene::sky::SkyLight;
+use crate::scene::material::Material;
+use crate::scene::Shadeable;
 
 pub enum LightSource {
     Point(PointLight),
+    Spot(SpotLight),
     Directional(DirectionalLight),
     Mesh(MeshInstance),
+    Environment(EnvironmentLight),
+    Sky(SkyLight),
 }
 
 impl LightSource {
-    pub fn update_transform(&mut self, transform: nalgebra::Matrix4<f32>) {
+    pub fn update_transform(&mut self, transform: Matrix4<f32>) {
         match self {
             LightSource::Point(light) => {
                 light.position = transform.transform_point(&Point3::origin());
             },
-            LightSource::Directional(_) => {
-                // Do nothing
+            LightSource::Spot(light) => {
+                light.position = transform.transform_point(&Point3::origin());
+                light.direction = transform.transform_vector(&Vector3::new(0.0, 0.0, -1.0)).normalize();
+            },
+            LightSource::Directional(light) => {
+                light.direction = transform.transform_vector(&Vector3::new(0.0, 0.0, -1.0)).normalize();
             }
             LightSource::Mesh(mesh) => {
                 mesh.update_transform(transform);
             },
+            LightSource::Environment(_) => {
+                // Not attached to any node
+            }
+            LightSource::Sky(sky) => {
+                // Only attached to a node when driven by a directional light
+                sky.set_sun_direction(-transform.transform_vector(&Vector3::new(0.0, 0.0, -1.0)));
+            }
+        }
+    }
+
+    /// Total emitted power. Lights infinitely far away are measured by how much of it reaches
+    /// a scene bounded by a sphere of `scene_radius`.
+    pub fn phi(&self, materials: &[Material], scene_radius: f32) -> f32 {
+        match self {
+            LightSource::Point(light) => 4.0 * PI * luminance(&light.color) * light.intensity,
+            LightSource::Spot(light) => {
+                // Full intensity inside the inner cone, approximating the falloff as linear beyond it.
+                let solid_angle = 2.0 * PI * ((1.0 - light.cos_inner) + (light.cos_inner - light.cos_outer) / 2.0);
+                solid_angle * luminance(&light.color) * light.intensity
+            }
+            LightSource::Directional(light) => PI * scene_radius * scene_radius * luminance(&light.color) * light.intensity,
+            LightSource::Mesh(mesh) => {
+                let material = &materials[mesh.material_index() as usize];
+                PI * mesh.surface_area() * luminance(&material.emissive_factor())
+            }
+            LightSource::Environment(environment) => environment.phi(scene_radius),
+            LightSource::Sky(sky) => sky.phi(scene_radius),
+        }
+    }
+
+    /// Spatial and directional bounds of the emitted light, `None` for lights infinitely far away.
+    pub fn bounds(&self, materials: &[Material]) -> Option<LightBounds> {
+        let phi = self.phi(materials, 0.0);
+        match self {
+            LightSource::Point(light) => {
+                let mut bounds = AABB::new(light.position, light.position);
+                bounds.inflate(light.radius);
+                Some(LightBounds::omnidirectional(bounds, phi))
+            }
+            LightSource::Spot(light) => {
+                let theta_e = light.cos_outer.acos() - light.cos_inner.acos();
+                Some(LightBounds {
+                    bounds: AABB::new(light.position, light.position),
+                    phi,
+                    direction: light.direction,
+                    cos_theta_o: light.cos_inner,
+                    cos_theta_e: theta_e.cos(),
+                })
+            }
+            LightSource::Mesh(mesh) => {
+                let (direction, cos_theta_o) = mesh.normal_cone()?;
+                Some(LightBounds {
+                    bounds: mesh.world_bounds(),
+                    phi,
+                    direction,
+                    cos_theta_o,
+                    cos_theta_e: 0.0,
+                })
+            }
+            LightSource::Directional(_) | LightSource::Environment(_) | LightSource::Sky(_) => None,
         }
     }
 }
@@ -41,6 +116,45 @@ impl PointLight {
     }
 }
 
+/// Spot light as defined by KHR_lights_punctual, shining down its local -Z axis.
+pub struct SpotLight {
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+    pub position: Point3<f32>,
+    pub direction: Vector3<f32>,
+    /// Cosine of the angle at which the falloff starts.
+    pub cos_inner: f32,
+    /// Cosine of the angle at which the light has faded out completely.
+    pub cos_outer: f32,
+}
+
+impl SpotLight {
+    /// Cone angles are in radians, measured from the spot direction.
+    pub fn new(position: Point3<f32>, direction: Vector3<f32>, color: Vector3<f32>, intensity: f32, inner_cone_angle: f32, outer_cone_angle: f32) -> Self {
+        let outer_cone_angle = outer_cone_angle.clamp(0.0, std::f32::consts::FRAC_PI_2);
+        let inner_cone_angle = inner_cone_angle.clamp(0.0, outer_cone_angle);
+
+        Self {
+            color,
+            intensity,
+            position,
+            direction: direction.normalize(),
+            cos_inner: inner_cone_angle.cos(),
+            cos_outer: outer_cone_angle.cos(),
+        }
+    }
+
+    /// Angular attenuation towards `direction` (pointing away from the light), using the smooth
+    /// falloff recommended by the glTF spec.
+    pub fn falloff(&self, direction: &Vector3<f32>) -> f32 {
+        let cos_theta = self.direction.dot(&direction.normalize());
+        let scale = 1.0 / (self.cos_inner - self.cos_outer).max(0.001);
+        let offset = -self.cos_outer * scale;
+        let attenuation = (cos_theta * scale + offset).clamp(0.0, 1.0);
+        attenuation * attenuation
+    }
+}
+
 pub struct DirectionalLight {
     pub color: Vector3<f32>,
     pub intensity: f32,
@@ -55,4 +169,43 @@ impl DirectionalLight {
             direction,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spot_light_falloff_follows_cone_angles() {
+        let light = SpotLight::new(Point3::origin(), -Vector3::z(), Vector3::new(1.0, 1.0, 1.0), 1.0, 0.2, 0.4);
+
+        assert_eq!(light.falloff(&-Vector3::z()), 1.0);
+        assert_eq!(light.falloff(&Vector3::new(0.19f32.sin(), 0.0, -0.19f32.cos())), 1.0);
+        assert_eq!(light.falloff(&Vector3::new(0.41f32.sin(), 0.0, -0.41f32.cos())), 0.0);
+        assert_eq!(light.falloff(&Vector3::z()), 0.0);
+
+        let halfway = light.falloff(&Vector3::new(0.3f32.sin(), 0.0, -0.3f32.cos()));
+        assert!(halfway > 0.0 && halfway < 1.0);
+    }
+
+    #[test]
+    fn spot_light_follows_node_transform() {
+        let mut light = LightSource::Spot(SpotLight::new(Point3::origin(), -Vector3::z(), Vector3::new(1.0, 1.0, 1.0), 1.0, 0.2, 0.4));
+        let transform = Matrix4::new_translation(&Vector3::new(1.0, 2.0, 3.0)) * Matrix4::from_euler_angles(-std::f32::consts::FRAC_PI_2, 0.0, 0.0);
+        light.update_transform(transform);
+
+        let LightSource::Spot(light) = light else { unreachable!() };
+        assert!((light.position - Point3::new(1.0, 2.0, 3.0)).norm() < 1e-5);
+        assert!((light.direction - -Vector3::y()).norm() < 1e-5);
+    }
+
+    #[test]
+    fn directional_light_follows_node_rotation() {
+        let mut light = LightSource::Directional(DirectionalLight::new(-Vector3::z(), Vector3::new(1.0, 1.0, 1.0), 1.0));
+        let transform = Matrix4::new_translation(&Vector3::new(5.0, 0.0, 0.0)) * Matrix4::from_euler_angles(0.0, std::f32::consts::FRAC_PI_2, 0.0);
+        light.update_transform(transform);
+
+        let LightSource::Directional(light) = light else { unreachable!() };
+        assert!((light.direction - -Vector3::x()).norm() < 1e-5);
+    }
+}
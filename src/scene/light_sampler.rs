@@ -0,0 +1,151 @@
+use nalgebra::{Point3, Vector3};
+use crate::acceleration::light_bvh::LightBVH;
+use crate::math::distribution::Distribution1D;
+use crate::options::LightSampling;
+use crate::scene::light::LightSource;
+use crate::scene::material::Material;
+
+/// The shading point a light is picked for.
+#[derive(Copy, Clone, Debug)]
+pub struct LightSampleContext {
+    pub position: Point3<f32>,
+    /// Shading normal, or zero if the point isn't on a surface.
+    pub normal: Vector3<f32>,
+}
+
+/// Lights without bounds (directional and environment lights) are picked with the same
+/// probability as the whole BVH of bounded lights.
+pub struct BvhLightSampler {
+    bvh: LightBVH,
+    infinite_lights: Vec<usize>,
+}
+
+impl BvhLightSampler {
+    fn infinite_probability(&self) -> f32 {
+        let bounded = if self.bvh.is_empty() { 0.0 } else { 1.0 };
+        self.infinite_lights.len() as f32 / (self.infinite_lights.len() as f32 + bounded)
+    }
+}
+
+/// Picks which light to sample for direct lighting.
+pub enum LightSampler {
+    Uniform { light_count: usize },
+    Power(Distribution1D),
+    Bvh(BvhLightSampler),
+}
+
+impl LightSampler {
+    pub fn new(strategy: LightSampling, lights: &[LightSource], materials: &[Material], scene_radius: f32) -> Self {
+        if lights.is_empty() {
+            return LightSampler::Uniform { light_count: 0 };
+        }
+
+        match strategy {
+            LightSampling::Uniform => LightSampler::Uniform { light_count: lights.len() },
+            LightSampling::Power => {
+                let power = lights.iter().map(|light| light.phi(materials, scene_radius)).collect();
+                LightSampler::Power(Distribution1D::new(power))
+            }
+            LightSampling::Bvh => {
+                let mut bounded_lights = Vec::new();
+                let mut infinite_lights = Vec::new();
+                for (light_index, light) in lights.iter().enumerate() {
+                    match light.bounds(materials) {
+                        Some(bounds) => bounded_lights.push((light_index, bounds)),
+                        None => infinite_lights.push(light_index),
+                    }
+                }
+
+                LightSampler::Bvh(BvhLightSampler {
+                    bvh: LightBVH::new(bounded_lights, lights.len()),
+                    infinite_lights,
+                })
+            }
+        }
+    }
+
+    /// Pick a light for `context`. Returns (light index, probability of picking it).
+    pub fn sample(&self, context: &LightSampleContext, u: f32) -> Option<(usize, f32)> {
+        match self {
+            LightSampler::Uniform { light_count } => {
+                if *light_count == 0 {
+                    return None;
+                }
+                let light_index = ((u * *light_count as f32) as usize).min(light_count - 1);
+                Some((light_index, 1.0 / *light_count as f32))
+            }
+            LightSampler::Power(distribution) => {
+                let (light_index, pmf) = distribution.sample_discrete(u);
+                (pmf > 0.0).then_some((light_index, pmf))
+            }
+            LightSampler::Bvh(sampler) => {
+                let p_infinite = sampler.infinite_probability();
+                if u < p_infinite {
+                    let count = sampler.infinite_lights.len();
+                    let index = ((u / p_infinite * count as f32) as usize).min(count - 1);
+                    Some((sampler.infinite_lights[index], p_infinite / count as f32))
+                } else {
+                    let u = ((u - p_infinite) / (1.0 - p_infinite)).min(1.0 - f32::EPSILON);
+                    sampler
+                        .bvh
+                        .sample(&context.position, &context.normal, u)
+                        .map(|(light_index, pmf)| (light_index, pmf * (1.0 - p_infinite)))
+                }
+            }
+        }
+    }
+
+    /// Probability of `sample` picking `light_index` for `context`.
+    pub fn pmf(&self, context: &LightSampleContext, light_index: usize) -> f32 {
+        match self {
+            LightSampler::Uniform { light_count } => 1.0 / *light_count as f32,
+            LightSampler::Power(distribution) => distribution.pmf(light_index),
+            LightSampler::Bvh(sampler) => {
+                let p_infinite = sampler.infinite_probability();
+                if sampler.infinite_lights.contains(&light_index) {
+                    p_infinite / sampler.infinite_lights.len() as f32
+                } else {
+                    sampler.bvh.pmf(&context.position, &context.normal, light_index) * (1.0 - p_infinite)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::light::{DirectionalLight, PointLight};
+
+    fn create_lights() -> Vec<LightSource> {
+        vec![
+            LightSource::Point(PointLight::new(Point3::new(0.0, 2.0, 0.0), Vector3::new(1.0, 1.0, 1.0), 1.0, 0.0)),
+            LightSource::Point(PointLight::new(Point3::new(4.0, 2.0, 0.0), Vector3::new(1.0, 1.0, 1.0), 3.0, 0.0)),
+            LightSource::Directional(DirectionalLight::new(-Vector3::y(), Vector3::new(1.0, 1.0, 1.0), 2.0)),
+        ]
+    }
+
+    #[test]
+    fn power_sampling_is_proportional_to_intensity() {
+        let sampler = LightSampler::new(LightSampling::Power, &create_lights(), &[], 0.0);
+        let context = LightSampleContext { position: Point3::origin(), normal: Vector3::zeros() };
+
+        assert!((sampler.pmf(&context, 0) - 0.25).abs() < 1e-5);
+        assert!((sampler.pmf(&context, 1) - 0.75).abs() < 1e-5);
+        assert_eq!(sampler.pmf(&context, 2), 0.0);
+    }
+
+    #[test]
+    fn bvh_sampling_pmf_sums_to_one() {
+        let sampler = LightSampler::new(LightSampling::Bvh, &create_lights(), &[], 10.0);
+        let context = LightSampleContext { position: Point3::new(1.0, 0.0, 0.0), normal: Vector3::y() };
+
+        let total: f32 = (0..3).map(|i| sampler.pmf(&context, i)).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+
+        for u in [0.1, 0.4, 0.6, 0.9] {
+            let (light_index, pmf) = sampler.sample(&context, u).unwrap();
+            assert!((sampler.pmf(&context, light_index) - pmf).abs() < 1e-5);
+        }
+    }
+}
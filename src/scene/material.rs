@@ -1,8 +1,8 @@
 use std::f32::consts::PI;
 use nalgebra::{Matrix3, Vector2, Vector3, Vector4};
-use rand::Rng;
 use crate::context::Context;
 use crate::math::lerp;
+use crate::sampler::Sampler;
 use crate::scene::coordinate_system::CoordinateSystem;
 use crate::scene::texture::{Texture};
 use crate::static_stack::StaticStack;
@@ -76,12 +76,21 @@ impl<'a> CachedTextureLookups<'a> {
 }
 
 
+/// Which part of the BSDF a sample was drawn from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BsdfLobe {
+    Diffuse,
+    Glossy,
+    Transmission,
+}
+
 pub struct BsdfSample {
     pub direction: Vector3<f32>,
     pub bsdf_value: Vector3<f32>,
     pub pdf: f32,
     pub is_reflection: bool,
     pub is_transmission: bool,
+    pub lobe: BsdfLobe,
     pub albedo: Vector3<f32>,
 }
 
@@ -157,9 +166,8 @@ impl Material {
         }
     }
 
-    pub fn sample_emissive(&self, _u: f32, _v: f32) -> Vector3<f32> {
-        self.emissive
-        //self.emissive_texture.as_ref().map(|t| {t.sample_color(u, v).component_mul(&self.emissive)}).unwrap_or(self.emissive)
+    pub fn sample_emissive(&self, u: f32, v: f32) -> Vector3<f32> {
+        self.emissive_texture.as_ref().map(|t| t.sample_color(u, v).component_mul(&self.emissive)).unwrap_or(self.emissive)
     }
 
     /*fn cosine_sample_hemisphere(normal: &Vector3<f32>, rng: &mut impl Rng) -> (Vector3<f32>, f32) {
@@ -240,16 +248,20 @@ impl Material {
     /// Note: `bsdf_value` here is the contribution of the sampled lobe, not a
     /// full evaluation of all lobes. That is intentional because `pdf` is also
     /// branch-conditioned (e.g. `specular_prob * pdf_spec`).
-    pub fn sample_bsdf(&self, incoming: Vector3<f32>, normal: Vector3<f32>, albedo: Vector3<f32>, cached_textures: &mut CachedTextureLookups, rng: &mut impl Rng, eta_stack: &mut StaticStack<f32, ETA_STACK_SIZE>, ctx: &Context) -> BsdfSample {
+    pub fn sample_bsdf(&self, incoming: Vector3<f32>, normal: Vector3<f32>, cached_textures: &mut CachedTextureLookups, sampler: &mut impl Sampler, eta_stack: &mut StaticStack<f32, ETA_STACK_SIZE>, ctx: &Context) -> BsdfSample {
+        let albedo = cached_textures.albedo();
         let n = normal;
         let v = (-incoming).normalize();
         let n_dot_v = n.dot(&v);
         let n_dot_v_max = n_dot_v.max(0.0);
         let exiting_material = n_dot_v < 0.0;
         let transmissive = self.transmission_factor > 0.0;
+        // Always the same dimensions, whichever lobe is chosen
+        let direction_sample = sampler.get_2d();
+        let lobe_sample = sampler.get_1d();
 
         // Handle transmission (refraction) for transparent materials
-        if self.transmission_factor > 0.0 && rng.random::<f32>() < self.transmission_factor {
+        if self.transmission_factor > 0.0 && lobe_sample < self.transmission_factor {
             let eta_ratio = if exiting_material { // Refracting from inside. Assuming we are leaving material
                /*
                It seems that sometimes we find ourselves leaving materials we never entered.
@@ -295,6 +307,7 @@ impl Material {
                         pdf: self.transmission_factor * transmission_prob,
                         is_reflection: false,
                         is_transmission: true,
+                        lobe: BsdfLobe::Transmission,
                         albedo,
                     };
                 }
@@ -309,6 +322,7 @@ impl Material {
                 pdf: 0.0,
                 is_reflection: true,
                 is_transmission: false,
+                lobe: BsdfLobe::Diffuse,
                 albedo,
             };
         }
@@ -321,8 +335,8 @@ impl Material {
             self.specular_sampling_probability(&f0)
         };
 
-        if rng.random::<f32>() < specular_prob {
-            let h = self.sample_ggx_half_vector(&n, alpha, rng);
+        if lobe_sample < specular_prob {
+            let h = self.sample_ggx_half_vector(&n, alpha, direction_sample);
             let v_dot_h = v.dot(&h).max(0.0);
             if v_dot_h <= 1e-6 {
                 return BsdfSample {
@@ -331,6 +345,7 @@ impl Material {
                     pdf: 0.0,
                     is_reflection: true,
                     is_transmission: false,
+                    lobe: BsdfLobe::Glossy,
                     albedo,
                 };
             }
@@ -344,6 +359,7 @@ impl Material {
                     pdf: 0.0,
                     is_reflection: true,
                     is_transmission: false,
+                    lobe: BsdfLobe::Glossy,
                     albedo,
                 };
             }
@@ -361,12 +377,13 @@ impl Material {
                 pdf: specular_prob * pdf_spec,
                 is_reflection: true,
                 is_transmission: false,
+                lobe: BsdfLobe::Glossy,
                 albedo,
             };
         }
 
         let local_system = CoordinateSystem::from_normal(&n);
-        let local_dir = Self::cosine_sample_hemisphere(rng);
+        let local_dir = Self::cosine_sample_hemisphere(direction_sample);
         let direction = (local_system.u * local_dir.x + local_system.v * local_dir.y + local_system.w * local_dir.z).normalize();
         let n_dot_l = n.dot(&direction).max(0.0);
         if n_dot_l <= 0.0 {
@@ -376,6 +393,7 @@ impl Material {
                 pdf: 0.0,
                 is_reflection: true,
                 is_transmission: false,
+                lobe: BsdfLobe::Diffuse,
                 albedo,
             };
         }
@@ -390,14 +408,15 @@ impl Material {
             pdf: (1.0 - specular_prob) * pdf_diffuse,
             is_reflection: true,
             is_transmission: false,
+            lobe: BsdfLobe::Diffuse,
             albedo,
         }
     }
 
-pub fn sample_lambertian_bsdf(&self, _incoming: Vector3<f32>, normal: Vector3<f32>, albedo: Vector3<f32>, rng: &mut impl Rng) -> BsdfSample {
+pub fn sample_lambertian_bsdf(&self, _incoming: Vector3<f32>, normal: Vector3<f32>, albedo: Vector3<f32>, sampler: &mut impl Sampler) -> BsdfSample {
 
         let local_system = CoordinateSystem::from_normal(&normal);
-        let local_dir = Self::cosine_sample_hemisphere(rng);
+        let local_dir = Self::cosine_sample_hemisphere(sampler.get_2d());
 
         let direction = local_system.u * local_dir.x + local_system.v * local_dir.y + local_system.w * local_dir.z;
 
@@ -413,13 +432,14 @@ pub fn sample_lambertian_bsdf(&self, _incoming: Vector3<f32>, normal: Vector3<f3
             pdf,
             is_reflection: true,
             is_transmission: false,
+            lobe: BsdfLobe::Diffuse,
             albedo: self.color,
         }
     }
 
-    fn cosine_sample_hemisphere(rng: &mut impl Rng) -> Vector3<f32> {
-        let phi: f32 = 2.0 * PI * rng.random::<f32>();  // Random angle around Z
-        let cos_theta = rng.random::<f32>().sqrt();  // Cosine of polar angle
+    fn cosine_sample_hemisphere(u: Vector2<f32>) -> Vector3<f32> {
+        let phi: f32 = 2.0 * PI * u.x;  // Random angle around Z
+        let cos_theta = u.y.sqrt();  // Cosine of polar angle
         let sin_theta = (1.0f32 - cos_theta * cos_theta).sqrt();
 
         Vector3::new(
@@ -435,11 +455,17 @@ pub fn sample_lambertian_bsdf(&self, _incoming: Vector3<f32>, normal: Vector3<f3
     /// (`n·l > 0` and `n·v > 0`). Use [`evaluate_btdf`] when light arrives from the
     /// opposite side of a transmissive surface.
     pub fn evaluate_bsdf(&self, light_dir: &Vector3<f32>, view_dir: &Vector3<f32>, normal: &Vector3<f32>, albedo: &Vector3<f32>, cached_textures: &mut CachedTextureLookups) -> Vector3<f32> {
+        let (diffuse, specular) = self.evaluate_bsdf_lobes(light_dir, view_dir, normal, albedo, cached_textures);
+        diffuse + specular
+    }
+
+    /// The diffuse and the specular part of [`evaluate_bsdf`].
+    pub fn evaluate_bsdf_lobes(&self, light_dir: &Vector3<f32>, view_dir: &Vector3<f32>, normal: &Vector3<f32>, albedo: &Vector3<f32>, cached_textures: &mut CachedTextureLookups) -> (Vector3<f32>, Vector3<f32>) {
         let n_dot_l = normal.dot(&light_dir).max(0.0);
         let n_dot_v = normal.dot(&view_dir).max(0.0);
 
         if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
-            return Vector3::zeros();
+            return (Vector3::zeros(), Vector3::zeros());
         }
 
         let half_vector = (light_dir + view_dir).normalize();
@@ -456,7 +482,7 @@ pub fn sample_lambertian_bsdf(&self, _incoming: Vector3<f32>, normal: Vector3<f3
         let diffuse_weight = (1.0 - self.transmission_factor) * (1.0 - cached_textures.metallic());
         let diffuse = albedo * (diffuse_weight / PI);
 
-        diffuse + specular
+        (diffuse, specular)
     }
 
     /// Evaluate the **transmissive** lobe (BTDF) for a specific pair of directions.
@@ -527,6 +553,36 @@ pub fn sample_lambertian_bsdf(&self, _incoming: Vector3<f32>, normal: Vector3<f3
         one_minus_f.component_mul(&(albedo * d * g * jacobian.abs() * self.transmission_factor))
     }
 
+    /// Solid angle pdf of [`sample_bsdf`] choosing `light_dir` through one of the reflective lobes.
+    ///
+    /// Refraction is a delta lobe that can't be reached by any other sampling strategy, so it
+    /// isn't included.
+    pub fn pdf_bsdf(&self, light_dir: &Vector3<f32>, view_dir: &Vector3<f32>, normal: &Vector3<f32>, albedo: &Vector3<f32>, cached_textures: &mut CachedTextureLookups) -> f32 {
+        let n_dot_l = normal.dot(light_dir);
+        let n_dot_v = normal.dot(view_dir);
+
+        if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return 0.0;
+        }
+
+        let alpha = self.alpha(cached_textures);
+        let f0 = self.f0_from_albedo(albedo, cached_textures);
+        let specular_prob = if self.transmission_factor > 0.0 {
+            1.0
+        } else {
+            self.specular_sampling_probability(&f0)
+        };
+
+        let half_vector = (light_dir + view_dir).normalize();
+        let n_dot_h = normal.dot(&half_vector).max(0.0);
+        let v_dot_h = view_dir.dot(&half_vector).max(0.0);
+
+        let pdf_spec = Self::ggx_ndf(n_dot_h, alpha) * n_dot_h / (4.0 * v_dot_h + 1e-6);
+        let pdf_diffuse = n_dot_l / PI;
+
+        specular_prob * pdf_spec + (1.0 - specular_prob) * pdf_diffuse
+    }
+
     fn alpha(&self, cached_textures: &mut CachedTextureLookups) -> f32 {
         let roughness = cached_textures.roughness().clamp(0.02, 1.0);
         (roughness * roughness).max(1e-4)
@@ -543,9 +599,8 @@ pub fn sample_lambertian_bsdf(&self, _incoming: Vector3<f32>, normal: Vector3<f3
         lerp(0.08, 0.95, max_f0).clamp(0.08, 0.95)
     }
 
-    fn sample_ggx_half_vector(&self, normal: &Vector3<f32>, alpha: f32, rng: &mut impl Rng) -> Vector3<f32> {
-        let u1: f32 = rng.random();
-        let u2: f32 = rng.random();
+    fn sample_ggx_half_vector(&self, normal: &Vector3<f32>, alpha: f32, u: Vector2<f32>) -> Vector3<f32> {
+        let (u1, u2) = (u.x, u.y);
 
         let phi = 2.0 * PI * u1;
         let a2 = alpha * alpha;
@@ -640,11 +695,11 @@ pub fn sample_lambertian_bsdf(&self, _incoming: Vector3<f32>, normal: Vector3<f3
 
 #[cfg(test)]
 mod tests {
-    use crate::scene::texture::WrapMode;
+    use crate::scene::texture::{ColorSpace, WrapMode};
     use super::*;
 
     fn solid_texture(rgb: [u8; 3]) -> Texture {
-        Texture::new(vec![rgb[0], rgb[1], rgb[2], 255], 1, 1, WrapMode::ClampToEdge)
+        Texture::new(vec![rgb[0], rgb[1], rgb[2], 255], 1, 1, WrapMode::ClampToEdge, ColorSpace::Linear)
     }
 
     fn make_material(normal_map: Option<Texture>, normal_scale: f32) -> Material {
@@ -9,6 +9,9 @@ pub mod scene;
 pub mod texture;
 mod coordinate_system;
 pub mod light;
+pub mod light_sampler;
+pub mod environment;
+pub mod sky;
 pub mod node_graph;
 
 pub struct Intersection {
@@ -16,12 +19,14 @@ pub struct Intersection {
     pub tex_coord: Vector2<f32>,
     pub normal: Vector3<f32>,
     pub tangent: Vector4<f32>,
-
+    /// Normal of the surface itself, on the side of `normal`, where `normal` is interpolated.
+    pub geometric_normal: Vector3<f32>,
 }
 
 pub struct ShadingContext {
     pub intersection: Intersection,
     pub material_index: u32,
+    pub mesh_index: u32,
 }
 
 pub trait Intersectable {
@@ -70,11 +75,14 @@ impl Intersectable for Sphere {
             }
         }
 
+        let normal = (ray.origin() + ray.direction() * root - self.position).normalize();
         Some(Intersection {
             dist: root,
             tex_coord: Vector2::new(0.0, 0.0),
-            normal: (ray.origin() + ray.direction() * root - self.position).normalize(),
-        tangent: Vector4::new(0.0, 0.0, 0.0, 0.0),})
+            normal,
+            tangent: Vector4::new(0.0, 0.0, 0.0, 0.0),
+            geometric_normal: normal,
+        })
     }
 
     fn transform(&self) -> &Matrix4<f32> {
@@ -1,4 +1,5 @@
 use nalgebra::{Matrix4, UnitQuaternion, Vector3};
+use crate::options::{CameraSelector, FocalDistance};
 
 /// Decomposed TRS transform — the native representation for animation.
 #[derive(Debug, Clone)]
@@ -45,6 +46,10 @@ pub struct SceneNode {
     pub mesh_indices: Vec<usize>,
     /// Index into the scene's camera list, if this node has a camera.
     pub camera_index: Option<usize>,
+    /// Name of the GLTF camera, if this node has a named camera.
+    pub camera_name: Option<String>,
+    /// Focus of the camera of this node, if set in the glTF extras.
+    pub camera_focus: Option<FocalDistance>,
     /// Index into the scene's light list, if this node has a light.
     pub light_index: Option<usize>,
     pub children: Vec<SceneNode>,
@@ -75,6 +80,40 @@ impl NodeGraph {
         }
         None
     }
+
+    /// World transform of the node named `name`, combining the local transforms of its ancestors.
+    pub fn world_transform(&self, name: &str) -> Option<Matrix4<f32>> {
+        Self::find_world_transform(&self.roots, &Matrix4::identity(), name)
+    }
+
+    /// The node holding the camera with index `camera_index` in the scene's camera list.
+    pub fn camera_node(&self, camera_index: usize) -> Option<&SceneNode> {
+        self.iter().find(|node| node.camera_index == Some(camera_index))
+    }
+
+    fn find_world_transform(nodes: &[SceneNode], parent_transform: &Matrix4<f32>, name: &str) -> Option<Matrix4<f32>> {
+        nodes.iter().find_map(|node| {
+            let transform = parent_transform * node.local_transform.to_matrix();
+            if node.name.as_deref() == Some(name) {
+                Some(transform)
+            } else {
+                Self::find_world_transform(&node.children, &transform, name)
+            }
+        })
+    }
+
+    /// Index into the scene's camera list of the camera picked by `selector`. Indices aren't
+    /// checked against the number of cameras.
+    pub fn find_camera(&self, selector: &CameraSelector) -> Option<usize> {
+        match selector {
+            CameraSelector::Index(index) => Some(*index),
+            CameraSelector::Node(name) => self.get_node_by_name(name)?.camera_index,
+            CameraSelector::Camera(name) => self
+                .iter()
+                .find(|node| node.camera_name.as_deref() == Some(name.as_str()))?
+                .camera_index,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -92,3 +131,38 @@ impl<'a> Iterator for NodeIter<'a> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str, camera: Option<(usize, &str)>, children: Vec<SceneNode>) -> SceneNode {
+        SceneNode {
+            name: Some(name.to_string()),
+            index: 0,
+            local_transform: NodeTransform::default(),
+            mesh_indices: Vec::new(),
+            camera_index: camera.map(|(index, _)| index),
+            camera_name: camera.map(|(_, name)| name.to_string()),
+            camera_focus: None,
+            light_index: None,
+            children,
+        }
+    }
+
+    #[test]
+    fn cameras_are_found_by_index_node_and_camera_name() {
+        let graph = NodeGraph::new(vec![
+            node("root", None, vec![node("left", Some((0, "lens")), Vec::new())]),
+            node("right", Some((1, "wide")), Vec::new()),
+        ]);
+
+        assert_eq!(graph.find_camera(&CameraSelector::Index(1)), Some(1));
+        assert_eq!(graph.find_camera(&CameraSelector::Node("left".to_string())), Some(0));
+        assert_eq!(graph.find_camera(&CameraSelector::Camera("wide".to_string())), Some(1));
+
+        assert_eq!(graph.find_camera(&CameraSelector::Node("root".to_string())), None);
+        assert_eq!(graph.find_camera(&CameraSelector::Node("lens".to_string())), None);
+        assert_eq!(graph.find_camera(&CameraSelector::Camera("missing".to_string())), None);
+    }
+}
@@ -1,31 +1,65 @@
+use std::f32::consts::PI;
 use std::fmt::Display;
 use crate::acceleration::bvh::BVH;
-use crate::camera::perspective_camera::PerspectiveCamera;
+use crate::camera::viewpoint::CameraImpl;
 use crate::content::mesh::MeshInstance;
 use crate::core::Ray;
 use crate::scene::light::LightSource;
-use crate::scene::{Intersectable, Intersection, Shadeable, ShadingContext};
+use crate::scene::light_sampler::{LightSampleContext, LightSampler};
+use crate::scene::{Intersection, Shadeable, ShadingContext};
 use nalgebra::{Point3, Vector3};
+use crate::acceleration::bounds::AABB;
 use crate::context::Context;
+use crate::math;
 use crate::math::lerp;
+use crate::options::LightSampling;
+use crate::scene::coordinate_system::CoordinateSystem;
 use crate::scene::material::Material;
 
 pub struct Scene {
-    cameras : Vec<PerspectiveCamera>,
+    cameras : Vec<CameraImpl>,
+    active_camera: usize,
+    selected_cameras: Vec<usize>,
     meshes: Vec<MeshInstance>,
+    /// Position in `meshes` of each mesh, by the index it was added with.
+    mesh_positions: Vec<usize>,
     bvh: BVH,
     lights: Vec<LightSource>,
     materials: Vec<Material>,
+    environment_light_index: Option<usize>,
+    light_sampling: LightSampling,
+    light_sampler: LightSampler,
+    /// Picks the light light subpaths start from, proportional to power.
+    emission_sampler: LightSampler,
+    bounding_sphere: (Point3<f32>, f32),
 }
 
 pub struct LightSample {
+    pub light_index: usize,
     pub wi: Vector3<f32>,
     pub radiance: Vector3<f32>,
+    /// Includes the probability of having selected this light.
     pub pdf: f32,
     pub is_delta: bool,
+    /// Whether BSDF-sampled rays can hit this light, in which case its contribution
+    /// has to be MIS-weighted.
+    pub intersectable: bool,
     pub position: Option<Point3<f32>>,
 }
 
+/// A ray leaving a light, the start of a light subpath.
+pub struct LightEmissionSample {
+    pub light_index: usize,
+    pub ray: Ray,
+    /// Surface normal at the origin of the ray, zero for point and infinite lights.
+    pub normal: Vector3<f32>,
+    pub radiance: Vector3<f32>,
+    /// Area pdf of the origin, including the probability of having selected this light.
+    pub pdf_position: f32,
+    /// Solid angle pdf of the direction, one for lights that only emit in a single direction.
+    pub pdf_direction: f32,
+}
+
 pub struct PathIntersection {
     pub mesh_index: u32,
     pub intersection: Intersection,
@@ -104,40 +138,115 @@ impl Scene {
         }
     }
 
-    pub fn new(cameras: Vec<PerspectiveCamera>, mut meshes: Vec<MeshInstance>, materials: Vec<Material>, mut lights: Vec<LightSource>) -> Self {
-        for mesh in &meshes {
+    pub fn new(cameras: Vec<CameraImpl>, mut meshes: Vec<MeshInstance>, materials: Vec<Material>, mut lights: Vec<LightSource>, light_sampling: LightSampling) -> Self {
+        for (scene_index, mesh) in meshes.iter_mut().enumerate() {
+            mesh.set_scene_index(scene_index);
+        }
+
+        for mesh in &mut meshes {
             let material = &materials[mesh.material_index() as usize];
             if material.emissive_factor().x > 0.0 || material.emissive_factor().y > 0.0 || material.emissive_factor().z > 0.0 {
+                mesh.set_light_index(Some(lights.len()));
+                mesh.enable_area_sampling();
                 lights.push(LightSource::Mesh(mesh.clone()));
             }
         }
 
-        let bvh = BVH::new(&mut meshes, &materials);
+        let environment_light_index = lights.iter().position(|light| matches!(light, LightSource::Environment(_) | LightSource::Sky(_)));
 
+        let bvh = BVH::new(&mut meshes, &materials);
+        let mesh_positions = Self::compute_mesh_positions(&meshes);
+        let bounding_sphere = Self::compute_bounding_sphere(&meshes);
+        let light_sampler = LightSampler::new(light_sampling, &lights, &materials, bounding_sphere.1);
+        let emission_sampler = LightSampler::new(LightSampling::Power, &lights, &materials, bounding_sphere.1);
 
         Self {
             cameras,
+            active_camera: 0,
+            selected_cameras: vec![0],
             meshes,
+            mesh_positions,
             bvh,
             lights,
             materials,
+            environment_light_index,
+            light_sampling,
+            light_sampler,
+            emission_sampler,
+            bounding_sphere,
         }
     }
 
+    fn compute_mesh_positions(meshes: &[MeshInstance]) -> Vec<usize> {
+        let mut positions = vec![0; meshes.len()];
+        for (position, mesh) in meshes.iter().enumerate() {
+            positions[mesh.scene_index()] = position;
+        }
+        positions
+    }
+
+    fn compute_bounding_sphere(meshes: &[MeshInstance]) -> (Point3<f32>, f32) {
+        if meshes.is_empty() {
+            return (Point3::origin(), 0.0);
+        }
+
+        let bounds = AABB::compound(meshes.iter().map(|mesh| mesh.world_bounds()));
+        (nalgebra::center(&bounds.min(), &bounds.max()), (bounds.max() - bounds.min()).norm() / 2.0)
+    }
+
+    /// Center and radius of a sphere enclosing all meshes.
+    pub fn bounding_sphere(&self) -> (Point3<f32>, f32) {
+        self.bounding_sphere
+    }
+
     pub fn rebuild_bvh(&mut self) {
         self.bvh = BVH::new(&mut self.meshes, &self.materials);
+        self.mesh_positions = Self::compute_mesh_positions(&self.meshes);
+
+        // Emissive meshes are lit through their own copy in the light list; keep it in sync with
+        // the transform the mesh was just animated to.
+        for mesh in &self.meshes {
+            if let Some(light_index) = mesh.light_index() {
+                self.lights[light_index] = LightSource::Mesh(mesh.clone());
+            }
+        }
+
+        self.bounding_sphere = Self::compute_bounding_sphere(&self.meshes);
+        self.light_sampler = LightSampler::new(self.light_sampling, &self.lights, &self.materials, self.bounding_sphere.1);
+        self.emission_sampler = LightSampler::new(LightSampling::Power, &self.lights, &self.materials, self.bounding_sphere.1);
     }
 
-    pub fn active_camera(&self) -> &PerspectiveCamera {
-        &self.cameras[0]
+    pub fn active_camera(&self) -> &CameraImpl {
+        &self.cameras[self.active_camera]
     }
 
-    pub fn cameras_mut(&mut self) -> &mut [PerspectiveCamera] {
+    pub fn set_active_camera(&mut self, camera_index: usize) {
+        self.active_camera = camera_index;
+    }
+
+    /// Cameras to render, in order. Only the first camera unless others were selected.
+    pub fn selected_cameras(&self) -> &[usize] {
+        &self.selected_cameras
+    }
+
+    /// Select the cameras to render, and make the first of them active.
+    pub fn set_selected_cameras(&mut self, camera_indices: Vec<usize>) {
+        self.active_camera = camera_indices[0];
+        self.selected_cameras = camera_indices;
+    }
+
+    pub fn cameras_mut(&mut self) -> &mut [CameraImpl] {
         &mut self.cameras
     }
 
-    pub fn meshes_mut(&mut self) -> &mut [MeshInstance] {
-        &mut self.meshes
+    pub fn meshes(&self) -> &[MeshInstance] {
+        &self.meshes
+    }
+
+    /// The mesh added to the scene at `scene_index`. Building the BVH reorders `meshes`, but
+    /// not these indices.
+    pub fn mesh_mut(&mut self, scene_index: usize) -> &mut MeshInstance {
+        &mut self.meshes[self.mesh_positions[scene_index]]
     }
 
     pub fn materials(&self) -> &[Material] { &self.materials }
@@ -151,6 +260,7 @@ impl Scene {
             ShadingContext {
                 intersection: hit,
                 material_index: self.meshes[mesh_index as usize].material_index(),
+                mesh_index,
             }
         })
     }
@@ -159,18 +269,57 @@ impl Scene {
         &self.lights
     }
 
-    pub fn environment(&self, _: &Ray) -> Vector3<f32> {
-        Vector3::zeros()
+    /// The light surrounding the scene, either an environment map or a sky.
+    pub fn environment_light(&self) -> Option<&LightSource> {
+        self.environment_light_index.map(|index| &self.lights[index])
     }
 
-    /// Sample a random point on a random emissive surface
-    /// Returns (point, normal, emissive_color, pdf)
-    pub fn sample_light(&self, rng: &mut impl rand::Rng) -> Option<LightSample> {
-        if self.lights.is_empty() {
-            return None;
+    pub fn environment(&self, ray: &Ray) -> Vector3<f32> {
+        match self.environment_light() {
+            Some(LightSource::Environment(environment)) => environment.radiance(&ray.direction()),
+            Some(LightSource::Sky(sky)) => sky.radiance(&ray.direction()),
+            _ => Vector3::zeros(),
+        }
+    }
+
+    /// Solid angle pdf of `sample_light` at `context` choosing the environment in `direction`.
+    pub fn environment_pdf(&self, context: &LightSampleContext, direction: &Vector3<f32>) -> f32 {
+        let pdf = match self.environment_light() {
+            Some(LightSource::Environment(environment)) => environment.pdf(direction),
+            Some(LightSource::Sky(sky)) => sky.pdf(direction),
+            _ => return 0.0,
+        };
+
+        self.environment_light_index
+            .map_or(0.0, |light_index| self.light_sampler.pmf(context, light_index) * pdf)
+    }
+
+    /// Solid angle pdf of `sample_light` at `context` choosing the point `hit` seen along `ray`.
+    /// Zero if the surface isn't emissive or is seen from behind.
+    pub fn light_pdf(&self, context: &LightSampleContext, ray: &Ray, hit: &ShadingContext) -> f32 {
+        let mesh = &self.meshes[hit.mesh_index as usize];
+        let Some(light_index) = mesh.light_index() else {
+            return 0.0;
+        };
+
+        let direction = ray.direction().normalize();
+        let cos_theta_light = hit.intersection.normal.dot(&-direction);
+        if cos_theta_light <= 0.0 {
+            return 0.0;
         }
 
-        let light_index = rng.random_range(0..self.lights.len());
+        let surface_area = mesh.surface_area();
+        if surface_area <= 0.0 {
+            return 0.0;
+        }
+
+        let distance = hit.intersection.dist * ray.direction().norm();
+        self.light_sampler.pmf(context, light_index) * distance * distance / (surface_area * cos_theta_light)
+    }
+
+    /// Pick a light for shading `context` and sample a point or direction on it
+    pub fn sample_light(&self, context: &LightSampleContext, rng: &mut impl rand::Rng) -> Option<LightSample> {
+        let (light_index, selection_pdf) = self.light_sampler.sample(context, rng.random())?;
         let light = &self.lights[light_index];
 
         match light {
@@ -179,10 +328,12 @@ impl Scene {
 
                 if point_light.radius <= 0.0 {
                     return Some(LightSample {
+                        light_index,
                         wi: Vector3::zeros(),
                         radiance,
-                        pdf: 1.0,
+                        pdf: selection_pdf,
                         is_delta: true,
+                        intersectable: false,
                         position: Some(point_light.position),
                     });
                 }
@@ -200,61 +351,235 @@ impl Scene {
                 let point = point_light.position + direction * point_light.radius;
                 let normal = direction;
                 let area = 4.0 * std::f32::consts::PI * point_light.radius * point_light.radius;
-                let pdf = 1.0 / area;
+                let pdf = selection_pdf / area;
 
                 Some(LightSample {
+                    light_index,
                     wi: normal,
                     radiance: radiance / area,
                     pdf,
                     is_delta: false,
+                    intersectable: false,
                     position: Some(point),
                 })
             },
+            LightSource::Spot(spot_light) => {
+                let radiance = spot_light.color * spot_light.intensity * spot_light.falloff(&(context.position - spot_light.position));
+                if !math::is_greater_than_zero(radiance) {
+                    return None;
+                }
+
+                Some(LightSample {
+                    light_index,
+                    wi: Vector3::zeros(),
+                    radiance,
+                    pdf: selection_pdf,
+                    is_delta: true,
+                    intersectable: false,
+                    position: Some(spot_light.position),
+                })
+            },
             LightSource::Directional(directional_light) => {
                 let normal = -directional_light.direction.normalize(); // Light comes from this direction
 
-                let pdf = 1.0;
+                let pdf = selection_pdf;
 
                 let radiance = directional_light.color * directional_light.intensity;
 
                 Some(LightSample {
+                    light_index,
                     wi: normal,
                     radiance,
                     pdf,
                     is_delta: true,
+                    intersectable: false,
                     position: None
                 })
                 //Some((point, normal, emissive, pdf))
             },
             LightSource::Mesh(mesh) => {
-                // Sample a random point on the mesh by sampling a random triangle
-                let triangle_index = rng.random_range(..mesh.triangle_count() as usize);
-
-                let triangle = mesh.triangle_at(triangle_index);
-
-                let (point, normal) = triangle.sample_uniform_point(rng);
+                // Sample a point proportional to area over the whole emissive surface
+                let surface = mesh.sample_surface(rng)?;
 
                 let material = &self.materials[mesh.material_index() as usize];
-                let radiance = material.emissive_factor();
-
-                // PDF is 1/area. For now, use a rough estimate
-                let bounds = mesh.bounds();
-                let area = (bounds.max().x - bounds.min().x) * (bounds.max().z - bounds.min().z);
-                let pdf = 1.0 / area;
+                let radiance = material.sample_emissive(surface.tex_coord.x, surface.tex_coord.y);
 
                 Some(LightSample {
-                    wi: normal,
+                    light_index,
+                    wi: surface.normal,
                     radiance,
-                    pdf,
+                    pdf: selection_pdf * surface.pdf,
                     is_delta: false,
-                    position: Some(point),
+                    intersectable: true,
+                    position: Some(surface.position),
                 })
                 //Some((point, normal, emissive, pdf))
+            },
+            LightSource::Environment(_) | LightSource::Sky(_) => {
+                let (wi, radiance, pdf) = match light {
+                    LightSource::Sky(sky) => sky.sample(rng),
+                    LightSource::Environment(environment) => environment.sample(rng),
+                    _ => unreachable!(),
+                };
+                if pdf <= 0.0 {
+                    return None;
+                }
+
+                Some(LightSample {
+                    light_index,
+                    wi,
+                    radiance,
+                    pdf: selection_pdf * pdf,
+                    is_delta: false,
+                    intersectable: true,
+                    position: None,
+                })
             }
         }
     }
 
-    pub fn transmissions_along_path_2(&self, start: Point3<f32>, end: Point3<f32>, ctx: &Context) -> Vector3<f32> {
+    /// Pick a light proportional to its power and sample a ray leaving it.
+    pub fn sample_light_emission(&self, rng: &mut impl rand::Rng) -> Option<LightEmissionSample> {
+        let context = LightSampleContext { position: Point3::origin(), normal: Vector3::zeros() };
+        let (light_index, selection_pdf) = self.emission_sampler.sample(&context, rng.random())?;
+        let (center, radius) = self.bounding_sphere;
+
+        let (origin, normal, direction, radiance, pdf_position, pdf_direction) = match &self.lights[light_index] {
+            LightSource::Point(point_light) => {
+                let intensity = point_light.color * point_light.intensity;
+                if point_light.radius <= 0.0 {
+                    let direction = Self::uniform_sample_sphere(rng);
+                    (point_light.position, Vector3::zeros(), direction, intensity, 1.0, 1.0 / (4.0 * PI))
+                } else {
+                    let normal = Self::uniform_sample_sphere(rng);
+                    let area = 4.0 * PI * point_light.radius * point_light.radius;
+                    let (direction, pdf_direction) = Self::cosine_sample_hemisphere(&normal, rng);
+                    (point_light.position + normal * point_light.radius, normal, direction, intensity / area, 1.0 / area, pdf_direction)
+                }
+            }
+            LightSource::Spot(spot_light) => {
+                let cos_theta = 1.0 - rng.random::<f32>() * (1.0 - spot_light.cos_outer);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * rng.random::<f32>();
+                let basis = CoordinateSystem::from_normal(&spot_light.direction);
+                let direction = basis.u * sin_theta * phi.cos() + basis.v * sin_theta * phi.sin() + basis.w * cos_theta;
+                let radiance = spot_light.color * spot_light.intensity * spot_light.falloff(&direction);
+                (spot_light.position, Vector3::zeros(), direction, radiance, 1.0, 1.0 / (2.0 * PI * (1.0 - spot_light.cos_outer)))
+            }
+            LightSource::Directional(directional_light) => {
+                let direction = directional_light.direction.normalize();
+                let origin = Self::sample_disk_facing(&center, radius, &direction, rng);
+                let radiance = directional_light.color * directional_light.intensity;
+                (origin, Vector3::zeros(), direction, radiance, 1.0 / (PI * radius * radius), 1.0)
+            }
+            LightSource::Mesh(mesh) => {
+                let surface = mesh.sample_surface(rng)?;
+                let material = &self.materials[mesh.material_index() as usize];
+                let radiance = material.sample_emissive(surface.tex_coord.x, surface.tex_coord.y);
+                let (direction, pdf_direction) = Self::cosine_sample_hemisphere(&surface.normal, rng);
+                (surface.position, surface.normal, direction, radiance, surface.pdf, pdf_direction)
+            }
+            light @ (LightSource::Environment(_) | LightSource::Sky(_)) => {
+                let (wi, radiance, pdf) = match light {
+                    LightSource::Sky(sky) => sky.sample(rng),
+                    LightSource::Environment(environment) => environment.sample(rng),
+                    _ => unreachable!(),
+                };
+                let origin = Self::sample_disk_facing(&center, radius, &-wi, rng);
+                (origin, Vector3::zeros(), -wi, radiance, 1.0 / (PI * radius * radius), pdf)
+            }
+        };
+
+        if pdf_position <= 0.0 || pdf_direction <= 0.0 || !math::is_greater_than_zero(radiance) {
+            return None;
+        }
+
+        Some(LightEmissionSample {
+            light_index,
+            ray: Ray::new(origin, direction),
+            normal,
+            radiance,
+            pdf_position: selection_pdf * pdf_position,
+            pdf_direction,
+        })
+    }
+
+    /// The (position, direction) pdfs of `sample_light_emission` producing a ray leaving light
+    /// `light_index` in `direction`, from a point with surface `normal`. See [`LightEmissionSample`].
+    pub fn light_emission_pdf(&self, light_index: usize, normal: &Vector3<f32>, direction: &Vector3<f32>) -> (f32, f32) {
+        let context = LightSampleContext { position: Point3::origin(), normal: Vector3::zeros() };
+        let selection_pdf = self.emission_sampler.pmf(&context, light_index);
+        let radius = self.bounding_sphere.1;
+
+        let (pdf_position, pdf_direction) = match &self.lights[light_index] {
+            LightSource::Point(point_light) => {
+                if point_light.radius <= 0.0 {
+                    (1.0, 1.0 / (4.0 * PI))
+                } else {
+                    let area = 4.0 * PI * point_light.radius * point_light.radius;
+                    (1.0 / area, normal.dot(direction).max(0.0) / PI)
+                }
+            }
+            LightSource::Spot(spot_light) => {
+                let inside = direction.normalize().dot(&spot_light.direction.normalize()) >= spot_light.cos_outer;
+                (1.0, if inside { 1.0 / (2.0 * PI * (1.0 - spot_light.cos_outer)) } else { 0.0 })
+            }
+            LightSource::Directional(_) => (1.0 / (PI * radius * radius), 0.0),
+            LightSource::Mesh(mesh) => {
+                let area = mesh.surface_area();
+                if area <= 0.0 {
+                    return (0.0, 0.0);
+                }
+                (1.0 / area, normal.dot(direction).max(0.0) / PI)
+            }
+            LightSource::Environment(environment) => (1.0 / (PI * radius * radius), environment.pdf(&-direction)),
+            LightSource::Sky(sky) => (1.0 / (PI * radius * radius), sky.pdf(&-direction)),
+        };
+
+        (selection_pdf * pdf_position, pdf_direction)
+    }
+
+    /// Solid angle pdf of `sample_light_emission` starting a ray from the environment that
+    /// arrives from `direction`.
+    pub fn environment_emission_pdf(&self, direction: &Vector3<f32>) -> f32 {
+        let context = LightSampleContext { position: Point3::origin(), normal: Vector3::zeros() };
+        let pdf = match self.environment_light() {
+            Some(LightSource::Environment(environment)) => environment.pdf(direction),
+            Some(LightSource::Sky(sky)) => sky.pdf(direction),
+            _ => return 0.0,
+        };
+
+        self.environment_light_index
+            .map_or(0.0, |light_index| self.emission_sampler.pmf(&context, light_index) * pdf)
+    }
+
+    fn uniform_sample_sphere(rng: &mut impl rand::Rng) -> Vector3<f32> {
+        let z = 1.0 - 2.0 * rng.random::<f32>();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * rng.random::<f32>();
+        Vector3::new(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    /// Returns (direction, solid angle pdf).
+    fn cosine_sample_hemisphere(normal: &Vector3<f32>, rng: &mut impl rand::Rng) -> (Vector3<f32>, f32) {
+        let phi = 2.0 * PI * rng.random::<f32>();
+        let cos_theta = rng.random::<f32>().sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let basis = CoordinateSystem::from_normal(normal);
+        let direction = basis.u * sin_theta * phi.cos() + basis.v * sin_theta * phi.sin() + basis.w * cos_theta;
+        (direction, cos_theta / PI)
+    }
+
+    /// A point on the disk of radius `radius` facing `direction`, placed outside the sphere around
+    /// `center` so that rays along `direction` from it cover the whole sphere.
+    fn sample_disk_facing(center: &Point3<f32>, radius: f32, direction: &Vector3<f32>, rng: &mut impl rand::Rng) -> Point3<f32> {
+        let r = rng.random::<f32>().sqrt() * radius;
+        let phi = 2.0 * PI * rng.random::<f32>();
+        let basis = CoordinateSystem::from_normal(direction);
+        center - direction * radius + basis.u * r * phi.cos() + basis.v * r * phi.sin()
+    }
+
+    pub fn transmissions_along_path_2(&self, start: Point3<f32>, end: Point3<f32>, time: f32, ctx: &Context) -> Vector3<f32> {
         let mut throughput = Vector3::new(1.0, 1.0, 1.0);
 
         let direction = end - start;
@@ -264,11 +589,11 @@ impl Scene {
             return throughput;
         }
 
-        let ray = Ray::new(start, direction / distance);
+        let ray = Ray::new(start, direction / distance).with_time(time);
         let t_min = 0.001;
         let t_max = (distance - 0.001).max(0.0);
         if !self.bvh.might_intersect_transparent_objects(&ray, t_min, t_max, ctx) {
-            return if self.is_visible(start, end, ctx) {
+            return if self.is_visible(start, end, time, ctx) {
                 Vector3::repeat(1.0)
             } else {
                 Vector3::zeros()
@@ -295,14 +620,14 @@ impl Scene {
         throughput
     }
 
-    pub fn transmission_along_path(&self, p1: Point3<f32>, p2: Point3<f32>, ctx: &Context) -> Vector3<f32> {
+    pub fn transmission_along_path(&self, p1: Point3<f32>, p2: Point3<f32>, time: f32, ctx: &Context) -> Vector3<f32> {
         let direction = p2 - p1;
         let distance = direction.norm();
         if distance <= 1e-5 {
             return Vector3::new(1.0, 1.0, 1.0);
         }
 
-        let ray = Ray::new(p1.into(), direction / distance);
+        let ray = Ray::new(p1.into(), direction / distance).with_time(time);
         let mut t_min = 0.001;
         let t_max = (distance - 0.001).max(0.0);
         if t_max <= t_min {
@@ -349,15 +674,15 @@ impl Scene {
         throughput
     }
 
-    /// Check if there's an unoccluded path between two points
-    pub fn is_visible(&self, p1: Point3<f32>, p2: Point3<f32>, ctx: &Context) -> bool {
+    /// Check if there's an unoccluded path between two points at `time` in the shutter interval
+    pub fn is_visible(&self, p1: Point3<f32>, p2: Point3<f32>, time: f32, ctx: &Context) -> bool {
         let direction = p2 - p1;
         let distance = direction.norm();
         if distance <= 1e-5 {
             return true;
         }
 
-        let ray = Ray::new(p1.into(), direction / distance);
+        let ray = Ray::new(p1.into(), direction / distance).with_time(time);
         let t_min = 0.001;
         let t_max = (distance - 0.001).max(0.0);
 
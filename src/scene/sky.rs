@@ -0,0 +1,313 @@
+use std::f32::consts::PI;
+use nalgebra::{Matrix3, Vector3};
+use rand::Rng;
+use crate::color;
+use crate::math::luminance;
+use crate::options::RgbSpace;
+use crate::scene::coordinate_system::CoordinateSystem;
+use crate::scene::environment::EnvironmentLight;
+
+const SKY_MAP_WIDTH: u32 = 512;
+const SKY_MAP_HEIGHT: u32 = 256;
+
+/// Solar illuminance at the top of the atmosphere, in klux to match the kcd/m² of the sky model.
+const SOLAR_ILLUMINANCE: f32 = 127.5;
+
+/// Sine of the elevation below the horizon at which the sky has faded to black, the end of civil
+/// twilight 6° down.
+const TWILIGHT_END: f32 = 0.104528;
+
+/// Preetham daylight sky with an analytic sun disk.
+///
+/// The sky itself is baked into an equirectangular map so it can be importance sampled like any
+/// other environment. The sun is far too small for that and is sampled separately as a cone.
+/// Radiance is in kcd/m², scaled by `intensity`, in the RGB `working_space`.
+pub struct SkyLight {
+    turbidity: f32,
+    ground_albedo: f32,
+    intensity: f32,
+    working_space: RgbSpace,
+    cos_sun_radius: f32,
+    sun_direction: Vector3<f32>,
+    sun_frame: CoordinateSystem,
+    sun_radiance: Vector3<f32>,
+    sun_probability: f32,
+    sky: EnvironmentLight,
+}
+
+impl SkyLight {
+    /// `sun_direction` points towards the sun. `sun_angular_diameter` is in radians.
+    pub fn new(sun_direction: Vector3<f32>, turbidity: f32, sun_angular_diameter: f32, ground_albedo: f32, intensity: f32, working_space: RgbSpace) -> Self {
+        let sun_direction = sun_direction.normalize();
+        let turbidity = turbidity.clamp(1.7, 10.0);
+        let cos_sun_radius = (sun_angular_diameter.clamp(1e-4, PI) / 2.0).cos();
+        let to_working_space = color::from_rec709(working_space);
+        let sky = Self::bake(&sun_direction, turbidity, ground_albedo, intensity * to_working_space);
+        let sun_radiance = to_working_space * Self::sun_radiance(&sun_direction, turbidity, cos_sun_radius) * intensity;
+
+        let mut light = Self {
+            turbidity,
+            ground_albedo,
+            intensity,
+            working_space,
+            cos_sun_radius,
+            sun_frame: CoordinateSystem::from_normal(&sun_direction),
+            sun_direction,
+            sun_radiance,
+            sun_probability: 0.0,
+            sky,
+        };
+        light.sun_probability = light.sun_power(1.0) / (light.sun_power(1.0) + light.sky.phi(1.0)).max(f32::MIN_POSITIVE);
+        light
+    }
+
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        self.sun_direction
+    }
+
+    /// Move the sun, rebaking the sky if it actually moved.
+    pub fn set_sun_direction(&mut self, sun_direction: Vector3<f32>) {
+        if (sun_direction.normalize() - self.sun_direction).norm_squared() < 1e-12 {
+            return;
+        }
+
+        let angular_diameter = 2.0 * self.cos_sun_radius.acos();
+        *self = Self::new(sun_direction, self.turbidity, angular_diameter, self.ground_albedo, self.intensity, self.working_space);
+    }
+
+    fn solid_angle_of_sun(&self) -> f32 {
+        2.0 * PI * (1.0 - self.cos_sun_radius)
+    }
+
+    fn sun_power(&self, scene_radius: f32) -> f32 {
+        PI * scene_radius * scene_radius * luminance(&self.sun_radiance) * self.solid_angle_of_sun()
+    }
+
+    /// Power arriving at a scene bounded by a sphere of `scene_radius`.
+    pub fn phi(&self, scene_radius: f32) -> f32 {
+        self.sky.phi(scene_radius) + self.sun_power(scene_radius)
+    }
+
+    fn is_in_sun(&self, direction: &Vector3<f32>) -> bool {
+        direction.normalize().dot(&self.sun_direction) >= self.cos_sun_radius
+    }
+
+    /// Radiance arriving from `direction` (pointing away from the scene).
+    pub fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let sky = self.sky.radiance(direction);
+        if self.is_in_sun(direction) {
+            sky + self.sun_radiance
+        } else {
+            sky
+        }
+    }
+
+    /// Importance sample either the sun disk or the sky.
+    /// Returns (direction, radiance, solid angle pdf).
+    pub fn sample(&self, rng: &mut impl Rng) -> (Vector3<f32>, Vector3<f32>, f32) {
+        let direction = if rng.random::<f32>() < self.sun_probability {
+            let cos_theta = 1.0 - rng.random::<f32>() * (1.0 - self.cos_sun_radius);
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * rng.random::<f32>();
+            let frame = &self.sun_frame;
+            frame.u * (sin_theta * phi.cos()) + frame.v * (sin_theta * phi.sin()) + frame.w * cos_theta
+        } else {
+            let (direction, _, pdf) = self.sky.sample(rng);
+            if pdf <= 0.0 {
+                return (direction, Vector3::zeros(), 0.0);
+            }
+            direction
+        };
+
+        (direction, self.radiance(&direction), self.pdf(&direction))
+    }
+
+    /// Solid angle pdf of `sample` returning `direction`.
+    pub fn pdf(&self, direction: &Vector3<f32>) -> f32 {
+        let sun_pdf = if self.is_in_sun(direction) {
+            1.0 / self.solid_angle_of_sun()
+        } else {
+            0.0
+        };
+
+        self.sun_probability * sun_pdf + (1.0 - self.sun_probability) * self.sky.pdf(direction)
+    }
+
+    /// `scale` turns the Rec.709 radiance of the model into the radiance stored in the map.
+    fn bake(sun_direction: &Vector3<f32>, turbidity: f32, ground_albedo: f32, scale: Matrix3<f32>) -> EnvironmentLight {
+        let model = PreethamModel::new(sun_direction, turbidity);
+        // The model itself stays at sunset once the sun is down, so fade it out over twilight
+        let scale = scale * (1.0 + sun_direction.y / TWILIGHT_END).clamp(0.0, 1.0);
+
+        let mut pixels = Vec::with_capacity((SKY_MAP_WIDTH * SKY_MAP_HEIGHT) as usize);
+        for y in 0..SKY_MAP_HEIGHT {
+            let v = (y as f32 + 0.5) / SKY_MAP_HEIGHT as f32;
+            for x in 0..SKY_MAP_WIDTH {
+                let u = (x as f32 + 0.5) / SKY_MAP_WIDTH as f32;
+                let direction = EnvironmentLight::direction_at(u, v, 0.0);
+
+                // The model is only defined above the horizon; below it, reflect the horizon off
+                // a diffuse ground.
+                let radiance = if direction.y >= 0.0 {
+                    model.radiance(&direction)
+                } else {
+                    let horizon = Vector3::new(direction.x, 0.0, direction.z).try_normalize(1e-6).unwrap_or_else(Vector3::x);
+                    model.radiance(&horizon) * ground_albedo
+                };
+
+                pixels.push(scale * radiance);
+            }
+        }
+
+        EnvironmentLight::new(pixels, SKY_MAP_WIDTH, SKY_MAP_HEIGHT, 0.0, 1.0)
+    }
+
+    /// Sun disk radiance after extinction through the atmosphere, using the Rayleigh and aerosol
+    /// optical depths from Preetham et al. at wavelengths representative of each RGB channel.
+    fn sun_radiance(sun_direction: &Vector3<f32>, turbidity: f32, cos_sun_radius: f32) -> Vector3<f32> {
+        let elevation = sun_direction.y.clamp(-1.0, 1.0).asin();
+        if elevation < -(cos_sun_radius.acos()) {
+            return Vector3::zeros();
+        }
+
+        let zenith_degrees = 90.0 - elevation.max(0.0).to_degrees();
+        let air_mass = 1.0 / (zenith_degrees.to_radians().cos() + 0.15 * (93.885 - zenith_degrees).powf(-1.253));
+
+        let beta = 0.04608 * turbidity - 0.04586;
+        let transmittance = |wavelength_um: f32| {
+            let rayleigh = 0.008735 * wavelength_um.powf(-4.08);
+            let aerosol = beta * wavelength_um.powf(-1.3);
+            (-air_mass * (rayleigh + aerosol)).exp()
+        };
+
+        let solid_angle = 2.0 * PI * (1.0 - cos_sun_radius);
+        Vector3::new(transmittance(0.65), transmittance(0.57), transmittance(0.475)) * (SOLAR_ILLUMINANCE / solid_angle)
+    }
+}
+
+/// Perez distribution coefficients A..E.
+type Perez = [f32; 5];
+
+struct PreethamModel {
+    sun_direction: Vector3<f32>,
+    zenith: Vector3<f32>,
+    coefficients: [Perez; 3],
+    /// Perez function at the zenith, per xyY channel.
+    normalization: Vector3<f32>,
+}
+
+impl PreethamModel {
+    fn new(sun_direction: &Vector3<f32>, turbidity: f32) -> Self {
+        let t = turbidity;
+        // The model breaks down with the sun below the horizon; keep it just above.
+        let theta_s = sun_direction.y.clamp(0.0, 1.0).acos().min(PI / 2.0 - 1e-3);
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+
+        let theta = Vector3::new(theta_s * theta_s * theta_s, theta_s * theta_s, theta_s).push(1.0);
+        let polynomial = |c: [f32; 4]| c[0] * theta[0] + c[1] * theta[1] + c[2] * theta[2] + c[3] * theta[3];
+        let zenith_x = t * t * polynomial([0.00166, -0.00375, 0.00209, 0.0])
+            + t * polynomial([-0.02903, 0.06377, -0.03202, 0.00394])
+            + polynomial([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_y = t * t * polynomial([0.00275, -0.00610, 0.00317, 0.0])
+            + t * polynomial([-0.04214, 0.08970, -0.04153, 0.00516])
+            + polynomial([0.15346, -0.26756, 0.06670, 0.26688]);
+
+        let coefficients = [
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+        ];
+
+        let normalization = Vector3::new(
+            Self::perez(&coefficients[0], 0.0, theta_s),
+            Self::perez(&coefficients[1], 0.0, theta_s),
+            Self::perez(&coefficients[2], 0.0, theta_s),
+        );
+
+        Self {
+            sun_direction: Vector3::new(sun_direction.x, theta_s.cos(), sun_direction.z).normalize(),
+            zenith: Vector3::new(zenith_x, zenith_y, zenith_luminance),
+            coefficients,
+            normalization,
+        }
+    }
+
+    fn perez(c: &Perez, theta: f32, gamma: f32) -> f32 {
+        (1.0 + c[0] * (c[1] / theta.cos().max(0.01)).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+    }
+
+    /// Linear Rec. 709 radiance in kcd/m² for a direction above the horizon.
+    fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let theta = direction.y.clamp(0.0, 1.0).acos();
+        let gamma = direction.dot(&self.sun_direction).clamp(-1.0, 1.0).acos();
+
+        let x = self.zenith.x * Self::perez(&self.coefficients[0], theta, gamma) / self.normalization.x;
+        let y = self.zenith.y * Self::perez(&self.coefficients[1], theta, gamma) / self.normalization.y;
+        let luminance = self.zenith.z * Self::perez(&self.coefficients[2], theta, gamma) / self.normalization.z;
+        if y <= 0.0 {
+            return Vector3::zeros();
+        }
+
+        let cie_x = x / y * luminance;
+        let cie_z = (1.0 - x - y) / y * luminance;
+        Vector3::new(
+            3.2406 * cie_x - 1.5372 * luminance - 0.4986 * cie_z,
+            -0.9689 * cie_x + 1.8758 * luminance + 0.0415 * cie_z,
+            0.0557 * cie_x - 0.2040 * luminance + 1.0570 * cie_z,
+        )
+        .map(|c| c.max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use super::*;
+
+    #[test]
+    fn sky_is_brighter_towards_the_sun() {
+        let sun_direction = Vector3::new(0.0, 0.5, -1.0).normalize();
+        let sky = SkyLight::new(sun_direction, 3.0, 0.53f32.to_radians(), 0.3, 1.0, RgbSpace::LinearRec709);
+
+        let near_sun = sky.radiance(&Vector3::new(0.1, 0.5, -1.0).normalize());
+        let away_from_sun = sky.radiance(&Vector3::new(0.0, 0.5, 1.0).normalize());
+        assert!(luminance(&near_sun) > luminance(&away_from_sun));
+        assert!(luminance(&sky.radiance(&sun_direction)) > 1000.0 * luminance(&near_sun));
+    }
+
+    #[test]
+    fn sampled_pdf_matches_pdf_query() {
+        let sky = SkyLight::new(Vector3::new(1.0, 1.0, 0.0).normalize(), 4.0, 2.0f32.to_radians(), 0.3, 1.0, RgbSpace::LinearRec709);
+        let mut rng = StdRng::seed_from_u64(11);
+
+        let mut sun_samples = 0;
+        for _ in 0..128 {
+            let (direction, radiance, pdf) = sky.sample(&mut rng);
+            assert!(pdf > 0.0);
+            assert_eq!(radiance, sky.radiance(&direction));
+            assert!((sky.pdf(&direction) - pdf).abs() / pdf < 1e-3);
+            if sky.is_in_sun(&direction) {
+                sun_samples += 1;
+            }
+        }
+
+        assert!(sun_samples > 0);
+    }
+
+    #[test]
+    fn sun_below_the_horizon_emits_nothing() {
+        let sky = SkyLight::new(Vector3::new(0.0, -0.5, 1.0).normalize(), 3.0, 0.53f32.to_radians(), 0.3, 1.0, RgbSpace::LinearRec709);
+        assert_eq!(sky.radiance(&sky.sun_direction()), sky.sky.radiance(&sky.sun_direction()));
+        assert_eq!(sky.radiance(&Vector3::y()), Vector3::zeros());
+        assert_eq!(sky.radiance(&Vector3::new(0.0, 0.1, 1.0).normalize()), Vector3::zeros());
+
+        // Just below the horizon the sky is dimmer than at sunset, but not yet dark
+        let sunset = SkyLight::new(Vector3::new(0.0, 0.0, 1.0), 3.0, 0.53f32.to_radians(), 0.3, 1.0, RgbSpace::LinearRec709);
+        let twilight = SkyLight::new(Vector3::new(0.0, -0.05, 1.0).normalize(), 3.0, 0.53f32.to_radians(), 0.3, 1.0, RgbSpace::LinearRec709);
+        let zenith = |sky: &SkyLight| luminance(&sky.radiance(&Vector3::y()));
+        assert!(zenith(&twilight) > 0.0 && zenith(&twilight) < zenith(&sunset));
+    }
+}
@@ -1,4 +1,6 @@
-use nalgebra::Vector3;
+use nalgebra::{Matrix3, Vector3};
+use crate::color;
+use crate::options::RgbSpace;
 
 #[derive(Copy, Clone, Debug)]
 pub enum WrapMode {
@@ -7,12 +9,23 @@ pub enum WrapMode {
     MirroredRepeat,
 }
 
+/// How the values of a texture are encoded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorSpace {
+    /// Rec.709 colors encoded with the sRGB curve, like glTF base color and emissive textures.
+    Srgb,
+    /// Values used as they are, like normals, metalness and roughness.
+    Linear,
+}
 
 pub struct Texture {
     pixels: Vec<u8>,
     width: u32,
     height: u32,
     wrap_mode: WrapMode,
+    color_space: ColorSpace,
+    /// From Rec.709 to the working space, for sRGB textures outside a Rec.709 working space.
+    to_working_space: Option<Matrix3<f32>>,
 }
 
 #[allow(dead_code)]
@@ -32,14 +45,31 @@ impl Channel {
 }
 
 impl Texture {
-    pub fn new(pixels: Vec<u8>, width: u32, height: u32, wrap_mode: WrapMode) -> Self {
+    pub fn new(pixels: Vec<u8>, width: u32, height: u32, wrap_mode: WrapMode, color_space: ColorSpace) -> Self {
         assert!(width > 0 && height > 0, "texture dimensions must be non-zero");
         assert_eq!(pixels.len(), width as usize * height as usize * 4);
         Self {
             pixels,
             width,
             height,
-            wrap_mode
+            wrap_mode,
+            color_space,
+            to_working_space: None,
+        }
+    }
+
+    /// Convert the colors of sRGB textures to `working_space` as they are sampled.
+    pub fn in_working_space(mut self, working_space: RgbSpace) -> Self {
+        if self.color_space == ColorSpace::Srgb && working_space != RgbSpace::LinearRec709 {
+            self.to_working_space = Some(color::from_rec709(working_space));
+        }
+        self
+    }
+
+    fn decode(&self, value: u8) -> f32 {
+        match self.color_space {
+            ColorSpace::Srgb => color::srgb_to_linear(value),
+            ColorSpace::Linear => value as f32 / 255.0,
         }
     }
 
@@ -68,10 +98,14 @@ impl Texture {
         let y = ((v.clamp(0.0, 1.0) * self.height as f32) as usize).min(self.height as usize - 1);
 
         let idx = 4 * (x + y * self.width as usize);
-        let r = self.pixels[idx];
-        let g = self.pixels[idx + 1];
-        let b = self.pixels[idx + 2];
-        Vector3::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)
+        let r = self.decode(self.pixels[idx]);
+        let g = self.decode(self.pixels[idx + 1]);
+        let b = self.decode(self.pixels[idx + 2]);
+        let color = Vector3::new(r, g, b);
+        match &self.to_working_space {
+            Some(matrix) => matrix * color,
+            None => color,
+        }
     }
 
     #[allow(dead_code)]
@@ -81,6 +115,6 @@ impl Texture {
         let x = ((u.clamp(0.0, 1.0) * self.width as f32) as usize).min(self.width as usize - 1);
         let y = ((v.clamp(0.0, 1.0) * self.height as f32) as usize).min(self.height as usize - 1);
         let idx = 4 * (x + y * self.width as usize) + channel.index() as usize;
-        self.pixels[idx] as f32 / 255.0
+        self.decode(self.pixels[idx])
     }
 }
\ No newline at end of file